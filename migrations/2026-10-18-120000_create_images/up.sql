@@ -0,0 +1,14 @@
+CREATE TABLE images (
+  id SERIAL PRIMARY KEY,
+  path TEXT NOT NULL UNIQUE,
+  width INT,
+  height INT,
+  hash TEXT NOT NULL,
+  uploader TEXT NOT NULL,
+  credit TEXT,
+  caption TEXT,
+  alt_text TEXT,
+  created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX images_hash ON images (hash);
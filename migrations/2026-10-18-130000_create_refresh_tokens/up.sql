@@ -0,0 +1,14 @@
+CREATE TYPE Role AS ENUM ('admin', 'editor', 'default');
+
+CREATE TABLE refresh_tokens (
+  id SERIAL PRIMARY KEY,
+  token_hash TEXT NOT NULL UNIQUE,
+  family TEXT NOT NULL,
+  username TEXT NOT NULL,
+  role Role NOT NULL,
+  expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+  used_at TIMESTAMP WITH TIME ZONE,
+  created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX refresh_tokens_family ON refresh_tokens (family);
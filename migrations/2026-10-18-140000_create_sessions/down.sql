@@ -0,0 +1,10 @@
+DELETE FROM refresh_tokens;
+DROP INDEX refresh_tokens_session_id;
+ALTER TABLE refresh_tokens
+  DROP COLUMN session_id,
+  ADD COLUMN family TEXT NOT NULL,
+  ADD COLUMN username TEXT NOT NULL,
+  ADD COLUMN role Role NOT NULL;
+CREATE INDEX refresh_tokens_family ON refresh_tokens (family);
+
+DROP TABLE sessions;
@@ -0,0 +1,21 @@
+CREATE TABLE sessions (
+  id TEXT PRIMARY KEY,
+  username TEXT NOT NULL,
+  role Role NOT NULL,
+  created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+  last_seen_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+  expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX sessions_username ON sessions (username);
+
+-- Refresh tokens now belong to a session, which holds who they are for. Tokens issued before
+-- sessions existed can't be tied to one, so their holders log in again.
+DELETE FROM refresh_tokens;
+DROP INDEX refresh_tokens_family;
+ALTER TABLE refresh_tokens
+  DROP COLUMN family,
+  DROP COLUMN username,
+  DROP COLUMN role,
+  ADD COLUMN session_id TEXT NOT NULL REFERENCES sessions (id) ON DELETE CASCADE;
+CREATE INDEX refresh_tokens_session_id ON refresh_tokens (session_id);
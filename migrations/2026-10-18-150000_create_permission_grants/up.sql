@@ -0,0 +1,33 @@
+CREATE TYPE Permission AS ENUM (
+  'article.publish',
+  'article.edit',
+  'article.draft',
+  'image.upload',
+  'writer.manage',
+  'drive.read',
+  'drive.move',
+  'submission.create',
+  'submission.review',
+  'site.maintain',
+  'session.manage',
+  'permission.manage'
+);
+
+-- A grant is either to every account with a role or to a single account, and may be limited to
+-- one section. Admins have every permission without any grants.
+CREATE TABLE permission_grants (
+  id SERIAL PRIMARY KEY,
+  role Role,
+  username TEXT,
+  permission Permission NOT NULL,
+  section Section,
+  CHECK ((role IS NULL) <> (username IS NULL))
+);
+
+CREATE INDEX permission_grants_permission ON permission_grants (permission);
+
+-- What editors could do before permissions existed.
+INSERT INTO permission_grants (role, permission) VALUES
+  ('editor', 'article.draft'),
+  ('editor', 'image.upload'),
+  ('editor', 'submission.create');
@@ -0,0 +1,13 @@
+ALTER TABLE article_submission DROP COLUMN status;
+DROP TYPE submission_status;
+
+DROP TABLE writer_accounts;
+
+-- Values can't be removed from an enum, so Role is recreated without 'writer'.
+DELETE FROM sessions WHERE role = 'writer';
+DELETE FROM permission_grants WHERE role = 'writer';
+ALTER TYPE Role RENAME TO Role_Old;
+CREATE TYPE Role AS ENUM ('admin', 'editor', 'default');
+ALTER TABLE sessions ALTER COLUMN role TYPE Role USING role::TEXT::Role;
+ALTER TABLE permission_grants ALTER COLUMN role TYPE Role USING role::TEXT::Role;
+DROP TYPE Role_Old;
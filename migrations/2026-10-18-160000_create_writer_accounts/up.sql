@@ -0,0 +1,15 @@
+ALTER TYPE Role ADD VALUE 'writer';
+
+-- Accounts writers log in with, each linked to their writer profile.
+CREATE TABLE writer_accounts (
+  id SERIAL PRIMARY KEY,
+  username TEXT NOT NULL UNIQUE,
+  password_hash TEXT NOT NULL,
+  writer_id INT NOT NULL UNIQUE REFERENCES writers (id) ON DELETE CASCADE,
+  created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE TYPE submission_status AS ENUM ('submitted', 'accepted', 'rejected');
+
+ALTER TABLE article_submission
+  ADD COLUMN status submission_status NOT NULL DEFAULT 'submitted';
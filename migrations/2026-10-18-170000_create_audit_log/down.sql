@@ -0,0 +1,23 @@
+DROP TABLE audit_log;
+DROP FUNCTION audit_log_append_only;
+
+-- Values can't be removed from an enum, so Permission is recreated without 'audit.read'.
+DELETE FROM permission_grants WHERE permission = 'audit.read';
+ALTER TYPE Permission RENAME TO Permission_Old;
+CREATE TYPE Permission AS ENUM (
+  'article.publish',
+  'article.edit',
+  'article.draft',
+  'image.upload',
+  'writer.manage',
+  'drive.read',
+  'drive.move',
+  'submission.create',
+  'submission.review',
+  'site.maintain',
+  'session.manage',
+  'permission.manage'
+);
+ALTER TABLE permission_grants
+  ALTER COLUMN permission TYPE Permission USING permission::TEXT::Permission;
+DROP TYPE Permission_Old;
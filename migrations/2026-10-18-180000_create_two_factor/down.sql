@@ -0,0 +1,4 @@
+ALTER TABLE sessions DROP COLUMN two_factor;
+DROP TABLE two_factor_policy;
+DROP TABLE two_factor_recovery_codes;
+DROP TABLE two_factor;
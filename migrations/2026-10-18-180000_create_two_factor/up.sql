@@ -0,0 +1,26 @@
+-- The TOTP secret of each username that has set up two-factor authentication. The secret is only
+-- in use once a code from it has been confirmed.
+CREATE TABLE two_factor (
+  username TEXT PRIMARY KEY,
+  secret TEXT NOT NULL,
+  enabled_at TIMESTAMPTZ,
+  -- The time step of the last code used, so no code can be used twice.
+  last_used_step BIGINT,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE two_factor_recovery_codes (
+  id SERIAL PRIMARY KEY,
+  username TEXT NOT NULL REFERENCES two_factor (username) ON DELETE CASCADE,
+  code_hash TEXT NOT NULL,
+  used_at TIMESTAMPTZ
+);
+
+CREATE INDEX two_factor_recovery_codes_username ON two_factor_recovery_codes (username);
+
+-- The roles that get no permissions in sessions started without a second factor.
+CREATE TABLE two_factor_policy (
+  role Role PRIMARY KEY
+);
+
+ALTER TABLE sessions ADD COLUMN two_factor BOOLEAN NOT NULL DEFAULT FALSE;
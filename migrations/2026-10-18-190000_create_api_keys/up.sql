@@ -0,0 +1,14 @@
+-- Keys scripts send as `Authorization: Bearer <key>` instead of logging in. Only the SHA-256 of
+-- each key is stored, along with its first few characters so people can tell keys apart.
+CREATE TABLE api_keys (
+  id SERIAL PRIMARY KEY,
+  name TEXT NOT NULL,
+  key_prefix TEXT NOT NULL,
+  key_hash TEXT NOT NULL UNIQUE,
+  permissions Permission[] NOT NULL,
+  created_by TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  expires_at TIMESTAMPTZ NOT NULL,
+  last_used_at TIMESTAMPTZ,
+  revoked_at TIMESTAMPTZ
+);
@@ -0,0 +1,5 @@
+-- Sessions started before this get a random token too, which clients pick up from
+-- /api/csrf-token.
+CREATE EXTENSION IF NOT EXISTS pgcrypto;
+ALTER TABLE sessions ADD COLUMN csrf_token TEXT NOT NULL DEFAULT encode(gen_random_bytes(32), 'base64');
+ALTER TABLE sessions ALTER COLUMN csrf_token DROP DEFAULT;
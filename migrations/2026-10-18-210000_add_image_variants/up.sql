@@ -0,0 +1,3 @@
+-- The scaled down and WebP copies made of each image when it was stored, as a JSON array of
+-- `{"url", "width", "content_type"}`, so they can be listed without going to storage.
+ALTER TABLE images ADD COLUMN variants JSONB NOT NULL DEFAULT '[]';
@@ -1,9 +1,11 @@
-use crate::auth::AdminUser;
+use crate::auth::permission::{Permitted, RequiredPermission};
 use crate::error::{APIError, APIResult};
+use crate::images::{self, library, ImageVariant};
 use crate::schema::articles;
 use crate::section::Section;
 use crate::writer::DBWriter;
 use chrono::{DateTime, Utc};
+use diesel::PgConnection;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -78,33 +80,84 @@ pub struct ServerArticle {
     pub section: Section,
     pub publication_date: DateTime<Utc>,
     pub image_url: String,
+    /// Smaller and WebP copies of the image at `image_url`, for building a `srcset`.
+    pub image_variants: Vec<ImageVariant>,
     pub drive_file_id: Option<String>,
     pub featured: bool,
 }
 
 impl ServerArticle {
-    pub fn new(article: DBArticle, writer: DBWriter, user: Option<AdminUser>) -> APIResult<Self> {
+    /// `viewer` is told which Drive file the article came from if their permission covers its
+    /// section.
+    pub fn new<P: RequiredPermission>(
+        db_connection: &PgConnection,
+        article: DBArticle,
+        writer: DBWriter,
+        viewer: Option<&Permitted<P>>,
+    ) -> APIResult<Self> {
         let content = serde_json::from_str(&article.body).map_err(|_| APIError::default())?;
-        Ok(ServerArticle {
-            id: article.id,
-            headline: article.headline,
-            slug: article.slug,
+        Self::with_content(db_connection, article, content, writer, viewer)
+    }
+
+    /// Like [`ServerArticle::new`], for an article whose content has already been parsed.
+    pub fn with_content<P: RequiredPermission>(
+        db_connection: &PgConnection,
+        article: DBArticle,
+        content: ArticleContent,
+        writer: DBWriter,
+        viewer: Option<&Permitted<P>>,
+    ) -> APIResult<Self> {
+        let image_variants = match &article.image_url {
+            Some(url) => library::variants_of(db_connection, url)?,
+            None => Vec::new(),
+        };
+        Ok(Self::with_variants(
+            article,
             content,
             writer,
-            section: article.section,
-            publication_date: article.publication_date,
-            focus: article.focus,
-            image_url: article.image_url.unwrap_or_default(),
-            drive_file_id: user.and(article.drive_file_id),
-            featured: article.featured,
-        })
+            image_variants,
+            viewer,
+        ))
+    }
+
+    /// [`ServerArticle::new`] for a page of articles, looking up all of their images' variants in
+    /// one query.
+    pub fn list<P: RequiredPermission>(
+        db_connection: &PgConnection,
+        rows: Vec<(DBArticle, DBWriter)>,
+        viewer: Option<&Permitted<P>>,
+    ) -> APIResult<Vec<Self>> {
+        let urls: Vec<&str> = rows
+            .iter()
+            .filter_map(|(article, _)| article.image_url.as_deref())
+            .collect();
+        let variants = library::variants_for(db_connection, &urls)?;
+        rows.into_iter()
+            .map(|(article, writer)| {
+                let content =
+                    serde_json::from_str(&article.body).map_err(|_| APIError::default())?;
+                let image_variants = article
+                    .image_url
+                    .as_ref()
+                    .and_then(|url| variants.get(url).cloned())
+                    .unwrap_or_default();
+                Ok(Self::with_variants(
+                    article,
+                    content,
+                    writer,
+                    image_variants,
+                    viewer,
+                ))
+            })
+            .collect()
     }
 
-    pub fn with_content(
+    fn with_variants<P: RequiredPermission>(
         article: DBArticle,
         content: ArticleContent,
         writer: DBWriter,
-        user: Option<AdminUser>,
+        image_variants: Vec<ImageVariant>,
+        viewer: Option<&Permitted<P>>,
     ) -> Self {
         ServerArticle {
             id: article.id,
@@ -115,8 +168,15 @@ impl ServerArticle {
             section: article.section,
             publication_date: article.publication_date,
             focus: article.focus,
-            image_url: article.image_url.unwrap_or_default(),
-            drive_file_id: user.and(article.drive_file_id),
+            image_variants,
+            image_url: article
+                .image_url
+                .as_deref()
+                .map(images::public_url)
+                .unwrap_or_default(),
+            drive_file_id: article
+                .drive_file_id
+                .filter(|_| viewer.is_some_and(|viewer| viewer.allows(article.section))),
             featured: article.featured,
         }
     }
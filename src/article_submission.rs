@@ -1,7 +1,17 @@
 use crate::schema::*;
 use crate::section::Section;
+use diesel_derive_enum::DbEnum;
 use serde::{Deserialize, Serialize};
 
+/// Where a submission is in review.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DbEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SubmissionStatus {
+    Submitted,
+    Accepted,
+    Rejected,
+}
+
 #[derive(Queryable, Debug, Serialize)]
 pub struct DBArticleSubmission {
     pub id: i32,
@@ -11,6 +21,7 @@ pub struct DBArticleSubmission {
     pub author_id: i32,
     pub drive_file_id: String,
     pub thumbnail_url: Option<String>,
+    pub status: SubmissionStatus,
 }
 
 /// What the client receives when they request an article submission.
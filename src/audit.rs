@@ -0,0 +1,328 @@
+//! An append-only record of every change made through the API.
+//!
+//! Endpoints that change something take an [`Actor`] and call [`Actor::record`] in the same
+//! transaction as the change, with what was changed and how, so nothing changes without an entry
+//! saying so. Only the fields that changed are kept, so changing a writer's bio records the old
+//! and new bio and nothing else. The table refuses updates and deletes, so entries can't be edited
+//! or cleared through the API or by hand.
+//!
+//! Failed logins are recorded too, without an actor. Refreshing a session isn't recorded, as
+//! clients do it every few minutes and it changes nothing but the session's refresh token.
+
+use crate::auth::session::DBSession;
+use crate::auth::{current_principal, Principal, Role};
+use crate::client_ip::client_ip;
+use crate::schema::audit_log;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use rocket::request::{FromRequest, Outcome, Request};
+use serde::Serialize;
+use serde_json::Value;
+use std::convert::Infallible;
+
+#[derive(Queryable, Debug, Serialize)]
+pub struct DBAuditEntry {
+    pub id: i32,
+    pub actor: Option<String>,
+    pub role: Option<Role>,
+    pub action: String,
+    pub target_type: String,
+    pub target_id: Option<String>,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    pub ip_address: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[table_name = "audit_log"]
+struct NewAuditEntry<'a> {
+    actor: Option<&'a str>,
+    role: Option<Role>,
+    action: &'a str,
+    target_type: &'a str,
+    target_id: Option<String>,
+    before: Option<Value>,
+    after: Option<Value>,
+    ip_address: Option<String>,
+}
+
+/// What an action was done to, recorded as a type and an id.
+pub enum Target<'a> {
+    Article(i32),
+    Writer(i32),
+    /// The account of the writer with the id.
+    WriterAccount(i32),
+    Image(i32),
+    Submission(i32),
+    PermissionGrant(i32),
+    ApiKey(i32),
+    Session(&'a str),
+    /// Someone logging in or out, or all of their sessions.
+    User(&'a str),
+    /// The two-factor authentication of the username.
+    TwoFactor(&'a str),
+    /// Which roles have to use two-factor authentication.
+    TwoFactorPolicy,
+    DriveFile(&'a str),
+    /// An imported document, which isn't stored anywhere.
+    Document,
+    /// The whole site, for maintenance jobs.
+    Site,
+}
+
+impl Target<'_> {
+    fn kind(&self) -> &'static str {
+        match self {
+            Target::Article(_) => "article",
+            Target::Writer(_) => "writer",
+            Target::WriterAccount(_) => "writer_account",
+            Target::Image(_) => "image",
+            Target::Submission(_) => "submission",
+            Target::PermissionGrant(_) => "permission_grant",
+            Target::ApiKey(_) => "api_key",
+            Target::Session(_) => "session",
+            Target::User(_) => "user",
+            Target::TwoFactor(_) => "two_factor",
+            Target::TwoFactorPolicy => "two_factor_policy",
+            Target::DriveFile(_) => "drive_file",
+            Target::Document => "document",
+            Target::Site => "site",
+        }
+    }
+
+    fn id(&self) -> Option<String> {
+        match self {
+            Target::Article(id)
+            | Target::Writer(id)
+            | Target::WriterAccount(id)
+            | Target::Image(id)
+            | Target::Submission(id)
+            | Target::PermissionGrant(id)
+            | Target::ApiKey(id) => Some(id.to_string()),
+            Target::Session(id)
+            | Target::User(id)
+            | Target::TwoFactor(id)
+            | Target::DriveFile(id) => Some(id.to_string()),
+            Target::TwoFactorPolicy | Target::Document | Target::Site => None,
+        }
+    }
+}
+
+/// The state of what an action was done to, before and after it.
+#[derive(Debug, Default, PartialEq)]
+pub struct Change {
+    before: Option<Value>,
+    after: Option<Value>,
+}
+
+impl Change {
+    /// An action with nothing to record beyond that it happened.
+    pub fn none() -> Change {
+        Change::default()
+    }
+
+    pub fn created(after: &impl Serialize) -> Change {
+        Change {
+            before: None,
+            after: to_json(after),
+        }
+    }
+
+    /// Keeps only the fields that differ between `before` and `after`.
+    pub fn updated(before: &impl Serialize, after: &impl Serialize) -> Change {
+        let (before, after) = match (to_json(before), to_json(after)) {
+            (Some(Value::Object(mut before)), Some(Value::Object(mut after))) => {
+                let unchanged: Vec<String> = before
+                    .iter()
+                    .filter(|(key, value)| after.get(*key) == Some(value))
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                for key in unchanged {
+                    before.remove(&key);
+                    after.remove(&key);
+                }
+                (Some(Value::Object(before)), Some(Value::Object(after)))
+            }
+            (before, after) => (before, after),
+        };
+        Change { before, after }
+    }
+
+    pub fn deleted(before: &impl Serialize) -> Change {
+        Change {
+            before: to_json(before),
+            after: None,
+        }
+    }
+
+    /// The outcome of an action that doesn't change a single thing, like a maintenance job.
+    pub fn result(result: &impl Serialize) -> Change {
+        Change::created(result)
+    }
+}
+
+fn to_json(value: &impl Serialize) -> Option<Value> {
+    match serde_json::to_value(value) {
+        Ok(value) => Some(value),
+        Err(err) => {
+            println!("Could not serialize audit log state: {err}");
+            None
+        }
+    }
+}
+
+/// Whoever is making a request, for the audit log. Requests from anyone not logged in are
+/// recorded without an actor, so this never fails. API keys are recorded as `api_key:<id>`,
+/// without a role, and jobs the server runs by itself as `job:<name>`.
+pub struct Actor {
+    username: Option<String>,
+    role: Option<Role>,
+    ip_address: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Actor {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let (username, role) = match current_principal(req).await {
+            Some(Principal::Session(session)) => (Some(session.username), Some(session.role)),
+            Some(Principal::ApiKey(api_key)) => (Some(api_key.actor_name()), None),
+            None => (None, None),
+        };
+        Outcome::Success(Actor {
+            username,
+            role,
+            ip_address: client_ip(req).map(|ip| ip.to_string()),
+        })
+    }
+}
+
+impl Actor {
+    /// A job the server runs on a schedule, rather than because of a request.
+    pub fn job(name: &str) -> Actor {
+        Actor {
+            username: Some(format!("job:{name}")),
+            role: None,
+            ip_address: None,
+        }
+    }
+
+    /// The actor once they have logged in to `session`.
+    pub fn logged_in(self, session: &DBSession) -> Actor {
+        Actor {
+            username: Some(session.username.clone()),
+            role: Some(session.role),
+            ..self
+        }
+    }
+
+    /// Appends an entry to the audit log. Call it in the same transaction as the change, so the
+    /// change is rolled back if it can't be recorded.
+    pub fn record(
+        &self,
+        db_connection: &PgConnection,
+        action: &str,
+        target: Target<'_>,
+        change: Change,
+    ) -> QueryResult<()> {
+        let entry = NewAuditEntry {
+            actor: self.username.as_deref(),
+            role: self.role,
+            action,
+            target_type: target.kind(),
+            target_id: target.id(),
+            before: change.before,
+            after: change.after,
+            ip_address: self.ip_address.clone(),
+        };
+        diesel::insert_into(audit_log::table)
+            .values(&entry)
+            .execute(db_connection)?;
+        Ok(())
+    }
+}
+
+/// What the audit log can be filtered by.
+#[derive(Default)]
+pub struct AuditFilter<'a> {
+    pub actor: Option<&'a str>,
+    pub action: Option<&'a str>,
+    pub target_type: Option<&'a str>,
+    pub target_id: Option<&'a str>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl AuditFilter<'_> {
+    fn apply<'a>(
+        &self,
+        mut query: audit_log::BoxedQuery<'a, diesel::pg::Pg>,
+    ) -> audit_log::BoxedQuery<'a, diesel::pg::Pg> {
+        use crate::schema::audit_log::dsl;
+
+        if let Some(actor) = self.actor {
+            query = query.filter(dsl::actor.eq(actor.to_owned()));
+        }
+        if let Some(action) = self.action {
+            query = query.filter(dsl::action.eq(action.to_owned()));
+        }
+        if let Some(target_type) = self.target_type {
+            query = query.filter(dsl::target_type.eq(target_type.to_owned()));
+        }
+        if let Some(target_id) = self.target_id {
+            query = query.filter(dsl::target_id.eq(target_id.to_owned()));
+        }
+        if let Some(since) = self.since {
+            query = query.filter(dsl::created_at.ge(since));
+        }
+        if let Some(until) = self.until {
+            query = query.filter(dsl::created_at.lt(until));
+        }
+        query
+    }
+}
+
+/// A page of the entries matching `filter`, newest first, along with how many match in total.
+pub fn list(
+    db_connection: &PgConnection,
+    filter: &AuditFilter<'_>,
+    limit: i64,
+    page: i64,
+) -> QueryResult<(Vec<DBAuditEntry>, i64)> {
+    use crate::schema::audit_log::dsl;
+
+    let count = filter
+        .apply(dsl::audit_log.into_boxed())
+        .count()
+        .get_result(db_connection)?;
+    let entries = filter
+        .apply(dsl::audit_log.into_boxed())
+        .order(dsl::id.desc())
+        .offset((page - 1) * limit)
+        .limit(limit)
+        .load(db_connection)?;
+    Ok((entries, count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn updates_only_keep_changed_fields() {
+        let change = Change::updated(
+            &json!({"id": 1, "bio": "old", "title": "Staff"}),
+            &json!({"id": 1, "bio": "new", "title": "Staff"}),
+        );
+        assert_eq!(
+            change,
+            Change {
+                before: Some(json!({"bio": "old"})),
+                after: Some(json!({"bio": "new"})),
+            }
+        );
+    }
+}
@@ -1,11 +1,32 @@
+use crate::config::{AppConfig, Credentials, JwtKeys};
 use crate::error::APIError;
+use api_key::DBApiKey;
 use chrono::Utc;
-use jsonwebtoken::errors::Result;
+use diesel::PgConnection;
+use diesel_derive_enum::DbEnum;
+use jsonwebtoken::errors::{ErrorKind, Result};
 use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use rocket::request::{FromRequest, Outcome, Request};
+use rocket::State;
 use serde::{Deserialize, Serialize};
+use session::DBSession;
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+use subtle::ConstantTimeEq;
+
+pub mod account;
+pub mod api_key;
+pub mod permission;
+pub mod refresh;
+pub mod session;
+pub mod throttle;
+pub mod two_factor;
 
 pub const COOKIE_SESSION_TOKEN: &str = "session_token";
+pub const COOKIE_REFRESH_TOKEN: &str = "refresh_token";
+
+/// How long an access token lasts. Clients swap their refresh token for a new one once it expires.
+const ACCESS_TOKEN_MINUTES: i64 = 15;
 
 pub struct User(pub Role);
 
@@ -13,127 +34,266 @@ pub struct User(pub Role);
 impl<'r> FromRequest<'r> for User {
     type Error = APIError;
     async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let jwt_secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
-
-        let jwt = match req.cookies().get(COOKIE_SESSION_TOKEN) {
-            Some(jwt) => jwt.value(),
-            None => return Outcome::Forward(()),
-        };
+        if req.cookies().get(COOKIE_SESSION_TOKEN).is_none() {
+            return Outcome::Forward(());
+        }
 
-        match decode::<Claims>(
-            jwt,
-            &DecodingKey::from_secret(jwt_secret.as_bytes()),
-            &Validation::new(Algorithm::HS512),
-        ) {
-            Ok(decoded_jwt) => match decoded_jwt.claims.role {
-                Role::Admin => Outcome::Success(User(Role::Admin)),
-                Role::Editor => Outcome::Success(User(Role::Editor)),
-                Role::Default => Outcome::Success(User(Role::Default)),
-            },
-            Err(_) => Outcome::Success(User(Role::Default)),
+        match current_session(req).await {
+            Some(session) => Outcome::Success(User(session.role)),
+            None => Outcome::Success(User(Role::Default)),
         }
     }
 }
 
-#[derive(Clone, Copy)]
-pub struct AdminUser;
+/// The session of whoever is logged in, whatever their role.
+pub struct CurrentSession(pub DBSession);
 
 #[rocket::async_trait]
-impl<'r> FromRequest<'r> for AdminUser {
+impl<'r> FromRequest<'r> for CurrentSession {
     type Error = APIError;
+
     async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let jwt_secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+        match current_session(req).await {
+            Some(session) => Outcome::Success(CurrentSession(session.clone())),
+            None => Outcome::Forward(()),
+        }
+    }
+}
 
-        let jwt = match req.cookies().get(COOKIE_SESSION_TOKEN) {
-            Some(jwt) => jwt.value(),
-            None => return Outcome::Forward(()),
-        };
+/// Whoever a request is made by: someone logged in, or a script with an API key.
+#[derive(Clone, Debug)]
+pub enum Principal {
+    Session(DBSession),
+    ApiKey(DBApiKey),
+}
 
-        match decode::<Claims>(
-            jwt,
-            &DecodingKey::from_secret(jwt_secret.as_bytes()),
-            &Validation::new(Algorithm::HS512),
-        ) {
-            Ok(decoded_jwt) => {
-                if decoded_jwt.claims.role == Role::Admin {
-                    Outcome::Success(AdminUser)
-                } else {
-                    // Not enough permission
-                    Outcome::Forward(())
-                }
-            }
-            Err(_) => Outcome::Forward(()),
+impl Principal {
+    /// The role of whoever is logged in, or `ApiKey` for a key.
+    pub fn role_name(&self) -> &'static str {
+        match self {
+            Principal::Session(session) => session.role.as_str(),
+            Principal::ApiKey(_) => "ApiKey",
         }
     }
 }
 
-#[derive(Clone, Copy)]
-pub struct EditorUser;
+/// Someone logged in with a writer account, along with the writer it belongs to.
+pub struct WriterUser {
+    pub writer_id: i32,
+}
 
 #[rocket::async_trait]
-impl<'r> FromRequest<'r> for EditorUser {
+impl<'r> FromRequest<'r> for WriterUser {
     type Error = APIError;
 
     async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let jwt_secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
-
-        let jwt = match req.cookies().get(COOKIE_SESSION_TOKEN) {
-            Some(jwt) => jwt.value(),
-            None => return Outcome::Forward(()),
+        let Some(session) = current_session(req)
+            .await
+            .filter(|s| s.role == Role::Writer)
+        else {
+            return Outcome::Forward(());
         };
+        let db_connection = match req.guard::<&State<Mutex<PgConnection>>>().await {
+            Outcome::Success(db_connection) => db_connection,
+            _ => return Outcome::Forward(()),
+        };
+        let Ok(db_connection) = db_connection.lock() else {
+            return Outcome::Forward(());
+        };
+
+        match two_factor::satisfied(&db_connection, session) {
+            Ok(true) => {}
+            Ok(false) => return Outcome::Forward(()),
+            Err(err) => {
+                println!("Could not look up the two-factor policy: {err}");
+                return Outcome::Forward(());
+            }
+        }
+        match account::find_by_username(&db_connection, &session.username) {
+            Ok(Some(account)) => Outcome::Success(WriterUser {
+                writer_id: account.writer_id,
+            }),
+            Ok(None) => Outcome::Forward(()),
+            Err(err) => {
+                println!(
+                    "Could not look up the account of {}: {err}",
+                    session.username
+                );
+                Outcome::Forward(())
+            }
+        }
+    }
+}
+
+/// Cached on a request whose access token has expired, see [`session_expired`].
+struct ExpiredSession(bool);
+
+/// The session named by the request's access token, if it is valid and the session is still
+/// active. Looked up once per request, however many guards ask.
+pub(crate) async fn current_session<'r>(req: &'r Request<'_>) -> Option<&'r DBSession> {
+    struct CachedSession(Option<DBSession>);
+
+    let cached = req
+        .local_cache_async(async {
+            let Some(claims) = access_token_claims(req) else {
+                return CachedSession(None);
+            };
+            let db_connection = match req.guard::<&State<Mutex<PgConnection>>>().await {
+                Outcome::Success(db_connection) => db_connection,
+                _ => return CachedSession(None),
+            };
+            let Ok(db_connection) = db_connection.lock() else {
+                return CachedSession(None);
+            };
+            match session::find_active(&db_connection, &claims.jti) {
+                Ok(session) => CachedSession(session),
+                Err(err) => {
+                    println!("Could not look up session {}: {err}", claims.jti);
+                    CachedSession(None)
+                }
+            }
+        })
+        .await;
+    cached.0.as_ref()
+}
+
+/// The API key in the request's `Authorization: Bearer` header, if it is valid. Looked up once
+/// per request, however many guards ask.
+pub(crate) async fn current_api_key<'r>(req: &'r Request<'_>) -> Option<&'r DBApiKey> {
+    struct CachedApiKey(Option<DBApiKey>);
+
+    let cached = req
+        .local_cache_async(async {
+            let Some(key) = req
+                .headers()
+                .get_one("Authorization")
+                .and_then(|header| header.strip_prefix("Bearer "))
+            else {
+                return CachedApiKey(None);
+            };
+            let db_connection = match req.guard::<&State<Mutex<PgConnection>>>().await {
+                Outcome::Success(db_connection) => db_connection,
+                _ => return CachedApiKey(None),
+            };
+            let Ok(db_connection) = db_connection.lock() else {
+                return CachedApiKey(None);
+            };
+            match api_key::authenticate(&db_connection, key.trim(), Utc::now()) {
+                Ok(api_key) => CachedApiKey(api_key),
+                Err(err) => {
+                    println!("Could not look up API key: {err}");
+                    CachedApiKey(None)
+                }
+            }
+        })
+        .await;
+    cached.0.as_ref()
+}
+
+/// The session of whoever is logged in, or failing that the request's API key.
+pub(crate) async fn current_principal(req: &Request<'_>) -> Option<Principal> {
+    if let Some(session) = current_session(req).await {
+        return Some(Principal::Session(session.clone()));
+    }
+    current_api_key(req).await.cloned().map(Principal::ApiKey)
+}
 
+/// Decodes the request's access token, remembering if it was only rejected for having expired.
+fn access_token_claims(req: &Request<'_>) -> Option<Claims> {
+    let jwt_keys = &req.rocket().state::<AppConfig>()?.jwt_keys;
+    let jwt = req.cookies().get(COOKIE_SESSION_TOKEN)?;
+
+    // Tokens signed with a previous secret still work until they expire.
+    let mut expired = false;
+    for secret in jwt_keys.verifying() {
         match decode::<Claims>(
-            jwt,
-            &DecodingKey::from_secret(jwt_secret.as_bytes()),
+            jwt.value(),
+            &DecodingKey::from_secret(secret.as_bytes()),
             &Validation::new(Algorithm::HS512),
         ) {
-            Ok(decoded_jwt) => {
-                if decoded_jwt.claims.role == Role::Admin || decoded_jwt.claims.role == Role::Editor
-                {
-                    Outcome::Success(EditorUser)
-                } else {
-                    Outcome::Forward(())
-                }
-            }
-            Err(_) => Outcome::Forward(()),
+            Ok(decoded_jwt) => return Some(decoded_jwt.claims),
+            Err(err) => expired |= *err.kind() == ErrorKind::ExpiredSignature,
         }
     }
+    if expired {
+        req.local_cache(|| ExpiredSession(true));
+    }
+    None
+}
+
+/// Whether a guard turned the request away because its access token expired, in which case the
+/// client can get a new one from `/api/refresh` rather than logging in again.
+pub fn session_expired(req: &Request<'_>) -> bool {
+    req.local_cache(|| ExpiredSession(false)).0
 }
 
 #[derive(Serialize, Deserialize)]
 struct Claims {
-    role: Role,
+    /// The username the session is for.
+    sub: String,
+    /// The id of the session, which has to still be active for the token to be accepted.
+    jti: String,
     exp: i64,
 }
 
-#[derive(Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, DbEnum, Serialize, Deserialize)]
 pub enum Role {
     Admin,
     Editor,
     Default,
+    /// A writer logged in with their own account, see [`account`].
+    Writer,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Admin => "Admin",
+            Role::Editor => "Editor",
+            Role::Default => "Default",
+            Role::Writer => "Writer",
+        }
+    }
 }
 
 #[derive(Deserialize)]
 pub struct LoginInfo<'a> {
     pub username: &'a str,
     pub password: &'a str,
+    /// A code from an authenticator app, or a recovery code, for accounts with two-factor
+    /// authentication.
+    pub code: Option<&'a str>,
 }
 
-pub fn create_jwt(role: Role) -> Result<String> {
-    let jwt_secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+impl LoginInfo<'_> {
+    /// Compares against a username and password in constant time, so how long it takes doesn't
+    /// give away how much of either was right. Both are hashed first, so neither does their length.
+    pub fn matches(&self, credentials: &Credentials) -> bool {
+        let digest = |value: &str| Sha256::digest(value.as_bytes());
+        let username_matches = digest(self.username).ct_eq(&digest(&credentials.username));
+        let password_matches = digest(self.password).ct_eq(&digest(&credentials.password));
+        (username_matches & password_matches).into()
+    }
+}
 
+/// Creates an access token for the session, signed with the current secret.
+pub fn create_jwt(jwt_keys: &JwtKeys, session: &DBSession) -> Result<String> {
     let exp = Utc::now()
-        .checked_add_signed(chrono::Duration::minutes(90))
+        .checked_add_signed(chrono::Duration::minutes(ACCESS_TOKEN_MINUTES))
         .expect("valid timestamp")
         .timestamp();
 
-    let claims = Claims { role, exp };
+    let claims = Claims {
+        sub: session.username.clone(),
+        jti: session.id.clone(),
+        exp,
+    };
 
     let header = Header::new(Algorithm::HS512);
 
     encode(
         &header,
         &claims,
-        &EncodingKey::from_secret(jwt_secret.as_bytes()),
+        &EncodingKey::from_secret(jwt_keys.current.as_bytes()),
     )
 }
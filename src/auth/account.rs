@@ -0,0 +1,118 @@
+//! Accounts writers log in with, each linked to their writer profile.
+//!
+//! Passwords are stored as Argon2id hashes in the PHC string format.
+
+use crate::schema::writer_accounts;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::Serialize;
+
+/// Shorter passwords are refused when creating an account.
+pub const MIN_PASSWORD_LENGTH: usize = 10;
+
+#[derive(Queryable, Debug, Serialize)]
+pub struct DBWriterAccount {
+    pub id: i32,
+    pub username: String,
+    #[serde(skip)]
+    pub password_hash: String,
+    pub writer_id: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[table_name = "writer_accounts"]
+struct NewWriterAccount<'a> {
+    username: &'a str,
+    password_hash: String,
+    writer_id: i32,
+}
+
+/// Hashes a password with a fresh salt, for [`create`] to store.
+pub fn hash_password(password: &str) -> anyhow::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| anyhow::anyhow!("could not hash password: {err}"))
+}
+
+/// Creates an account for the writer, failing with a unique violation if the writer or the
+/// username already has one.
+pub fn create(
+    db_connection: &PgConnection,
+    writer_id: i32,
+    username: &str,
+    password_hash: String,
+) -> QueryResult<DBWriterAccount> {
+    diesel::insert_into(writer_accounts::table)
+        .values(NewWriterAccount {
+            username,
+            password_hash,
+            writer_id,
+        })
+        .get_result(db_connection)
+}
+
+pub fn find_by_username(
+    db_connection: &PgConnection,
+    username: &str,
+) -> QueryResult<Option<DBWriterAccount>> {
+    use crate::schema::writer_accounts::dsl;
+
+    dsl::writer_accounts
+        .filter(dsl::username.eq(username))
+        .first(db_connection)
+        .optional()
+}
+
+/// Deletes the writer's account, returning it if there was one.
+pub fn delete_for_writer(
+    db_connection: &PgConnection,
+    writer_id: i32,
+) -> QueryResult<Option<DBWriterAccount>> {
+    use crate::schema::writer_accounts::dsl;
+
+    diesel::delete(dsl::writer_accounts.filter(dsl::writer_id.eq(writer_id)))
+        .get_result(db_connection)
+        .optional()
+}
+
+/// The account found by [`find_by_username`], if the password is right.
+///
+/// This doesn't need the database, so call it after letting go of the connection, as checking a
+/// password takes a while.
+pub fn authenticate(account: Option<DBWriterAccount>, password: &str) -> Option<DBWriterAccount> {
+    lazy_static::lazy_static! {
+        static ref UNUSED_HASH: String =
+            hash_password("not anyone's password").expect("hashing a password works");
+    }
+
+    match account {
+        Some(account) => Some(account).filter(|account| account.verify_password(password)),
+        None => {
+            // Take as long as checking a real password, so nobody can time which usernames exist.
+            let _ = verify(&UNUSED_HASH, password);
+            None
+        }
+    }
+}
+
+fn verify(password_hash: &str, password: &str) -> Result<bool, argon2::password_hash::Error> {
+    let hash = PasswordHash::new(password_hash)?;
+    Ok(Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_ok())
+}
+
+impl DBWriterAccount {
+    pub fn verify_password(&self, password: &str) -> bool {
+        verify(&self.password_hash, password).unwrap_or_else(|err| {
+            println!("Password hash of {} is invalid: {err}", self.username);
+            false
+        })
+    }
+}
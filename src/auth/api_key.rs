@@ -0,0 +1,213 @@
+//! API keys, which scripts send as `Authorization: Bearer <key>` instead of logging in.
+//!
+//! Each key carries the permissions it was created with, in every section, and expires at a set
+//! time. Those have to be permissions its creator has in every section, and never
+//! [`REFUSED_PERMISSIONS`], so a key can't do more than whoever made it or hand out access. Only
+//! the SHA-256 of a key is stored, so it's shown once when created and never again.
+//! Revoked keys are kept, so the audit log and the list of keys still say what they were.
+
+use super::permission::{GrantedPermission, Permission, Scope};
+use crate::schema::api_keys;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Permissions no key can have, as they would let a leaked key grant access or end sessions.
+pub const REFUSED_PERMISSIONS: [Permission; 2] =
+    [Permission::PermissionManage, Permission::SessionManage];
+
+/// Starts every key, so they're easy to spot in scripts and logs.
+const KEY_PREFIX: &str = "bh_";
+/// How much of a key is stored in the clear, the [`KEY_PREFIX`] included.
+const SHOWN_LENGTH: usize = 11;
+
+#[derive(Queryable, Clone, Debug, Serialize)]
+pub struct DBApiKey {
+    pub id: i32,
+    pub name: String,
+    /// The first few characters of the key.
+    pub key_prefix: String,
+    #[serde(skip)]
+    _key_hash: String,
+    pub permissions: Vec<Permission>,
+    /// The username of whoever created the key.
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// What the client sends to create a key.
+#[derive(Deserialize, Debug)]
+pub struct ClientApiKey {
+    pub name: String,
+    pub permissions: Vec<Permission>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[table_name = "api_keys"]
+struct NewApiKey<'a> {
+    name: &'a str,
+    key_prefix: &'a str,
+    key_hash: String,
+    permissions: &'a [Permission],
+    created_by: &'a str,
+    expires_at: DateTime<Utc>,
+}
+
+impl DBApiKey {
+    /// How the key is named in the audit log.
+    pub fn actor_name(&self) -> String {
+        format!("api_key:{}", self.id)
+    }
+}
+
+/// Why a key with the permissions asked for can't be created by someone `granted` these, if it
+/// can't.
+pub fn check_permissions(
+    new_key: &ClientApiKey,
+    granted: &[GrantedPermission],
+) -> Result<(), String> {
+    for permission in &new_key.permissions {
+        if REFUSED_PERMISSIONS.contains(permission) {
+            return Err(format!(
+                "API keys can't have the {} permission.",
+                permission.as_str()
+            ));
+        }
+        let everywhere = granted
+            .iter()
+            .any(|granted| granted.permission == *permission && granted.sections == Scope::All);
+        if !everywhere {
+            return Err(format!(
+                "API keys have their permissions in every section, and you don't have {} in \
+                 every section.",
+                permission.as_str()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Creates a key, returning it along with the key itself. Its permissions are checked with
+/// [`check_permissions`] first.
+pub fn create(
+    db_connection: &PgConnection,
+    new_key: &ClientApiKey,
+    created_by: &str,
+) -> QueryResult<(DBApiKey, String)> {
+    let key = format!(
+        "{KEY_PREFIX}{}",
+        base64::encode_config(
+            rand::thread_rng().gen::<[u8; 32]>(),
+            base64::URL_SAFE_NO_PAD,
+        )
+    );
+
+    let created = diesel::insert_into(api_keys::table)
+        .values(NewApiKey {
+            name: &new_key.name,
+            key_prefix: &key[..SHOWN_LENGTH],
+            key_hash: hash(&key),
+            permissions: &new_key.permissions,
+            created_by,
+            expires_at: new_key.expires_at,
+        })
+        .get_result(db_connection)?;
+    Ok((created, key))
+}
+
+/// The key, unless it is unknown, expired or revoked, noting that it was just used.
+pub fn authenticate(
+    db_connection: &PgConnection,
+    key: &str,
+    now: DateTime<Utc>,
+) -> QueryResult<Option<DBApiKey>> {
+    use crate::schema::api_keys::dsl;
+
+    diesel::update(
+        dsl::api_keys
+            .filter(dsl::key_hash.eq(hash(key)))
+            .filter(dsl::expires_at.gt(now))
+            .filter(dsl::revoked_at.is_null()),
+    )
+    .set(dsl::last_used_at.eq(now))
+    .get_result(db_connection)
+    .optional()
+}
+
+/// Every key, newest first.
+pub fn list(db_connection: &PgConnection) -> QueryResult<Vec<DBApiKey>> {
+    use crate::schema::api_keys::dsl;
+
+    dsl::api_keys.order(dsl::id.desc()).load(db_connection)
+}
+
+/// Revokes a key, returning whether there was one that wasn't revoked already.
+pub fn revoke(db_connection: &PgConnection, id: i32) -> QueryResult<bool> {
+    use crate::schema::api_keys::dsl;
+
+    let revoked = diesel::update(dsl::api_keys.find(id).filter(dsl::revoked_at.is_null()))
+        .set(dsl::revoked_at.eq(Utc::now()))
+        .execute(db_connection)?;
+    Ok(revoked > 0)
+}
+
+/// Revokes every key `username` created, returning how many were still in use.
+pub fn revoke_created_by(db_connection: &PgConnection, username: &str) -> QueryResult<usize> {
+    use crate::schema::api_keys::dsl;
+
+    diesel::update(
+        dsl::api_keys
+            .filter(dsl::created_by.eq(username))
+            .filter(dsl::revoked_at.is_null()),
+    )
+    .set(dsl::revoked_at.eq(Utc::now()))
+    .execute(db_connection)
+}
+
+fn hash(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::section::Section;
+
+    fn key(permissions: &[Permission]) -> ClientApiKey {
+        ClientApiKey {
+            name: "script".into(),
+            permissions: permissions.to_vec(),
+            expires_at: Utc::now(),
+        }
+    }
+
+    fn granted(permission: Permission, sections: Scope) -> GrantedPermission {
+        GrantedPermission {
+            permission,
+            sections,
+        }
+    }
+
+    #[test]
+    fn only_allows_permissions_the_creator_has_everywhere() {
+        let creator = [
+            granted(Permission::ImageUpload, Scope::All),
+            granted(
+                Permission::ArticleEdit,
+                Scope::Sections(vec![Section::News]),
+            ),
+            granted(Permission::PermissionManage, Scope::All),
+        ];
+
+        assert!(check_permissions(&key(&[Permission::ImageUpload]), &creator).is_ok());
+        assert!(check_permissions(&key(&[Permission::ArticleEdit]), &creator).is_err());
+        assert!(check_permissions(&key(&[Permission::SiteMaintain]), &creator).is_err());
+        assert!(check_permissions(&key(&[Permission::PermissionManage]), &creator).is_err());
+    }
+}
@@ -0,0 +1,359 @@
+//! What each account is allowed to do.
+//!
+//! Permissions are granted to every account with a role, or to a single account, and grants of
+//! the permissions that concern articles or submissions can be limited to one section, so the
+//! sports editor can publish sports articles only. Admins have every permission.
+//!
+//! Endpoints ask for a permission with the [`Permitted`] guard, e.g.
+//! `Option<Permitted<perm::ArticlePublish>>`, and check the section of whatever they act on with
+//! [`Permitted::require`].
+//!
+//! Sessions that don't meet the two-factor policy have no permissions, see
+//! [`super::two_factor::satisfied`]. API keys have the permissions they were created with, in
+//! every section, see [`super::api_key`].
+
+use super::session::DBSession;
+use super::{current_principal, two_factor, Principal, Role};
+use crate::error::{APIError, APIResult};
+use crate::schema::permission_grants;
+use crate::section::Section;
+use diesel::prelude::*;
+use diesel_derive_enum::DbEnum;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
+use std::sync::Mutex;
+
+// Named like the permission strings, so `permission.manage` is `PermissionManage`.
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DbEnum, Serialize, Deserialize)]
+pub enum Permission {
+    /// Create and delete articles.
+    #[db_rename = "article.publish"]
+    #[serde(rename = "article.publish")]
+    ArticlePublish,
+    /// Change published articles, and see which Drive file they came from.
+    #[db_rename = "article.edit"]
+    #[serde(rename = "article.edit")]
+    ArticleEdit,
+    /// Turn documents into article content, by importing them or reading them from Drive.
+    #[db_rename = "article.draft"]
+    #[serde(rename = "article.draft")]
+    ArticleDraft,
+    /// Upload images, and browse and describe those in the library.
+    #[db_rename = "image.upload"]
+    #[serde(rename = "image.upload")]
+    ImageUpload,
+    /// Create and change writers and their headshots.
+    #[db_rename = "writer.manage"]
+    #[serde(rename = "writer.manage")]
+    WriterManage,
+    /// List the files in the Drive drafts and finals folders.
+    #[db_rename = "drive.read"]
+    #[serde(rename = "drive.read")]
+    DriveRead,
+    /// Move files between the Drive drafts and finals folders.
+    #[db_rename = "drive.move"]
+    #[serde(rename = "drive.move")]
+    DriveMove,
+    #[db_rename = "submission.create"]
+    #[serde(rename = "submission.create")]
+    SubmissionCreate,
+    /// See and delete submissions.
+    #[db_rename = "submission.review"]
+    #[serde(rename = "submission.review")]
+    SubmissionReview,
+    /// Run the maintenance jobs, like collecting orphaned images.
+    #[db_rename = "site.maintain"]
+    #[serde(rename = "site.maintain")]
+    SiteMaintain,
+    /// See and revoke anyone's sessions.
+    #[db_rename = "session.manage"]
+    #[serde(rename = "session.manage")]
+    SessionManage,
+    /// Grant and revoke permissions, and set which roles have to use two-factor authentication.
+    #[db_rename = "permission.manage"]
+    #[serde(rename = "permission.manage")]
+    PermissionManage,
+    /// Browse the audit log.
+    #[db_rename = "audit.read"]
+    #[serde(rename = "audit.read")]
+    AuditRead,
+}
+
+impl Permission {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Permission::ArticlePublish => "article.publish",
+            Permission::ArticleEdit => "article.edit",
+            Permission::ArticleDraft => "article.draft",
+            Permission::ImageUpload => "image.upload",
+            Permission::WriterManage => "writer.manage",
+            Permission::DriveRead => "drive.read",
+            Permission::DriveMove => "drive.move",
+            Permission::SubmissionCreate => "submission.create",
+            Permission::SubmissionReview => "submission.review",
+            Permission::SiteMaintain => "site.maintain",
+            Permission::SessionManage => "session.manage",
+            Permission::PermissionManage => "permission.manage",
+            Permission::AuditRead => "audit.read",
+        }
+    }
+
+    /// Whether grants of the permission can be limited to a section.
+    pub fn is_sectioned(self) -> bool {
+        matches!(
+            self,
+            Permission::ArticlePublish
+                | Permission::ArticleEdit
+                | Permission::SubmissionCreate
+                | Permission::SubmissionReview
+        )
+    }
+}
+
+#[derive(Queryable, Debug, Serialize)]
+pub struct DBPermissionGrant {
+    pub id: i32,
+    pub role: Option<Role>,
+    pub username: Option<String>,
+    pub permission: Permission,
+    /// The only section the permission applies to, or `None` for every section.
+    pub section: Option<Section>,
+}
+
+/// What the client sends to grant a permission, to either a role or an account.
+#[derive(Deserialize, Insertable, Debug)]
+#[table_name = "permission_grants"]
+pub struct ClientPermissionGrant {
+    pub role: Option<Role>,
+    pub username: Option<String>,
+    pub permission: Permission,
+    pub section: Option<Section>,
+}
+
+/// The sections a permission applies to, serialized as `null` for every section.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum Scope {
+    All,
+    Sections(Vec<Section>),
+}
+
+impl Scope {
+    pub fn allows(&self, section: Section) -> bool {
+        match self {
+            Scope::All => true,
+            Scope::Sections(sections) => sections.contains(&section),
+        }
+    }
+}
+
+/// The sections the session has `permission` for, or `None` if it doesn't have it at all.
+pub fn scope_of(
+    db_connection: &PgConnection,
+    session: &DBSession,
+    permission: Permission,
+) -> QueryResult<Option<Scope>> {
+    Ok(permissions_of(db_connection, session)?
+        .into_iter()
+        .find(|granted| granted.permission == permission)
+        .map(|granted| granted.sections))
+}
+
+/// Removes the grants made to `username` itself, returning how many there were. Grants to their
+/// role are left alone.
+pub fn revoke_user_grants(db_connection: &PgConnection, username: &str) -> QueryResult<usize> {
+    use crate::schema::permission_grants::dsl;
+
+    diesel::delete(dsl::permission_grants.filter(dsl::username.eq(username))).execute(db_connection)
+}
+
+/// A permission someone has, along with where it applies.
+#[derive(Debug, Serialize)]
+pub struct GrantedPermission {
+    pub permission: Permission,
+    pub sections: Scope,
+}
+
+/// Every permission the session has.
+pub fn permissions_of(
+    db_connection: &PgConnection,
+    session: &DBSession,
+) -> QueryResult<Vec<GrantedPermission>> {
+    use crate::schema::permission_grants::dsl;
+
+    if !two_factor::satisfied(db_connection, session)? {
+        return Ok(Vec::new());
+    }
+
+    if session.role == Role::Admin {
+        return Ok(PERMISSIONS
+            .into_iter()
+            .map(|permission| GrantedPermission {
+                permission,
+                sections: Scope::All,
+            })
+            .collect());
+    }
+
+    let grants = dsl::permission_grants
+        .filter(
+            dsl::role
+                .eq(session.role)
+                .or(dsl::username.eq(&session.username)),
+        )
+        .select((dsl::permission, dsl::section))
+        .load::<(Permission, Option<Section>)>(db_connection)?;
+
+    let mut permissions: Vec<GrantedPermission> = Vec::new();
+    for (permission, section) in grants {
+        let index = match permissions
+            .iter()
+            .position(|granted| granted.permission == permission)
+        {
+            Some(index) => index,
+            None => {
+                permissions.push(GrantedPermission {
+                    permission,
+                    sections: Scope::Sections(Vec::new()),
+                });
+                permissions.len() - 1
+            }
+        };
+        let scope = &mut permissions[index].sections;
+        match (section, &mut *scope) {
+            (None, _) => *scope = Scope::All,
+            (Some(section), Scope::Sections(sections)) => sections.push(section),
+            (Some(_), Scope::All) => {}
+        }
+    }
+    Ok(permissions)
+}
+
+const PERMISSIONS: [Permission; 13] = [
+    Permission::ArticlePublish,
+    Permission::ArticleEdit,
+    Permission::ArticleDraft,
+    Permission::ImageUpload,
+    Permission::WriterManage,
+    Permission::DriveRead,
+    Permission::DriveMove,
+    Permission::SubmissionCreate,
+    Permission::SubmissionReview,
+    Permission::SiteMaintain,
+    Permission::SessionManage,
+    Permission::PermissionManage,
+    Permission::AuditRead,
+];
+
+/// Names a permission in a type, for [`Permitted`] to check.
+pub trait RequiredPermission {
+    const PERMISSION: Permission;
+}
+
+/// The types naming each permission.
+pub mod perm {
+    use super::{Permission, RequiredPermission};
+
+    macro_rules! required_permissions {
+        ($($name:ident),* $(,)?) => {
+            $(
+                pub struct $name;
+
+                impl RequiredPermission for $name {
+                    const PERMISSION: Permission = Permission::$name;
+                }
+            )*
+        };
+    }
+
+    required_permissions!(
+        ArticlePublish,
+        ArticleEdit,
+        ArticleDraft,
+        ImageUpload,
+        WriterManage,
+        DriveRead,
+        DriveMove,
+        SubmissionCreate,
+        SubmissionReview,
+        SiteMaintain,
+        SessionManage,
+        PermissionManage,
+        AuditRead,
+    );
+}
+
+/// Someone with the permission `P`, in at least one section for sectioned permissions.
+pub struct Permitted<P> {
+    pub principal: Principal,
+    pub scope: Scope,
+    permission: PhantomData<fn() -> P>,
+}
+
+impl<P: RequiredPermission> Permitted<P> {
+    pub fn allows(&self, section: Section) -> bool {
+        self.scope.allows(section)
+    }
+
+    /// Fails unless the permission applies to `section`.
+    pub fn require(&self, section: Section) -> APIResult<()> {
+        if self.allows(section) {
+            Ok(())
+        } else {
+            Err(APIError::new(
+                Status::Forbidden,
+                format!(
+                    "Permission {} does not cover the {section:?} section.",
+                    P::PERMISSION.as_str()
+                ),
+            ))
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, P: RequiredPermission> FromRequest<'r> for Permitted<P> {
+    type Error = APIError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(principal) = current_principal(req).await else {
+            return Outcome::Forward(());
+        };
+        let scope = match &principal {
+            Principal::Session(session) => {
+                let db_connection = match req.guard::<&State<Mutex<PgConnection>>>().await {
+                    Outcome::Success(db_connection) => db_connection,
+                    _ => return Outcome::Forward(()),
+                };
+                let Ok(db_connection) = db_connection.lock() else {
+                    return Outcome::Forward(());
+                };
+                scope_of(&db_connection, session, P::PERMISSION)
+            }
+            Principal::ApiKey(api_key) => Ok(api_key
+                .permissions
+                .contains(&P::PERMISSION)
+                .then_some(Scope::All)),
+        };
+
+        match scope {
+            Ok(Some(scope)) => Outcome::Success(Permitted {
+                principal,
+                scope,
+                permission: PhantomData,
+            }),
+            Ok(None) => Outcome::Forward(()),
+            Err(err) => {
+                println!(
+                    "Could not look up permission {}: {err}",
+                    P::PERMISSION.as_str()
+                );
+                Outcome::Forward(())
+            }
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//! Refresh tokens, which keep someone logged in once their short-lived access token expires.
+//!
+//! A refresh token is a random string kept in an http-only cookie, and only its SHA-256 is stored.
+//! Each token can be used once: `/api/refresh` swaps it for a new one in the same session along
+//! with a new access token. A token that was already swapped being used again means it was most
+//! likely copied, so the whole session is revoked and whoever holds it has to log in again.
+//!
+//! Swapped tokens are kept until their session ends, so however long ago one was swapped, using it
+//! again is noticed.
+
+use super::session::{self, DBSession};
+use super::Role;
+use crate::schema::refresh_tokens;
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+pub const REFRESH_TOKEN_DAYS: i64 = 30;
+/// A token used again this soon after being swapped is taken to be two tabs refreshing at once,
+/// rather than a copied token, so the session is left alone.
+const REUSE_GRACE_SECONDS: i64 = 10;
+
+#[derive(Queryable, Debug)]
+struct DBRefreshToken {
+    id: i32,
+    _token_hash: String,
+    expires_at: DateTime<Utc>,
+    used_at: Option<DateTime<Utc>>,
+    _created_at: DateTime<Utc>,
+    session_id: String,
+}
+
+#[derive(Insertable)]
+#[table_name = "refresh_tokens"]
+struct NewRefreshToken<'a> {
+    token_hash: String,
+    expires_at: DateTime<Utc>,
+    session_id: &'a str,
+}
+
+pub enum Rotation {
+    Rotated {
+        /// The refresh token to send back in place of the one that was used.
+        token: String,
+        session: DBSession,
+    },
+    /// The token was swapped moments ago by another request, whose new token the client should
+    /// have by now.
+    AlreadyRotated,
+    /// The token is unknown, expired or revoked.
+    Invalid,
+}
+
+/// Starts a session for someone who just logged in, returning it along with its first refresh
+/// token. `two_factor` is whether they logged in with a second factor.
+pub fn issue(
+    db_connection: &PgConnection,
+    username: &str,
+    role: Role,
+    two_factor: bool,
+) -> QueryResult<(DBSession, String)> {
+    db_connection.transaction::<_, DieselError, _>(|| {
+        let expires_at = Utc::now() + Duration::days(REFRESH_TOKEN_DAYS);
+        let session = session::start(db_connection, username, role, two_factor, expires_at)?;
+        let token = insert(db_connection, &session.id, expires_at)?;
+        Ok((session, token))
+    })
+}
+
+/// Swaps a refresh token for a new one.
+pub fn rotate(db_connection: &PgConnection, token: &str) -> QueryResult<Rotation> {
+    use crate::schema::refresh_tokens::dsl;
+
+    db_connection.transaction::<_, DieselError, _>(|| {
+        let current = dsl::refresh_tokens
+            .filter(dsl::token_hash.eq(hash(token)))
+            .for_update()
+            .first::<DBRefreshToken>(db_connection)
+            .optional()?;
+        let Some(current) = current else {
+            return Ok(Rotation::Invalid);
+        };
+
+        let now = Utc::now();
+        if let Some(used_at) = current.used_at {
+            if now - used_at <= Duration::seconds(REUSE_GRACE_SECONDS) {
+                return Ok(Rotation::AlreadyRotated);
+            }
+            println!(
+                "A used refresh token for session {} was used again, revoking it",
+                current.session_id
+            );
+            session::revoke(db_connection, &current.session_id)?;
+            return Ok(Rotation::Invalid);
+        }
+        if current.expires_at <= now {
+            return Ok(Rotation::Invalid);
+        }
+        let Some(session) = session::find_active(db_connection, &current.session_id)? else {
+            return Ok(Rotation::Invalid);
+        };
+
+        diesel::update(dsl::refresh_tokens.find(current.id))
+            .set(dsl::used_at.eq(now))
+            .execute(db_connection)?;
+        let expires_at = now + Duration::days(REFRESH_TOKEN_DAYS);
+        let token = insert(db_connection, &session.id, expires_at)?;
+        session::touch(db_connection, &session.id, expires_at)?;
+
+        Ok(Rotation::Rotated { token, session })
+    })
+}
+
+/// Ends the session a refresh token belongs to, if there is one.
+pub fn revoke(db_connection: &PgConnection, token: &str) -> QueryResult<()> {
+    use crate::schema::refresh_tokens::dsl;
+
+    let session_id = dsl::refresh_tokens
+        .filter(dsl::token_hash.eq(hash(token)))
+        .select(dsl::session_id)
+        .first::<String>(db_connection)
+        .optional()?;
+    if let Some(session_id) = session_id {
+        session::revoke(db_connection, &session_id)?;
+    }
+    Ok(())
+}
+
+fn insert(
+    db_connection: &PgConnection,
+    session_id: &str,
+    expires_at: DateTime<Utc>,
+) -> QueryResult<String> {
+    let token = base64::encode_config(
+        rand::thread_rng().gen::<[u8; 32]>(),
+        base64::URL_SAFE_NO_PAD,
+    );
+
+    diesel::insert_into(refresh_tokens::table)
+        .values(NewRefreshToken {
+            token_hash: hash(&token),
+            expires_at,
+            session_id,
+        })
+        .execute(db_connection)?;
+
+    Ok(token)
+}
+
+fn hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rotated(rotation: Rotation) -> String {
+        match rotation {
+            Rotation::Rotated { token, .. } => token,
+            Rotation::AlreadyRotated => panic!("the token was already rotated"),
+            Rotation::Invalid => panic!("the token is invalid"),
+        }
+    }
+
+    /// Needs `DATABASE_URL` to point at a database with the migrations run. Everything is done in a
+    /// transaction that is rolled back.
+    #[test]
+    #[ignore]
+    fn revokes_the_session_when_a_swapped_token_is_used_again() {
+        let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL isn't set");
+        let db_connection = PgConnection::establish(&database_url).unwrap();
+
+        db_connection.test_transaction::<_, DieselError, _>(|| {
+            let (session, first) = issue(&db_connection, "refresh-test", Role::Default, false)?;
+            let second = rotated(rotate(&db_connection, &first)?);
+            assert!(matches!(
+                rotate(&db_connection, &first)?,
+                Rotation::AlreadyRotated
+            ));
+
+            // As if the first token had been swapped before the grace period, and the session had
+            // refreshed a few times since.
+            let swapped_at = Utc::now() - Duration::seconds(REUSE_GRACE_SECONDS + 1);
+            diesel::update(
+                refresh_tokens::table.filter(refresh_tokens::token_hash.eq(hash(&first))),
+            )
+            .set(refresh_tokens::used_at.eq(swapped_at))
+            .execute(&db_connection)?;
+            let third = rotated(rotate(&db_connection, &second)?);
+            rotated(rotate(&db_connection, &third)?);
+
+            assert!(matches!(rotate(&db_connection, &first)?, Rotation::Invalid));
+            assert!(session::find_active(&db_connection, &session.id)?.is_none());
+            Ok(())
+        });
+    }
+}
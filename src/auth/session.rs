@@ -0,0 +1,133 @@
+//! Sessions, one for each login, which the `jti` of every access token names.
+//!
+//! The guards only accept access tokens whose session is still here, so deleting a session logs
+//! it out straight away, without waiting for its access token to expire or changing `JWT_SECRET`.
+//! A session lasts as long as its refresh tokens, see [`super::refresh`].
+
+use super::Role;
+use crate::schema::sessions;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use rand::Rng;
+use serde::Serialize;
+
+#[derive(Queryable, Identifiable, Clone, Debug, Serialize)]
+#[table_name = "sessions"]
+pub struct DBSession {
+    pub id: String,
+    pub username: String,
+    pub role: Role,
+    pub created_at: DateTime<Utc>,
+    /// When the session last got a new access token, which it does every few minutes while used.
+    pub last_seen_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// Whether the login was verified with a second factor, see [`super::two_factor`].
+    pub two_factor: bool,
+    /// Has to be sent back in the `X-CSRF-Token` header of writes, see [`crate::csrf`].
+    #[serde(skip)]
+    pub csrf_token: String,
+}
+
+#[derive(Insertable)]
+#[table_name = "sessions"]
+struct NewSession<'a> {
+    id: String,
+    username: &'a str,
+    role: Role,
+    expires_at: DateTime<Utc>,
+    two_factor: bool,
+    csrf_token: String,
+}
+
+/// Starts a session for someone who just logged in, clearing out expired ones while at it.
+pub fn start(
+    db_connection: &PgConnection,
+    username: &str,
+    role: Role,
+    two_factor: bool,
+    expires_at: DateTime<Utc>,
+) -> QueryResult<DBSession> {
+    use crate::schema::sessions::dsl;
+
+    diesel::delete(dsl::sessions.filter(dsl::expires_at.le(Utc::now()))).execute(db_connection)?;
+
+    diesel::insert_into(sessions::table)
+        .values(NewSession {
+            id: uuid::Uuid::new_v4().to_string(),
+            username,
+            role,
+            expires_at,
+            two_factor,
+            csrf_token: base64::encode_config(
+                rand::thread_rng().gen::<[u8; 32]>(),
+                base64::URL_SAFE_NO_PAD,
+            ),
+        })
+        .get_result(db_connection)
+}
+
+/// The session with the given id, unless it has expired or been revoked.
+pub fn find_active(db_connection: &PgConnection, id: &str) -> QueryResult<Option<DBSession>> {
+    use crate::schema::sessions::dsl;
+
+    dsl::sessions
+        .find(id)
+        .filter(dsl::expires_at.gt(Utc::now()))
+        .first(db_connection)
+        .optional()
+}
+
+/// Every unexpired session, or just those of `username`, most recently used first.
+pub fn list_active(
+    db_connection: &PgConnection,
+    username: Option<&str>,
+) -> QueryResult<Vec<DBSession>> {
+    use crate::schema::sessions::dsl;
+
+    let mut query = dsl::sessions
+        .filter(dsl::expires_at.gt(Utc::now()))
+        .order(dsl::last_seen_at.desc())
+        .into_boxed();
+    if let Some(username) = username {
+        query = query.filter(dsl::username.eq(username));
+    }
+    query.load(db_connection)
+}
+
+/// Keeps a session going after it got a new refresh token.
+pub fn touch(db_connection: &PgConnection, id: &str, expires_at: DateTime<Utc>) -> QueryResult<()> {
+    use crate::schema::sessions::dsl;
+
+    diesel::update(dsl::sessions.find(id))
+        .set((
+            dsl::last_seen_at.eq(Utc::now()),
+            dsl::expires_at.eq(expires_at),
+        ))
+        .execute(db_connection)?;
+    Ok(())
+}
+
+/// Marks a session as verified with a second factor, for when it was used to enroll.
+pub fn mark_two_factor(db_connection: &PgConnection, id: &str) -> QueryResult<()> {
+    use crate::schema::sessions::dsl;
+
+    diesel::update(dsl::sessions.find(id))
+        .set(dsl::two_factor.eq(true))
+        .execute(db_connection)?;
+    Ok(())
+}
+
+/// Ends a session along with its refresh tokens, returning whether there was one to end.
+pub fn revoke(db_connection: &PgConnection, id: &str) -> QueryResult<bool> {
+    use crate::schema::sessions::dsl;
+
+    let deleted = diesel::delete(dsl::sessions.find(id)).execute(db_connection)?;
+    Ok(deleted > 0)
+}
+
+/// Ends every session of `username`, returning how many there were.
+pub fn revoke_all(db_connection: &PgConnection, username: &str) -> QueryResult<usize> {
+    use crate::schema::sessions::dsl;
+
+    diesel::delete(dsl::sessions.filter(dsl::username.eq(username))).execute(db_connection)
+}
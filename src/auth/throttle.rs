@@ -0,0 +1,191 @@
+//! Throttling of failed logins, by IP address, by username, and by the two together.
+//!
+//! The first few failures are free. After that each attempt has to wait twice as long as the one
+//! before since the last failure, and enough failures from an IP address lock it out for a while.
+//! Failures for a username from anywhere only slow it down, up to [`MAX_DELAY`], so nobody can keep
+//! someone else locked out of their account by failing to log in as them. Failures are forgotten
+//! once there have been none for an hour, and logging in forgets those of the username and IP
+//! address it came from.
+//!
+//! Everything is kept in memory, so restarting the server forgets all failures.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Failures allowed before attempts are slowed down.
+const FREE_FAILURES: u32 = 3;
+/// The wait after the first failure past the free ones, doubling after each failure since.
+const BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+/// Failures from an IP address that lock it out for [`LOCKOUT`].
+const LOCKOUT_FAILURES: u32 = 10;
+const LOCKOUT: Duration = Duration::from_secs(15 * 60);
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+/// Forgotten failures are only cleared out once this many IPs and usernames are tracked.
+const MAX_TRACKED: usize = 10_000;
+
+#[derive(Default)]
+pub struct LoginThrottle {
+    failures: Mutex<HashMap<Key, Failures>>,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Ip(IpAddr),
+    IpUsername(IpAddr, String),
+    Username(String),
+}
+
+impl Key {
+    /// Whether enough failures lock logins out, rather than only slowing them down.
+    fn locks_out(&self) -> bool {
+        !matches!(self, Key::Username(_))
+    }
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+impl Failures {
+    /// When the next attempt is allowed, or `None` if it is allowed right away.
+    fn blocked_until(&self, now: Instant, locks_out: bool) -> Option<Instant> {
+        if now.saturating_duration_since(self.last) >= FORGET_AFTER {
+            return None;
+        }
+        let wait = if locks_out && self.count >= LOCKOUT_FAILURES {
+            LOCKOUT
+        } else if self.count >= FREE_FAILURES {
+            let doublings = (self.count - FREE_FAILURES).min(16);
+            (BASE_DELAY * 2u32.pow(doublings)).min(MAX_DELAY)
+        } else {
+            return None;
+        };
+        Some(self.last + wait).filter(|until| *until > now)
+    }
+}
+
+/// The failures a login counts towards, the username's last.
+fn keys(ip: Option<IpAddr>, username: &str) -> impl Iterator<Item = Key> {
+    let by_ip = ip.map(|ip| [Key::Ip(ip), Key::IpUsername(ip, username.to_owned())]);
+    by_ip
+        .into_iter()
+        .flatten()
+        .chain([Key::Username(username.to_owned())])
+}
+
+impl LoginThrottle {
+    /// How long until someone can try logging in as `username` from `ip`, if they have to wait.
+    pub fn check(&self, ip: Option<IpAddr>, username: &str, now: Instant) -> Option<Duration> {
+        let failures = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+        keys(ip, username)
+            .filter_map(|key| failures.get(&key)?.blocked_until(now, key.locks_out()))
+            .max()
+            .map(|until| until - now)
+    }
+
+    /// Counts a failed login, returning how many times in a row logging in as `username` failed.
+    pub fn record_failure(&self, ip: Option<IpAddr>, username: &str, now: Instant) -> u32 {
+        let mut failures = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+        if failures.len() >= MAX_TRACKED {
+            failures
+                .retain(|_, failures| now.saturating_duration_since(failures.last) < FORGET_AFTER);
+        }
+
+        let mut count = 0;
+        for key in keys(ip, username) {
+            let entry = failures.entry(key).or_insert(Failures {
+                count: 0,
+                last: now,
+            });
+            if now.saturating_duration_since(entry.last) >= FORGET_AFTER {
+                entry.count = 0;
+            }
+            entry.count += 1;
+            entry.last = now;
+            count = entry.count;
+        }
+        count
+    }
+
+    /// Forgets the failures of a username and IP address someone just logged in with.
+    pub fn record_success(&self, ip: Option<IpAddr>, username: &str) {
+        let mut failures = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+        for key in keys(ip, username) {
+            failures.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const IP: Option<IpAddr> = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+    #[test]
+    fn backs_off_then_locks_out() {
+        let throttle = LoginThrottle::default();
+        let start = Instant::now();
+
+        for _ in 0..FREE_FAILURES {
+            assert_eq!(throttle.check(IP, "editor", start), None);
+            throttle.record_failure(IP, "editor", start);
+        }
+        assert_eq!(throttle.check(IP, "editor", start), Some(BASE_DELAY));
+        // Another IP is still held back, as the username is throttled too.
+        assert_eq!(throttle.check(None, "editor", start), Some(BASE_DELAY));
+        assert_eq!(throttle.check(None, "admin", start), None);
+
+        throttle.record_failure(IP, "editor", start);
+        assert_eq!(throttle.check(IP, "editor", start), Some(BASE_DELAY * 2));
+        assert_eq!(throttle.check(IP, "editor", start + BASE_DELAY * 2), None);
+
+        for _ in FREE_FAILURES + 1..LOCKOUT_FAILURES {
+            throttle.record_failure(IP, "editor", start);
+        }
+        assert_eq!(throttle.check(IP, "editor", start), Some(LOCKOUT));
+        assert_eq!(throttle.check(IP, "editor", start + LOCKOUT), None);
+    }
+
+    #[test]
+    fn only_slows_down_a_username_from_elsewhere() {
+        let throttle = LoginThrottle::default();
+        let start = Instant::now();
+        let other_ip = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+
+        for _ in 0..LOCKOUT_FAILURES * 2 {
+            throttle.record_failure(IP, "admin", start);
+        }
+        assert_eq!(throttle.check(IP, "admin", start), Some(LOCKOUT));
+        // The IP address failing is locked out of every account.
+        assert_eq!(throttle.check(IP, "editor", start), Some(LOCKOUT));
+        // Anyone else only waits as long as the backoff allows.
+        assert_eq!(throttle.check(other_ip, "admin", start), Some(MAX_DELAY));
+        assert_eq!(throttle.check(other_ip, "admin", start + MAX_DELAY), None);
+    }
+
+    #[test]
+    fn forgets_failures() {
+        let throttle = LoginThrottle::default();
+        let start = Instant::now();
+
+        for _ in 0..LOCKOUT_FAILURES {
+            throttle.record_failure(IP, "editor", start);
+        }
+        assert_eq!(
+            throttle.record_failure(IP, "editor", start + FORGET_AFTER),
+            1
+        );
+
+        throttle.record_success(IP, "editor");
+        assert_eq!(
+            throttle.record_failure(IP, "editor", start + FORGET_AFTER),
+            1
+        );
+    }
+}
@@ -0,0 +1,423 @@
+//! Two-factor authentication with time-based one-time passwords (TOTP), the six digit codes
+//! authenticator apps show.
+//!
+//! Someone enrolls by scanning the provisioning URI of a new secret as a QR code, and confirms it
+//! with a code from their app, which gives them [`RECOVERY_CODES`] single-use recovery codes for
+//! when they lose their phone. From then on logging in takes a code along with the password. Each
+//! code can only be used once, so one seen over someone's shoulder is no good afterwards.
+//!
+//! Sessions remember whether they were started with a code, and sessions of the roles the policy
+//! requires two-factor authentication for get no permissions without one, see [`satisfied`].
+//! They can still enroll, so requiring it doesn't lock anyone out.
+//!
+//! Codes are made as in RFC 6238 with HMAC-SHA1, six digits and 30 second steps, which is what
+//! every authenticator app supports.
+//!
+//! Everything that depends on the time takes it as an argument, so tests can fix the clock.
+
+use super::session::DBSession;
+use super::Role;
+use crate::schema::{two_factor, two_factor_policy, two_factor_recovery_codes};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::Serialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+/// Shown in authenticator apps above the username.
+pub const ISSUER: &str = "Bullhorn";
+const DIGITS: u32 = 6;
+const STEP_SECONDS: u64 = 30;
+/// Codes from this many steps either side of the current one are accepted too, for phones whose
+/// clocks are a little off.
+const SKEW_STEPS: u64 = 1;
+const SECRET_BYTES: usize = 20;
+pub const RECOVERY_CODES: usize = 10;
+/// Recovery codes are written in lowercase letters and digits that can't be mistaken for each
+/// other, with a dash in the middle.
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+const RECOVERY_CODE_LENGTH: usize = 10;
+
+#[derive(Queryable, Debug)]
+struct DBTwoFactor {
+    _username: String,
+    secret: String,
+    enabled_at: Option<DateTime<Utc>>,
+    last_used_step: Option<i64>,
+    _created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[table_name = "two_factor"]
+struct NewTwoFactor<'a> {
+    username: &'a str,
+    secret: String,
+}
+
+#[derive(Insertable)]
+#[table_name = "two_factor_policy"]
+struct NewPolicyRole {
+    role: Role,
+}
+
+#[derive(Insertable)]
+#[table_name = "two_factor_recovery_codes"]
+struct NewRecoveryCode<'a> {
+    username: &'a str,
+    code_hash: String,
+}
+
+/// A new secret, to be confirmed with a code before it's used.
+#[derive(Debug, Serialize)]
+pub struct Enrollment {
+    /// The secret in base32, for typing into an app that can't scan the QR code.
+    pub secret: String,
+    /// The `otpauth://` URI to show as a QR code.
+    pub provisioning_uri: String,
+}
+
+/// Whether someone has two-factor authentication, and whether they have to.
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub enabled: bool,
+    pub enabled_at: Option<DateTime<Utc>>,
+    pub recovery_codes_left: i64,
+    /// Whether the policy requires it for the role of the session.
+    pub required: bool,
+    /// Whether the session was started with a code, or confirmed enrolling.
+    pub verified: bool,
+}
+
+const BASE32: base32::Alphabet = base32::Alphabet::Rfc4648 { padding: false };
+
+/// The code for a time step.
+fn code_at(secret: &[u8], step: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    // Dynamic truncation, section 5.3 of RFC 4226.
+    let offset = usize::from(hash[hash.len() - 1] & 0x0f);
+    let truncated = u32::from_be_bytes([
+        hash[offset],
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]) & 0x7fff_ffff;
+    format!(
+        "{:0width$}",
+        truncated % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+/// The `otpauth://` URI apps read the secret from, labelled with the issuer and username.
+fn provisioning_uri(secret: &str, username: &str) -> String {
+    let mut uri = url::Url::parse("otpauth://totp/").expect("valid url");
+    // The label is the issuer and account name separated by a colon, so the name can't have one.
+    uri.set_path(&format!("{ISSUER}:{}", username.replace([':', '/'], "_")));
+    uri.query_pairs_mut()
+        .append_pair("secret", secret)
+        .append_pair("issuer", ISSUER)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &STEP_SECONDS.to_string());
+    uri.to_string()
+}
+
+/// The time step of the code matching `code`, if there is one.
+fn matching_step(secret: &str, code: &str, now: DateTime<Utc>) -> Option<u64> {
+    let Some(secret) = base32::decode(BASE32, secret) else {
+        println!("Stored two-factor secret is not valid base32");
+        return None;
+    };
+    let current = u64::try_from(now.timestamp()).ok()? / STEP_SECONDS;
+    (current.saturating_sub(SKEW_STEPS)..=current + SKEW_STEPS).find(|step| {
+        let expected = code_at(&secret, *step);
+        bool::from(expected.as_bytes().ct_eq(code.as_bytes()))
+    })
+}
+
+fn find(db_connection: &PgConnection, username: &str) -> QueryResult<Option<DBTwoFactor>> {
+    use crate::schema::two_factor::dsl;
+
+    dsl::two_factor
+        .find(username)
+        .first(db_connection)
+        .optional()
+}
+
+/// Whether logging in as `username` takes a code.
+pub fn is_enabled(db_connection: &PgConnection, username: &str) -> QueryResult<bool> {
+    Ok(find(db_connection, username)?.is_some_and(|found| found.enabled_at.is_some()))
+}
+
+pub fn status(db_connection: &PgConnection, session: &DBSession) -> QueryResult<Status> {
+    use crate::schema::two_factor_recovery_codes::dsl;
+
+    let enabled_at = find(db_connection, &session.username)?.and_then(|found| found.enabled_at);
+    let recovery_codes_left = dsl::two_factor_recovery_codes
+        .filter(dsl::username.eq(&session.username))
+        .filter(dsl::used_at.is_null())
+        .count()
+        .get_result(db_connection)?;
+    Ok(Status {
+        enabled: enabled_at.is_some(),
+        enabled_at,
+        recovery_codes_left,
+        required: required_for(db_connection, session.role)?,
+        verified: session.two_factor,
+    })
+}
+
+/// Starts enrolling `username` with a new secret, replacing any that wasn't confirmed. Returns
+/// `None` if they already have two-factor authentication.
+pub fn enroll(db_connection: &PgConnection, username: &str) -> QueryResult<Option<Enrollment>> {
+    use crate::schema::two_factor::dsl;
+
+    db_connection.transaction::<_, DieselError, _>(|| {
+        if is_enabled(db_connection, username)? {
+            return Ok(None);
+        }
+
+        let secret = base32::encode(BASE32, &rand::thread_rng().gen::<[u8; SECRET_BYTES]>());
+        diesel::delete(dsl::two_factor.find(username)).execute(db_connection)?;
+        diesel::insert_into(two_factor::table)
+            .values(NewTwoFactor {
+                username,
+                secret: secret.clone(),
+            })
+            .execute(db_connection)?;
+
+        Ok(Some(Enrollment {
+            provisioning_uri: provisioning_uri(&secret, username),
+            secret,
+        }))
+    })
+}
+
+/// Turns on two-factor authentication for `username` if `code` matches the secret they're
+/// enrolling with, returning their recovery codes.
+pub fn confirm(
+    db_connection: &PgConnection,
+    username: &str,
+    code: &str,
+    now: DateTime<Utc>,
+) -> QueryResult<Option<Vec<String>>> {
+    use crate::schema::two_factor::dsl;
+
+    db_connection.transaction::<_, DieselError, _>(|| {
+        let pending = dsl::two_factor
+            .find(username)
+            .filter(dsl::enabled_at.is_null())
+            .for_update()
+            .first::<DBTwoFactor>(db_connection)
+            .optional()?;
+        let Some(step) = pending.and_then(|pending| matching_step(&pending.secret, code, now))
+        else {
+            return Ok(None);
+        };
+
+        diesel::update(dsl::two_factor.find(username))
+            .set((dsl::enabled_at.eq(now), dsl::last_used_step.eq(step as i64)))
+            .execute(db_connection)?;
+        replace_recovery_codes(db_connection, username).map(Some)
+    })
+}
+
+/// Checks a code from someone's app, or one of their recovery codes, and uses it up.
+pub fn verify(
+    db_connection: &PgConnection,
+    username: &str,
+    code: &str,
+    now: DateTime<Utc>,
+) -> QueryResult<bool> {
+    use crate::schema::two_factor::dsl;
+
+    let code = code.trim();
+    db_connection.transaction::<_, DieselError, _>(|| {
+        let enabled = dsl::two_factor
+            .find(username)
+            .filter(dsl::enabled_at.is_not_null())
+            .for_update()
+            .first::<DBTwoFactor>(db_connection)
+            .optional()?;
+        let Some(enabled) = enabled else {
+            return Ok(false);
+        };
+
+        if code.len() != DIGITS as usize || !code.bytes().all(|byte| byte.is_ascii_digit()) {
+            return use_recovery_code(db_connection, username, code, now);
+        }
+        let step = matching_step(&enabled.secret, code, now).map(|step| step as i64);
+        match step {
+            Some(step) if enabled.last_used_step.is_none_or(|last| step > last) => {
+                diesel::update(dsl::two_factor.find(username))
+                    .set(dsl::last_used_step.eq(step))
+                    .execute(db_connection)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    })
+}
+
+fn use_recovery_code(
+    db_connection: &PgConnection,
+    username: &str,
+    code: &str,
+    now: DateTime<Utc>,
+) -> QueryResult<bool> {
+    use crate::schema::two_factor_recovery_codes::dsl;
+
+    let used = diesel::update(
+        dsl::two_factor_recovery_codes
+            .filter(dsl::username.eq(username))
+            .filter(dsl::code_hash.eq(hash_recovery_code(code)))
+            .filter(dsl::used_at.is_null()),
+    )
+    .set(dsl::used_at.eq(now))
+    .execute(db_connection)?;
+    Ok(used > 0)
+}
+
+/// Replaces the recovery codes of `username` with new ones, returning them.
+pub fn replace_recovery_codes(
+    db_connection: &PgConnection,
+    username: &str,
+) -> QueryResult<Vec<String>> {
+    use crate::schema::two_factor_recovery_codes::dsl;
+
+    let mut rng = rand::thread_rng();
+    let codes: Vec<String> = (0..RECOVERY_CODES)
+        .map(|_| {
+            let mut code: String = (0..RECOVERY_CODE_LENGTH)
+                .map(|_| {
+                    RECOVERY_CODE_ALPHABET[rng.gen_range(0..RECOVERY_CODE_ALPHABET.len())] as char
+                })
+                .collect();
+            code.insert(RECOVERY_CODE_LENGTH / 2, '-');
+            code
+        })
+        .collect();
+
+    diesel::delete(dsl::two_factor_recovery_codes.filter(dsl::username.eq(username)))
+        .execute(db_connection)?;
+    let new_codes: Vec<NewRecoveryCode> = codes
+        .iter()
+        .map(|code| NewRecoveryCode {
+            username,
+            code_hash: hash_recovery_code(code),
+        })
+        .collect();
+    diesel::insert_into(two_factor_recovery_codes::table)
+        .values(&new_codes)
+        .execute(db_connection)?;
+    Ok(codes)
+}
+
+/// Recovery codes are random enough that a plain SHA-256 does. Case and dashes are ignored.
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    format!("{:x}", Sha256::digest(normalized.as_bytes()))
+}
+
+/// Turns off two-factor authentication for `username`, along with their recovery codes. Returns
+/// whether they had it, or were enrolling.
+pub fn disable(db_connection: &PgConnection, username: &str) -> QueryResult<bool> {
+    use crate::schema::two_factor::dsl;
+
+    let deleted = diesel::delete(dsl::two_factor.find(username)).execute(db_connection)?;
+    Ok(deleted > 0)
+}
+
+/// The roles that have to log in with a code to get any permissions.
+pub fn required_roles(db_connection: &PgConnection) -> QueryResult<Vec<Role>> {
+    use crate::schema::two_factor_policy::dsl;
+
+    dsl::two_factor_policy.select(dsl::role).load(db_connection)
+}
+
+pub fn set_required_roles(db_connection: &PgConnection, roles: &[Role]) -> QueryResult<()> {
+    db_connection.transaction::<_, DieselError, _>(|| {
+        diesel::delete(two_factor_policy::table).execute(db_connection)?;
+        let rows: Vec<NewPolicyRole> = roles.iter().map(|&role| NewPolicyRole { role }).collect();
+        diesel::insert_into(two_factor_policy::table)
+            .values(&rows)
+            .on_conflict_do_nothing()
+            .execute(db_connection)?;
+        Ok(())
+    })
+}
+
+pub fn required_for(db_connection: &PgConnection, role: Role) -> QueryResult<bool> {
+    Ok(required_roles(db_connection)?.contains(&role))
+}
+
+/// Whether the session meets the policy, either by having been started with a code or by being
+/// for a role that doesn't need one.
+pub fn satisfied(db_connection: &PgConnection, session: &DBSession) -> QueryResult<bool> {
+    Ok(session.two_factor || !required_for(db_connection, session.role)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const SECRET: &str = "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP";
+
+    #[test]
+    fn makes_the_codes_in_the_rfc() {
+        // The SHA-1 test vectors of RFC 6238, which have eight digits.
+        let secret = b"12345678901234567890";
+        assert_eq!(code_at(secret, 59 / STEP_SECONDS), "287082");
+        assert_eq!(code_at(secret, 1_111_111_109 / STEP_SECONDS), "081804");
+        assert_eq!(code_at(secret, 20_000_000_000 / STEP_SECONDS), "353130");
+    }
+
+    #[test]
+    fn accepts_codes_from_nearby_steps() {
+        let now = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let secret = base32::decode(BASE32, SECRET).unwrap();
+        let step = 1_700_000_000 / STEP_SECONDS;
+
+        let code = code_at(&secret, step);
+        assert_eq!(matching_step(SECRET, &code, now), Some(step));
+        let previous = code_at(&secret, step - 1);
+        assert_eq!(matching_step(SECRET, &previous, now), Some(step - 1));
+
+        let later = now + chrono::Duration::seconds(2 * STEP_SECONDS as i64);
+        assert_eq!(matching_step(SECRET, &code, later), None);
+    }
+
+    #[test]
+    fn labels_the_provisioning_uri() {
+        assert_eq!(
+            provisioning_uri(SECRET, "jane doe:1"),
+            format!(
+                "otpauth://totp/Bullhorn:jane%20doe_1?secret={SECRET}&issuer=Bullhorn&\
+                 algorithm=SHA1&digits=6&period=30"
+            )
+        );
+    }
+
+    #[test]
+    fn recovery_codes_ignore_case_and_dashes() {
+        assert_eq!(
+            hash_recovery_code("abcde-fghjk"),
+            hash_recovery_code(" ABCDEFGHJK ")
+        );
+        assert_ne!(
+            hash_recovery_code("abcde-fghjk"),
+            hash_recovery_code("abcde-fghjm")
+        );
+    }
+}
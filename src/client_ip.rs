@@ -0,0 +1,56 @@
+//! The IP address a request came from, which logins are throttled by, writes are rate limited by
+//! and the audit log records.
+//!
+//! That is the address of whoever connected, unless `TRUSTED_PROXY_HEADER` names a header the
+//! reverse proxy in front of the server sets. Anyone can send any header, so one is only trusted
+//! when a proxy is there to overwrite it, or clients could pick their own address.
+
+use crate::config::AppConfig;
+use rocket::request::{FromRequest, Outcome, Request};
+use std::convert::Infallible;
+use std::net::IpAddr;
+
+/// The IP address a request came from, if it is known.
+pub fn client_ip(req: &Request<'_>) -> Option<IpAddr> {
+    let header = req
+        .rocket()
+        .state::<AppConfig>()
+        .and_then(|config| config.trusted_proxy_header.as_deref());
+    header
+        .and_then(|header| req.headers().get(header).last())
+        .and_then(forwarded_ip)
+        .or_else(|| req.remote().map(|remote| remote.ip()))
+}
+
+/// The address in a proxy header. Headers like `X-Forwarded-For` list every proxy the request
+/// passed through, and only the last one was added by the proxy that is trusted.
+fn forwarded_ip(value: &str) -> Option<IpAddr> {
+    value.rsplit(',').next()?.trim().parse().ok()
+}
+
+/// [`client_ip`] as a request guard.
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientIp {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(ClientIp(client_ip(req)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn takes_the_address_the_proxy_added() {
+        let ip = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(forwarded_ip("192.0.2.1"), ip);
+        assert_eq!(forwarded_ip("10.0.0.1, 192.0.2.1"), ip);
+        assert_eq!(forwarded_ip("192.0.2.1, nonsense"), None);
+        assert_eq!(forwarded_ip(""), None);
+    }
+}
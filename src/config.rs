@@ -0,0 +1,496 @@
+//! Configuration, read from the environment (and `.env`) once at launch and managed as state.
+//!
+//! Every variable is checked up front, and all the problems found are reported together before
+//! the server refuses to start, rather than one at a time by panicking mid-request. That includes
+//! the `S3_*` variables of the image bucket and the `IMAGE_TRANSFORM_*` ones of resized images.
+//!
+//! Access tokens are signed with `JWT_SECRET`. To rotate it, move the old secret to
+//! `JWT_PREVIOUS_SECRETS` (a comma separated list) while setting the new one, so tokens signed
+//! with the old one keep working until they expire, which takes [`crate::auth`]'s access token
+//! lifetime at most.
+
+use rocket::http::SameSite;
+use std::fmt::{self, Display};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Shorter JWT secrets are warned about, as HS512 keys can be guessed offline from any token.
+const MIN_JWT_SECRET_BYTES: usize = 32;
+const DEFAULT_TRANSFORM_SIZES: [u32; 8] = [64, 160, 320, 480, 640, 960, 1280, 1920];
+const DEFAULT_TRANSFORM_CACHE_MB: u64 = 1024;
+
+pub struct AppConfig {
+    pub database_url: String,
+    pub jwt_keys: JwtKeys,
+    pub admin: Credentials,
+    pub editor: Credentials,
+    /// Where the built frontend is served from.
+    pub build_dir: PathBuf,
+    pub images: ImageConfig,
+    pub drive: DriveConfig,
+    pub cookies: CookieConfig,
+    /// How often orphaned images are collected, or never for 0.
+    pub image_gc_interval_hours: u64,
+    /// The header the reverse proxy puts the client's IP address in, see [`crate::client_ip`].
+    pub trusted_proxy_header: Option<String>,
+}
+
+/// The secrets access tokens are signed and checked with.
+pub struct JwtKeys {
+    /// Signs new tokens.
+    pub current: String,
+    /// Only checks tokens, signed before the secret was rotated.
+    pub previous: Vec<String>,
+}
+
+impl JwtKeys {
+    /// Every secret a token may have been signed with, the current one first.
+    pub fn verifying(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.current.as_str()).chain(self.previous.iter().map(String::as_str))
+    }
+}
+
+/// The username and password of the admin or editor, which aren't stored anywhere else.
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+pub struct ImageConfig {
+    pub storage: ImageStorageConfig,
+    /// See `IMAGE_PUBLIC_BASE_URL` in [`crate::images::storage`].
+    pub public_base_url: String,
+    pub transform: TransformConfig,
+}
+
+pub enum ImageStorageConfig {
+    /// Images are kept under this directory.
+    Filesystem(PathBuf),
+    /// Images are kept in an S3-compatible bucket, see [`crate::images::storage::S3Storage`].
+    S3 {
+        /// Checked to be a url with a host.
+        endpoint: String,
+        bucket: String,
+        region: String,
+        access_key_id: String,
+        secret_access_key: String,
+    },
+}
+
+/// See [`crate::images::transform`].
+pub struct TransformConfig {
+    /// The only widths and heights images can be resized to.
+    pub sizes: Vec<u32>,
+    pub cache_path: PathBuf,
+    pub cache_max_bytes: u64,
+}
+
+pub struct DriveConfig {
+    /// The service account key used to read and move Drive files.
+    pub client_secret_path: PathBuf,
+    /// Where the Drive access tokens are cached between restarts.
+    pub token_cache_path: PathBuf,
+}
+
+#[derive(Clone, Copy)]
+pub struct CookieConfig {
+    /// Whether cookies are only sent over HTTPS, which only local development turns off.
+    pub secure: bool,
+    pub same_site: SameSite,
+}
+
+/// Everything wrong with the configuration.
+#[derive(Debug, PartialEq)]
+pub struct ConfigError(pub Vec<String>);
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "The configuration is invalid:")?;
+        for problem in &self.0 {
+            writeln!(f, "  - {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Reads variables, noting every problem rather than stopping at the first.
+struct Vars<F> {
+    get: F,
+    problems: Vec<String>,
+}
+
+impl<F: Fn(&str) -> Option<String>> Vars<F> {
+    fn optional(&self, name: &str) -> Option<String> {
+        (self.get)(name).filter(|value| !value.is_empty())
+    }
+
+    fn required(&mut self, name: &str) -> String {
+        self.optional(name).unwrap_or_else(|| {
+            self.problems.push(format!("{name} must be set"));
+            String::new()
+        })
+    }
+
+    fn parsed<T: FromStr>(&mut self, name: &str, default: T, expected: &str) -> T {
+        match self.optional(name) {
+            Some(value) => value.trim().parse().unwrap_or_else(|_| {
+                self.problems
+                    .push(format!("{name} should be {expected}, not \"{value}\""));
+                default
+            }),
+            None => default,
+        }
+    }
+}
+
+impl AppConfig {
+    /// Reads the configuration from the environment, after loading `.env` if there is one.
+    pub fn from_env() -> Result<AppConfig, ConfigError> {
+        _ = dotenvy::dotenv();
+        AppConfig::from_vars(|name| std::env::var(name).ok())
+    }
+
+    fn from_vars(get: impl Fn(&str) -> Option<String>) -> Result<AppConfig, ConfigError> {
+        let mut vars = Vars {
+            get,
+            problems: Vec::new(),
+        };
+
+        let database_url = vars.required("DATABASE_URL");
+
+        let current = vars.required("JWT_SECRET");
+        let previous: Vec<String> = vars
+            .optional("JWT_PREVIOUS_SECRETS")
+            .map(|secrets| {
+                secrets
+                    .split(',')
+                    .map(|secret| secret.trim().to_owned())
+                    .filter(|secret| !secret.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        // Servers already running with a short secret keep starting, until it is rotated.
+        if !current.is_empty() && current.len() < MIN_JWT_SECRET_BYTES {
+            println!(
+                "Warning: JWT_SECRET should be at least {MIN_JWT_SECRET_BYTES} bytes long, or \
+                 access tokens could be forged"
+            );
+        }
+        if previous
+            .iter()
+            .any(|secret| secret.len() < MIN_JWT_SECRET_BYTES)
+        {
+            println!(
+                "Warning: every secret in JWT_PREVIOUS_SECRETS should be at least \
+                 {MIN_JWT_SECRET_BYTES} bytes long, or access tokens could be forged"
+            );
+        }
+
+        let admin = Credentials {
+            username: vars.required("ADMIN_USERNAME"),
+            password: vars.required("ADMIN_PASSWORD"),
+        };
+        let editor = Credentials {
+            username: vars.required("EDITOR_USERNAME"),
+            password: vars.required("EDITOR_PASSWORD"),
+        };
+        if !admin.username.is_empty() && admin.username == editor.username {
+            vars.problems
+                .push("ADMIN_USERNAME and EDITOR_USERNAME should be different".into());
+        }
+
+        let build_dir = vars
+            .optional("BUILD_DIR")
+            .unwrap_or_else(|| "build".into())
+            .into();
+
+        let storage = match vars.optional("IMAGE_STORAGE").as_deref() {
+            None | Some("filesystem") => {
+                ImageStorageConfig::Filesystem(vars.required("ARTICLE_IMAGE_PATH").into())
+            }
+            Some("s3") => {
+                let endpoint = vars.required("S3_ENDPOINT");
+                let has_host = url::Url::parse(&endpoint).is_ok_and(|url| url.has_host());
+                if !endpoint.is_empty() && !has_host {
+                    vars.problems.push(format!(
+                        "S3_ENDPOINT should be a url with a host, not \"{endpoint}\""
+                    ));
+                }
+                ImageStorageConfig::S3 {
+                    endpoint,
+                    bucket: vars.required("S3_BUCKET"),
+                    // Also what MinIO expects.
+                    region: vars
+                        .optional("S3_REGION")
+                        .unwrap_or_else(|| "us-east-1".into()),
+                    access_key_id: vars.required("S3_ACCESS_KEY_ID"),
+                    secret_access_key: vars.required("S3_SECRET_ACCESS_KEY"),
+                }
+            }
+            Some(other) => {
+                vars.problems.push(format!(
+                    "IMAGE_STORAGE should be \"filesystem\" or \"s3\", not \"{other}\""
+                ));
+                ImageStorageConfig::Filesystem(PathBuf::new())
+            }
+        };
+        let sizes = match vars.optional("IMAGE_TRANSFORM_SIZES") {
+            Some(sizes) => sizes
+                .split(',')
+                .map(|size| size.trim().parse())
+                .collect::<Result<Vec<u32>, _>>()
+                .unwrap_or_else(|_| {
+                    vars.problems.push(format!(
+                        "IMAGE_TRANSFORM_SIZES should be a comma separated list of sizes, not \
+                         \"{sizes}\""
+                    ));
+                    Vec::new()
+                }),
+            None => DEFAULT_TRANSFORM_SIZES.to_vec(),
+        };
+        let cache_mb = vars.parsed(
+            "IMAGE_TRANSFORM_CACHE_MB",
+            DEFAULT_TRANSFORM_CACHE_MB,
+            "a whole number of megabytes",
+        );
+        let cache_max_bytes = cache_mb.checked_mul(1024 * 1024).unwrap_or_else(|| {
+            vars.problems.push(format!(
+                "IMAGE_TRANSFORM_CACHE_MB should be at most {} megabytes, not \"{cache_mb}\"",
+                u64::MAX / (1024 * 1024)
+            ));
+            0
+        });
+        let images = ImageConfig {
+            storage,
+            public_base_url: vars
+                .optional("IMAGE_PUBLIC_BASE_URL")
+                .unwrap_or_else(|| crate::images::storage::DEFAULT_PUBLIC_BASE_URL.into()),
+            transform: TransformConfig {
+                sizes,
+                cache_path: vars
+                    .optional("IMAGE_TRANSFORM_CACHE_PATH")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| std::env::temp_dir().join("image-transform-cache")),
+                cache_max_bytes,
+            },
+        };
+
+        let drive = DriveConfig {
+            client_secret_path: vars.required("CLIENT_SECRET_PATH").into(),
+            token_cache_path: vars
+                .optional("DRIVE_TOKEN_CACHE_PATH")
+                .unwrap_or_else(|| "tokencache.json".into())
+                .into(),
+        };
+
+        let secure = vars.parsed("COOKIE_SECURE", true, "true or false");
+        let same_site = match vars.optional("COOKIE_SAME_SITE").as_deref() {
+            None | Some("strict") => SameSite::Strict,
+            Some("lax") => SameSite::Lax,
+            Some("none") => SameSite::None,
+            Some(other) => {
+                vars.problems.push(format!(
+                    "COOKIE_SAME_SITE should be \"strict\", \"lax\" or \"none\", not \"{other}\""
+                ));
+                SameSite::Strict
+            }
+        };
+        if same_site == SameSite::None && !secure {
+            vars.problems.push(
+                "COOKIE_SAME_SITE can only be \"none\" when COOKIE_SECURE is true, as browsers \
+                 refuse such cookies otherwise"
+                    .into(),
+            );
+        }
+
+        let image_gc_interval_hours =
+            vars.parsed("IMAGE_GC_INTERVAL_HOURS", 24, "a whole number of hours");
+
+        let trusted_proxy_header = vars.optional("TRUSTED_PROXY_HEADER");
+        if let Some(header) = &trusted_proxy_header {
+            if !header
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-')
+            {
+                vars.problems.push(format!(
+                    "TRUSTED_PROXY_HEADER should be a header name, not \"{header}\""
+                ));
+            }
+        }
+
+        if !vars.problems.is_empty() {
+            return Err(ConfigError(vars.problems));
+        }
+        Ok(AppConfig {
+            database_url,
+            jwt_keys: JwtKeys { current, previous },
+            admin,
+            editor,
+            build_dir,
+            images,
+            drive,
+            cookies: CookieConfig { secure, same_site },
+            image_gc_interval_hours,
+            trusted_proxy_header,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    fn from(vars: &[(&str, &str)]) -> Result<AppConfig, ConfigError> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        AppConfig::from_vars(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn reads_a_full_configuration() {
+        let previous = format!("{}, {}", SECRET.to_uppercase(), SECRET.replace('0', "z"));
+        let config = from(&[
+            ("DATABASE_URL", "postgres://localhost/crate"),
+            ("JWT_SECRET", SECRET),
+            ("JWT_PREVIOUS_SECRETS", &previous),
+            ("ADMIN_USERNAME", "admin"),
+            ("ADMIN_PASSWORD", "hunter2"),
+            ("EDITOR_USERNAME", "editor"),
+            ("EDITOR_PASSWORD", "hunter3"),
+            ("ARTICLE_IMAGE_PATH", "/srv/images"),
+            ("CLIENT_SECRET_PATH", "secret.json"),
+            ("COOKIE_SAME_SITE", "lax"),
+        ])
+        .unwrap();
+
+        assert_eq!(config.jwt_keys.verifying().count(), 3);
+        assert_eq!(config.jwt_keys.verifying().next(), Some(SECRET));
+        assert!(config.cookies.secure);
+        assert_eq!(config.cookies.same_site, SameSite::Lax);
+        assert_eq!(config.image_gc_interval_hours, 24);
+        assert_eq!(config.trusted_proxy_header, None);
+        assert!(matches!(
+            config.images.storage,
+            ImageStorageConfig::Filesystem(path) if path == std::path::Path::new("/srv/images")
+        ));
+        assert_eq!(config.images.transform.sizes, DEFAULT_TRANSFORM_SIZES);
+        assert_eq!(config.images.transform.cache_max_bytes, 1024 * 1024 * 1024);
+    }
+
+    #[test]
+    fn reads_the_s3_bucket() {
+        let config = from(&[
+            ("DATABASE_URL", "postgres://localhost/crate"),
+            ("JWT_SECRET", SECRET),
+            ("ADMIN_USERNAME", "admin"),
+            ("ADMIN_PASSWORD", "hunter2"),
+            ("EDITOR_USERNAME", "editor"),
+            ("EDITOR_PASSWORD", "hunter3"),
+            ("IMAGE_STORAGE", "s3"),
+            ("S3_ENDPOINT", "http://localhost:9000"),
+            ("S3_BUCKET", "images"),
+            ("S3_ACCESS_KEY_ID", "minio"),
+            ("S3_SECRET_ACCESS_KEY", "minio123"),
+            ("IMAGE_TRANSFORM_SIZES", "100, 200"),
+            ("CLIENT_SECRET_PATH", "secret.json"),
+        ])
+        .unwrap();
+
+        let ImageStorageConfig::S3 { bucket, region, .. } = config.images.storage else {
+            panic!("images should be kept in S3");
+        };
+        assert_eq!(bucket, "images");
+        assert_eq!(region, "us-east-1");
+        assert_eq!(config.images.transform.sizes, [100, 200]);
+    }
+
+    #[test]
+    fn reports_s3_problems() {
+        let error = from(&[
+            ("DATABASE_URL", "postgres://localhost/crate"),
+            ("JWT_SECRET", SECRET),
+            ("ADMIN_USERNAME", "admin"),
+            ("ADMIN_PASSWORD", "hunter2"),
+            ("EDITOR_USERNAME", "editor"),
+            ("EDITOR_PASSWORD", "hunter3"),
+            ("IMAGE_STORAGE", "s3"),
+            ("S3_ENDPOINT", "localhost:9000"),
+            ("S3_BUCKET", "images"),
+            ("CLIENT_SECRET_PATH", "secret.json"),
+        ])
+        .err()
+        .unwrap();
+
+        assert_eq!(
+            error.0,
+            [
+                "S3_ENDPOINT should be a url with a host, not \"localhost:9000\"",
+                "S3_ACCESS_KEY_ID must be set",
+                "S3_SECRET_ACCESS_KEY must be set",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_a_cache_too_large_to_count_in_bytes() {
+        let error = from(&[
+            ("DATABASE_URL", "postgres://localhost/crate"),
+            ("JWT_SECRET", SECRET),
+            ("ADMIN_USERNAME", "admin"),
+            ("ADMIN_PASSWORD", "hunter2"),
+            ("EDITOR_USERNAME", "editor"),
+            ("EDITOR_PASSWORD", "hunter3"),
+            ("ARTICLE_IMAGE_PATH", "/srv/images"),
+            ("IMAGE_TRANSFORM_CACHE_MB", "18446744073709551615"),
+            ("CLIENT_SECRET_PATH", "secret.json"),
+        ])
+        .err()
+        .unwrap();
+
+        assert_eq!(
+            error.0,
+            [
+                "IMAGE_TRANSFORM_CACHE_MB should be at most 17592186044415 megabytes, not \
+                 \"18446744073709551615\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let error = from(&[
+            ("JWT_SECRET", "short"),
+            ("IMAGE_STORAGE", "ftp"),
+            ("IMAGE_TRANSFORM_SIZES", "100,big"),
+            ("IMAGE_TRANSFORM_CACHE_MB", "-1"),
+            ("COOKIE_SECURE", "yes"),
+            ("TRUSTED_PROXY_HEADER", "X-Real-IP: 1"),
+        ])
+        .err()
+        .unwrap();
+
+        assert_eq!(
+            error.0,
+            [
+                "DATABASE_URL must be set",
+                "ADMIN_USERNAME must be set",
+                "ADMIN_PASSWORD must be set",
+                "EDITOR_USERNAME must be set",
+                "EDITOR_PASSWORD must be set",
+                "IMAGE_STORAGE should be \"filesystem\" or \"s3\", not \"ftp\"",
+                "IMAGE_TRANSFORM_SIZES should be a comma separated list of sizes, not \"100,big\"",
+                "IMAGE_TRANSFORM_CACHE_MB should be a whole number of megabytes, not \"-1\"",
+                "CLIENT_SECRET_PATH must be set",
+                "COOKIE_SECURE should be true or false, not \"yes\"",
+                "TRUSTED_PROXY_HEADER should be a header name, not \"X-Real-IP: 1\"",
+            ]
+        );
+    }
+}
@@ -0,0 +1,99 @@
+//! Protection against cross-site request forgery of writes made with the session cookie.
+//!
+//! Every session gets a random token when it starts, which `/api/csrf-token` hands to the client.
+//! Writes to the API from someone logged in have to send it back in the [`HEADER`] header, which
+//! other sites can't read or set, so they can't make a logged in browser change anything. A
+//! request missing the token, or with the wrong one, is sent to [`CSRF_REJECTED_PATH`] instead of
+//! where it was going, which answers with `403 Forbidden`.
+//!
+//! Requests with an API key are left alone, as browsers never send one by themselves. So are
+//! requests without a valid access token, which can't change anything that needs a session, so
+//! logging in and refreshing an expired session need no token.
+
+use crate::auth::{current_api_key, current_session};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::Method;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::Data;
+use subtle::ConstantTimeEq;
+
+pub const HEADER: &str = "X-CSRF-Token";
+
+pub const CSRF_REJECTED_PATH: &str = "/api/csrf-rejected";
+
+pub struct Csrf;
+
+/// Why a request was sent to [`CSRF_REJECTED_PATH`].
+#[derive(Clone, Copy)]
+pub struct CsrfRejected;
+
+/// Whether `sent` is the session's token, compared in constant time so it can't be guessed a
+/// byte at a time.
+fn token_matches(sent: Option<&str>, expected: &str) -> bool {
+    sent.is_some_and(|sent| bool::from(sent.as_bytes().ct_eq(expected.as_bytes())))
+}
+
+#[rocket::async_trait]
+impl Fairing for Csrf {
+    fn info(&self) -> Info {
+        Info {
+            name: "Check the CSRF token of writes with a session",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        if !matches!(
+            req.method(),
+            Method::Post | Method::Put | Method::Patch | Method::Delete
+        ) || !req.uri().path().starts_with("/api/")
+        {
+            return;
+        }
+        if current_api_key(&*req).await.is_some() {
+            return;
+        }
+        let Some(session) = current_session(&*req).await else {
+            return;
+        };
+
+        if !token_matches(req.headers().get_one(HEADER), &session.csrf_token) {
+            println!(
+                "Rejected {} {} from {} without a valid CSRF token",
+                req.method(),
+                req.uri().path(),
+                session.username
+            );
+            req.local_cache(|| Some(CsrfRejected));
+            req.set_method(Method::Get);
+            req.set_uri(Origin::parse(CSRF_REJECTED_PATH).expect("valid path"));
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CsrfRejected {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match req.local_cache(|| None::<CsrfRejected>) {
+            Some(rejected) => Outcome::Success(*rejected),
+            None => Outcome::Forward(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_exact_token_matches() {
+        assert!(token_matches(Some("abc123"), "abc123"));
+        assert!(!token_matches(Some("abc124"), "abc123"));
+        assert!(!token_matches(Some("abc"), "abc123"));
+        assert!(!token_matches(Some(""), "abc123"));
+        assert!(!token_matches(None, "abc123"));
+    }
+}
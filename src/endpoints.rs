@@ -1,44 +1,74 @@
 use crate::article::{ArticleContent, ClientArticle, DBArticle, ServerArticle};
 use crate::article_submission::{
-    ClientArticleSubmission, DBArticleSubmission, ServerArticleSubmission,
+    ClientArticleSubmission, DBArticleSubmission, ServerArticleSubmission, SubmissionStatus,
 };
-use crate::auth::{create_jwt, AdminUser, EditorUser, LoginInfo, Role, User, COOKIE_SESSION_TOKEN};
+use crate::audit::{self, Actor, AuditFilter, Change, DBAuditEntry, Target};
+use crate::auth::account::{self, DBWriterAccount};
+use crate::auth::api_key::{self, ClientApiKey, DBApiKey};
+use crate::auth::permission::{
+    self, perm, ClientPermissionGrant, DBPermissionGrant, GrantedPermission, Permitted,
+};
+use crate::auth::refresh::{self, Rotation};
+use crate::auth::session::{self, DBSession};
+use crate::auth::throttle::LoginThrottle;
+use crate::auth::two_factor::{self, Enrollment};
+use crate::auth::{
+    create_jwt, CurrentSession, LoginInfo, Principal, Role, User, WriterUser, COOKIE_REFRESH_TOKEN,
+    COOKIE_SESSION_TOKEN,
+};
+use crate::client_ip::ClientIp;
+use crate::config::{AppConfig, CookieConfig};
+use crate::csrf::CsrfRejected;
 use crate::error::{APIError, APIResult};
+use crate::export::{ExportFormat, ExportedArticle};
 use crate::gdrive::drive_v3_types::FilesService;
+use crate::gdrive::parser::ParseError;
 use crate::gdrive::{self, ServerDriveFile};
+use crate::images;
+use crate::images::gc;
+use crate::images::library::{self, DBImage, ImagePatch, ImageStore};
+use crate::images::serve::{ImageRequestHeaders, ImageResponse};
+use crate::images::transform::{Fit, Transform, TransformError};
+use crate::import::{self, ImportFormat};
+use crate::links;
 use crate::paginated::Paginated;
+use crate::rate_limit::RateLimited;
 use crate::section::Section;
 use crate::writer::{ClientWriter, DBWriter, ServerWriter};
-use chrono::Datelike;
 use chrono::Utc;
 use diesel::prelude::*;
-use diesel::result::Error as DieselError;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
 use rocket::form::Form;
 use rocket::fs::NamedFile;
 use rocket::fs::TempFile;
-use rocket::http::{Cookie, CookieJar, SameSite, Status};
+use rocket::http::{Cookie, CookieJar, Status};
 use rocket::response::status;
 use rocket::serde::json::Json;
-use rocket::{delete, get, patch, post, uri, State};
+use rocket::tokio::task::block_in_place;
+use rocket::{delete, get, patch, post, put, uri, FromForm, State};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
-use uuid::Uuid;
+use std::time::Instant;
 
 lazy_static::lazy_static! {
     static ref SLUG_REGEX: regex::Regex = regex::Regex::new(r"[^A-Za-z0-9 -]").unwrap();
 }
 
+/// The most images listed on one page of the library.
+const MAX_IMAGES_PER_PAGE: i64 = 100;
+/// The most audit log entries listed on one page.
+const MAX_AUDIT_ENTRIES_PER_PAGE: i64 = 200;
+
 #[get("/<files..>", rank = 10000)]
-pub async fn index(build_dir: &State<String>, files: PathBuf) -> Option<NamedFile> {
-    async fn open_index(build_path: &str) -> Option<NamedFile> {
-        NamedFile::open(Path::new(build_path).join("index.html"))
-            .await
-            .ok()
+pub async fn index(config: &State<AppConfig>, files: PathBuf) -> Option<NamedFile> {
+    async fn open_index(build_path: &Path) -> Option<NamedFile> {
+        NamedFile::open(build_path.join("index.html")).await.ok()
     }
 
-    let path = Path::new(&**build_dir).join(files);
+    let build_dir = &config.build_dir;
+    let path = build_dir.join(files);
 
     if path.is_dir() {
         open_index(build_dir).await
@@ -52,105 +82,291 @@ pub async fn index(build_dir: &State<String>, files: PathBuf) -> Option<NamedFil
 
 #[post("/upload_picture", data = "<picture>")]
 pub async fn upload_picture(
-    mut picture: Form<TempFile<'_>>,
-    user: Option<EditorUser>,
-) -> APIResult<status::Created<()>> {
-    user.ok_or_else(APIError::unauthorized)?;
+    db_connection: &State<Mutex<PgConnection>>,
+    picture: Form<TempFile<'_>>,
+    user: Option<Permitted<perm::ImageUpload>>,
+    actor: Actor,
+) -> APIResult<status::Created<Json<DBImage>>> {
+    let user = user.ok_or_else(APIError::unauthorized)?;
+
+    let bytes = import::read_upload(&picture)
+        .await
+        .map_err(|_| APIError::default())?;
+
+    let format = images::validate_image(&bytes, picture.content_type())
+        .map_err(|err| APIError::new(Status::BadRequest, err.to_string()))?;
+
+    let store = ImageStore {
+        db_connection,
+        uploader: user.principal.role_name(),
+        actor: &actor,
+    };
+    let image = block_in_place(|| store.store(&bytes, format.extension()))?;
+
+    Ok(status::Created::new(image.path.clone()).body(Json(image)))
+}
+
+#[get("/images?<search>&<limit>&<page>")]
+pub fn get_images(
+    db_connection: &State<Mutex<PgConnection>>,
+    search: Option<&str>,
+    limit: Option<i64>,
+    page: Option<i64>,
+    user: Option<Permitted<perm::ImageUpload>>,
+) -> APIResult<Paginated<Vec<DBImage>>> {
+    use crate::schema::images::dsl::{created_at, images};
 
-    let content_type = picture.content_type();
+    user.ok_or_else(APIError::unauthorized)?;
 
-    if !matches!(content_type, Some(x) if x.is_jpeg()) {
+    let limit = limit.unwrap_or(20).min(MAX_IMAGES_PER_PAGE);
+    let page = page.unwrap_or(1);
+    if page <= 0 || limit <= 0 {
         return Err(APIError::new(
             Status::BadRequest,
-            "Required image type is JPEG".into(),
+            "Page and limit must be positive".into(),
         ));
     }
 
-    let image_dir = std::env::var("ARTICLE_IMAGE_PATH")
-        .expect("environment variable ARTICLE_IMAGE_PATH should be set");
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+
+    let mut query = images.into_boxed();
+    let mut count_query = images.into_boxed();
+    if let Some(search) = search.filter(|search| !search.trim().is_empty()) {
+        query = query.filter(library::matches_search(search));
+        count_query = count_query.filter(library::matches_search(search));
+    }
+
+    let image_count: i64 = count_query.count().get_result(db_connection)?;
 
-    let extension = "jpeg";
+    let ret_images = query
+        .order(created_at.desc())
+        .offset((page - 1) * limit)
+        .limit(limit)
+        .load::<DBImage>(db_connection)?;
 
-    let file_name = Uuid::new_v4().to_string();
+    Ok(Paginated::new(ret_images, limit, page, image_count))
+}
 
-    let utc_now = chrono::Utc::now();
-    let (year, month) = (utc_now.year(), utc_now.month());
+#[patch("/images/<id>", data = "<patch>")]
+pub fn patch_image(
+    db_connection: &State<Mutex<PgConnection>>,
+    id: i32,
+    patch: Option<Json<ImagePatch>>,
+    user: Option<Permitted<perm::ImageUpload>>,
+    actor: Actor,
+) -> APIResult<Json<DBImage>> {
+    use crate::schema::images::dsl::images;
 
-    // image path: images/<year>/<month>/<name>.<extension>
-    let mut path = PathBuf::from(image_dir);
-    path.push(year.to_string());
-    path.push(month.to_string());
-    path.push(&file_name);
-    path.set_extension(extension);
+    user.ok_or_else(APIError::unauthorized)?;
 
-    // Create the image directory if it doesn't exist.
-    if let Some(parent) = path.parent() {
-        std::fs::create_dir_all(parent).unwrap();
+    let Some(patch) = patch else {
+        return Err(APIError::new(
+            Status::BadRequest,
+            "Invalid image format.".into(),
+        ));
+    };
+    if patch.is_empty() {
+        return Err(APIError::new(
+            Status::BadRequest,
+            "Give a credit, caption or alt text to change.".into(),
+        ));
     }
 
-    picture
-        .persist_to(path)
-        .await
-        .map_err(|_| APIError::default())?;
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+
+    let not_found = || APIError::new(Status::NotFound, format!("No image with id {id}."));
+
+    let image = db_connection.transaction::<_, APIError, _>(|| {
+        let before = images
+            .find(id)
+            .first::<DBImage>(db_connection)
+            .optional()?
+            .ok_or_else(not_found)?;
+        let image = diesel::update(images.find(id))
+            .set(patch.into_inner())
+            .get_result::<DBImage>(db_connection)
+            .map_err(|err| match err {
+                DieselError::NotFound => not_found(),
+                _ => APIError::from(err),
+            })?;
+        actor.record(
+            db_connection,
+            "update",
+            Target::Image(id),
+            Change::updated(&before, &image),
+        )?;
+        Ok(image)
+    })?;
 
-    let loc = format!("/image/{year}/{month}/{file_name}.jpeg");
+    Ok(Json(image))
+}
 
-    Ok(status::Created::new(loc))
+#[derive(FromForm)]
+pub struct DocumentUpload<'r> {
+    document: TempFile<'r>,
+    /// Images referenced by file name from a Markdown document.
+    images: Vec<TempFile<'r>>,
 }
 
-// #[post("/writers/headshot", data = "<headshot>")]
-// pub async fn post_headshot(
-//     mut headshot: Form<TempFile<'_>>,
-//     user: Option<AdminUser>,
-// ) -> APIResult<status::Created<()>> {
-//     user.ok_or_else(APIError::unauthorized)?;
+#[post("/import", data = "<upload>")]
+pub async fn import_document(
+    db_connection: &State<Mutex<PgConnection>>,
+    upload: Form<DocumentUpload<'_>>,
+    user: Option<Permitted<perm::ArticleDraft>>,
+    actor: Actor,
+) -> APIResult<Json<ArticleContent>> {
+    let user = user.ok_or_else(APIError::unauthorized)?;
+
+    let format = ImportFormat::from_file(&upload.document).ok_or_else(|| {
+        APIError::new(
+            Status::BadRequest,
+            "Document must be a .docx or .md file".into(),
+        )
+    })?;
+
+    let document = import::read_upload(&upload.document)
+        .await
+        .map_err(|_| APIError::default())?;
+
+    let mut attachments = HashMap::new();
+    if format == ImportFormat::Markdown {
+        for image in &upload.images {
+            let Some(name) = image.raw_name() else {
+                continue;
+            };
+            let name = name.dangerous_unsafe_unsanitized_raw().as_str().to_owned();
+            let bytes = import::read_upload(image)
+                .await
+                .map_err(|_| APIError::default())?;
+            attachments.insert(name, bytes);
+        }
+    }
+
+    let store = ImageStore {
+        db_connection,
+        uploader: user.principal.role_name(),
+        actor: &actor,
+    };
 
-//     let content_type = headshot.content_type();
+    let content = match format {
+        ImportFormat::Docx => block_in_place(|| import::docx::import(&document, &store)),
+        ImportFormat::Markdown => {
+            let source = String::from_utf8(document).map_err(|_| {
+                APIError::new(
+                    Status::BadRequest,
+                    "Markdown documents must be UTF-8".into(),
+                )
+            })?;
+
+            block_in_place(|| import::markdown::import(&source, &attachments, &store))
+        }
+    };
 
-//     if !matches!(content_type, Some(x) if x.is_jpeg()) {
-//         return Err(APIError::new(
-//             Status::BadRequest,
-//             "Required image type is JPEG".into(),
-//         ));
-//     }
+    let mut content = content.map_err(|err| APIError::new(Status::BadRequest, err.to_string()))?;
 
-//     let image_dir = std::env::var("ARTICLE_IMAGE_PATH")
-//         .expect("environment variable ARTICLE_IMAGE_PATH should be set");
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    db_connection.transaction::<_, APIError, _>(|| {
+        library::record_article_images(db_connection, &mut content, user.principal.role_name())?;
+        actor.record(db_connection, "import", Target::Document, Change::none())?;
+        Ok(())
+    })?;
 
-//     let extension = "jpeg";
+    Ok(Json(content))
+}
 
-//     let file_name = Uuid::new_v4().to_string();
+/// Sets a writer's headshot, replacing any previous one. Writers can set their own.
+#[post("/writers/<id>/headshot", data = "<headshot>")]
+pub async fn post_headshot(
+    db_connection: &State<Mutex<PgConnection>>,
+    headshot: Form<TempFile<'_>>,
+    id: i32,
+    user: Option<Permitted<perm::WriterManage>>,
+    writer: Option<WriterUser>,
+    actor: Actor,
+) -> APIResult<status::Created<Json<ServerWriter>>> {
+    use crate::schema::writers::dsl::{image_url, writers};
+
+    if user.is_none() && !is_writer(&writer, id) {
+        return Err(APIError::unauthorized());
+    }
 
-//     let utc_now = chrono::Utc::now();
-//     let (year, month) = (utc_now.year(), utc_now.month());
+    let bytes = import::read_upload(&headshot)
+        .await
+        .map_err(|_| APIError::default())?;
 
-//     // image path: images/<year>/<month>/<name>.<extension>
-//     let mut path = PathBuf::from(image_dir);
-//     path.push(year.to_string());
-//     path.push(month.to_string());
-//     path.push(&file_name);
-//     path.set_extension(extension);
+    let format = images::validate_image(&bytes, headshot.content_type())
+        .map_err(|err| APIError::new(Status::BadRequest, err.to_string()))?;
+    if format == images::ImageFormat::Avif {
+        return Err(APIError::new(
+            Status::BadRequest,
+            "Headshots must be JPEG, PNG, WebP or GIF images".into(),
+        ));
+    }
 
-//     // Create the image directory if it doesn't exist.
-//     if let Some(parent) = path.parent() {
-//         std::fs::create_dir_all(parent).unwrap();
-//     }
+    // Cropping and scaling the photo is slow, so it is done before taking the database lock. A
+    // headshot stored for a writer that turns out not to exist is deleted again below.
+    let url = rocket::tokio::task::spawn_blocking(move || images::store_headshot(&bytes, id))
+        .await
+        .map_err(|_| APIError::default())?
+        .map_err(|err| {
+            println!("Could not store headshot for writer {id}: {err}");
+            APIError::default()
+        })?;
 
-//     headshot
-//         .persist_to(path)
-//         .await
-//         .map_err(|_| APIError::default())?;
+    let (previous, writer) = {
+        let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+
+        // Lock the row so concurrent uploads can't both think they are replacing the same headshot.
+        db_connection
+            .transaction::<_, DieselError, _>(|| {
+                let previous = writers
+                    .find(id)
+                    .for_update()
+                    .first::<DBWriter>(db_connection)?;
+                let writer = diesel::update(writers.find(id))
+                    .set(image_url.eq(&url))
+                    .get_result::<DBWriter>(db_connection)?;
+                actor.record(
+                    db_connection,
+                    "update",
+                    Target::Writer(id),
+                    Change::updated(&previous, &writer),
+                )?;
+                Ok((previous, writer))
+            })
+            .map_err(|err| {
+                // Nothing refers to the new headshot if the writer wasn't updated.
+                if let Err(err) = images::delete_headshot(&url) {
+                    println!("Could not delete unused headshot {url}: {err}");
+                }
+                match err {
+                    DieselError::NotFound => {
+                        APIError::new(Status::NotFound, format!("No writer with id {id} found."))
+                    }
+                    _ => APIError::from(err),
+                }
+            })?
+    };
 
-//     let loc = format!("/image/{year}/{month}/{file_name}.jpeg");
+    // The same photo uploaded again is stored under the same name, so it mustn't be deleted.
+    if let Some(previous_url) = previous
+        .image_url
+        .filter(|previous_url| *previous_url != url)
+    {
+        if let Err(err) = images::delete_headshot(&previous_url) {
+            println!("Could not delete previous headshot {previous_url}: {err}");
+        }
+    }
 
-//     Ok(status::Created::new(loc))
-// }
+    Ok(status::Created::new(url).body(Json(writer)))
+}
 
 #[post("/writers", data = "<writer>")]
 pub fn post_writers(
     db_connection: &State<Mutex<PgConnection>>,
     writer: Option<Json<ClientWriter<'_>>>,
-    user: Option<AdminUser>,
+    user: Option<Permitted<perm::WriterManage>>,
+    actor: Actor,
 ) -> Result<status::Created<Json<ServerWriter>>, APIError> {
     use crate::schema::writers::dsl::writers;
 
@@ -167,27 +383,46 @@ pub fn post_writers(
     };
 
     let db_connection = db_connection.lock().map_err(|_| APIError::default())?;
-    let inserted_writer = diesel::insert_into(writers)
-        .values(writer.into_inner())
-        .get_results::<DBWriter>(&*db_connection)?
-        .swap_remove(0);
+    let inserted_writer = db_connection.transaction::<_, DieselError, _>(|| {
+        let inserted_writer = diesel::insert_into(writers)
+            .values(writer.into_inner())
+            .get_results::<DBWriter>(&*db_connection)?
+            .swap_remove(0);
+        actor.record(
+            &db_connection,
+            "create",
+            Target::Writer(inserted_writer.id),
+            Change::created(&inserted_writer),
+        )?;
+        Ok(inserted_writer)
+    })?;
 
     let location = uri!("/api", get_writer(inserted_writer.id)).to_string();
 
     Ok(status::Created::new(location).body(Json(inserted_writer)))
 }
 
+/// Whether `writer` is logged in to the account of the writer with the given id.
+fn is_writer(writer: &Option<WriterUser>, id: i32) -> bool {
+    writer.as_ref().is_some_and(|writer| writer.writer_id == id)
+}
+
+/// Changes a writer's profile. Writers can change their own bio, but nothing else.
 #[allow(clippy::extra_unused_lifetimes)]
 #[patch("/writers/<id>", data = "<new_writer>")]
 pub fn patch_writer_by_id(
     db_connection: &State<Mutex<PgConnection>>,
     new_writer: Option<Json<HashMap<&str, &str>>>,
     id: i32,
-    user: Option<AdminUser>,
+    user: Option<Permitted<perm::WriterManage>>,
+    writer: Option<WriterUser>,
+    actor: Actor,
 ) -> Result<(), APIError> {
     use crate::schema::writers;
 
-    user.ok_or_else(APIError::unauthorized)?;
+    if user.is_none() && !is_writer(&writer, id) {
+        return Err(APIError::unauthorized());
+    }
 
     let mut new_writer = match new_writer {
         Some(writer) => writer,
@@ -199,6 +434,13 @@ pub fn patch_writer_by_id(
         }
     };
 
+    if user.is_none() && new_writer.keys().any(|field| *field != "bio") {
+        return Err(APIError::new(
+            Status::Forbidden,
+            "Writers can only change their own bio.".into(),
+        ));
+    }
+
     let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
 
     #[derive(AsChangeset)]
@@ -215,22 +457,166 @@ pub fn patch_writer_by_id(
     let bio = new_writer.remove("bio");
     let title = new_writer.remove("title");
 
-    diesel::update(writers::table.find(id))
-        .set(PatchWriter {
-            first_name,
-            last_name,
-            bio,
-            title,
+    let not_found = || APIError::new(Status::NotFound, format!("No writer with {id}."));
+
+    db_connection.transaction::<_, APIError, _>(|| {
+        let before = writers::table
+            .find(id)
+            .first::<DBWriter>(db_connection)
+            .optional()?
+            .ok_or_else(not_found)?;
+        let after = diesel::update(writers::table.find(id))
+            .set(PatchWriter {
+                first_name,
+                last_name,
+                bio,
+                title,
+            })
+            .get_result::<DBWriter>(db_connection)
+            .map_err(|err| match err {
+                DieselError::NotFound => not_found(),
+                _ => APIError::from(err),
+            })?;
+        actor.record(
+            db_connection,
+            "update",
+            Target::Writer(id),
+            Change::updated(&before, &after),
+        )?;
+        Ok(())
+    })
+}
+
+#[derive(Deserialize)]
+pub struct ClientWriterAccount<'a> {
+    pub username: &'a str,
+    pub password: &'a str,
+}
+
+/// Gives a writer an account to log in with.
+#[post("/writers/<id>/account", data = "<new_account>")]
+pub fn post_writer_account(
+    db_connection: &State<Mutex<PgConnection>>,
+    config: &State<AppConfig>,
+    id: i32,
+    new_account: Option<Json<ClientWriterAccount<'_>>>,
+    user: Option<Permitted<perm::WriterManage>>,
+    actor: Actor,
+) -> APIResult<status::Created<Json<DBWriterAccount>>> {
+    use crate::schema::writers;
+
+    user.ok_or_else(APIError::unauthorized)?;
+    let new_account = new_account
+        .ok_or_else(|| APIError::new(Status::BadRequest, "Invalid account format.".into()))?;
+
+    let username = new_account.username.trim();
+    // The admin and editor log in with these, so a writer can't have them too.
+    let reserved = [&config.admin, &config.editor]
+        .into_iter()
+        .any(|credentials| credentials.username == username);
+    if username.is_empty() || reserved {
+        return Err(APIError::new(
+            Status::BadRequest,
+            format!("The username \"{username}\" is not available."),
+        ));
+    }
+    if new_account.password.chars().count() < account::MIN_PASSWORD_LENGTH {
+        return Err(APIError::new(
+            Status::BadRequest,
+            format!(
+                "Passwords must be at least {} characters long.",
+                account::MIN_PASSWORD_LENGTH
+            ),
+        ));
+    }
+
+    // Hashing takes a while, so it's done before taking the connection.
+    let password_hash =
+        block_in_place(|| account::hash_password(new_account.password)).map_err(|err| {
+            println!("{err}");
+            APIError::default()
+        })?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+
+    writers::table
+        .find(id)
+        .select(writers::id)
+        .first::<i32>(db_connection)
+        .optional()?
+        .ok_or_else(|| APIError::new(Status::NotFound, format!("No writer with id {id} found.")))?;
+    let created = db_connection
+        .transaction::<_, DieselError, _>(|| {
+            let created = account::create(db_connection, id, username, password_hash)?;
+            actor.record(
+                db_connection,
+                "create",
+                Target::WriterAccount(id),
+                Change::created(&created),
+            )?;
+            Ok(created)
         })
-        .execute(db_connection)
         .map_err(|err| match err {
-            DieselError::NotFound => {
-                APIError::new(Status::NotFound, format!("No writer with {id}."))
-            }
+            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => APIError::new(
+                Status::Conflict,
+                format!("Writer {id} already has an account, or \"{username}\" is taken."),
+            ),
             _ => APIError::from(err),
         })?;
 
-    Ok(())
+    let location = uri!("/api", get_writer(created.writer_id)).to_string();
+    Ok(status::Created::new(location).body(Json(created)))
+}
+
+/// Takes away a writer's account, logging them out. Their two-factor setup, the permissions granted
+/// to them and the API keys they created go with it, so nothing carries over to anyone who later
+/// gets the same username.
+#[delete("/writers/<id>/account")]
+pub fn delete_writer_account(
+    db_connection: &State<Mutex<PgConnection>>,
+    id: i32,
+    user: Option<Permitted<perm::WriterManage>>,
+    actor: Actor,
+) -> APIResult<status::Accepted<()>> {
+    user.ok_or_else(APIError::unauthorized)?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    db_connection.transaction::<_, APIError, _>(|| {
+        let deleted = account::delete_for_writer(db_connection, id)?.ok_or_else(|| {
+            APIError::new(Status::NotFound, format!("Writer {id} has no account."))
+        })?;
+        session::revoke_all(db_connection, &deleted.username)?;
+        two_factor::disable(db_connection, &deleted.username)?;
+        permission::revoke_user_grants(db_connection, &deleted.username)?;
+        api_key::revoke_created_by(db_connection, &deleted.username)?;
+        actor.record(
+            db_connection,
+            "delete",
+            Target::WriterAccount(id),
+            Change::deleted(&deleted),
+        )?;
+        Ok(())
+    })?;
+
+    Ok(status::Accepted(Some(())))
+}
+
+/// The profile of the writer who is logged in.
+#[get("/current/writer")]
+pub fn current_writer(
+    db_connection: &State<Mutex<PgConnection>>,
+    writer: Option<WriterUser>,
+) -> APIResult<Json<ServerWriter>> {
+    use crate::schema::writers;
+
+    let writer = writer.ok_or_else(APIError::unauthorized)?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    Ok(Json(
+        writers::table
+            .find(writer.writer_id)
+            .first::<DBWriter>(db_connection)?,
+    ))
 }
 
 #[get("/writers")]
@@ -298,7 +684,7 @@ pub fn get_writer_by_name(
 pub fn get_writer_id_articles(
     db_connection: &State<Mutex<PgConnection>>,
     id: i32,
-    user: Option<AdminUser>,
+    user: Option<Permitted<perm::ArticleEdit>>,
 ) -> Result<Json<Vec<ServerArticle>>, APIError> {
     use crate::schema::articles::dsl::{articles, writer_id};
     use crate::schema::writers::dsl::{id as writer_table_id, writers};
@@ -326,10 +712,7 @@ pub fn get_writer_id_articles(
         .load::<(DBArticle, DBWriter)>(db_connection)
         .map_err(APIError::from)?;
 
-    let mut output = Vec::new();
-    for (article, writer) in ret_articles {
-        output.push(ServerArticle::new(article, writer, user)?);
-    }
+    let output = ServerArticle::list(db_connection, ret_articles, user.as_ref())?;
     Ok(Json(output))
 }
 
@@ -337,11 +720,12 @@ pub fn get_writer_id_articles(
 pub fn post_articles(
     db_connection: &State<Mutex<PgConnection>>,
     article: Option<Json<ClientArticle>>,
-    user: Option<AdminUser>,
+    user: Option<Permitted<perm::ArticlePublish>>,
+    actor: Actor,
 ) -> Result<status::Created<Json<ServerArticle>>, APIError> {
     use crate::schema::*;
 
-    user.ok_or_else(APIError::unauthorized)?;
+    let user = user.ok_or_else(APIError::unauthorized)?;
 
     let article = match article {
         Some(article) => article,
@@ -352,6 +736,7 @@ pub fn post_articles(
             ))
         }
     };
+    user.require(article.section)?;
 
     let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
     let writer = writers::table
@@ -371,25 +756,40 @@ pub fn post_articles(
 
     println!("{slug:?}");
 
-    let inserted_article = diesel::insert_into(articles::table)
-        .values((
-            articles::body
-                .eq(serde_json::to_string(&article.content).map_err(|_| APIError::default())?),
-            articles::headline.eq(article.content.headline.clone()),
-            articles::slug.eq(slug),
-            articles::writer_id.eq(article.writer_id),
-            articles::section.eq(article.section),
-            articles::publication_date.eq(Utc::now().naive_utc()),
-            articles::focus.eq(article.focus),
-            articles::image_url.eq(article.image_url),
-            articles::drive_file_id.eq(article.drive_file_id),
-            articles::featured.eq(article.featured.unwrap_or(false)),
-        ))
-        .get_results::<DBArticle>(db_connection)?
-        .swap_remove(0);
-
-    let ret_article =
-        ServerArticle::with_content(inserted_article, article.into_inner().content, writer, user);
+    let inserted_article = db_connection.transaction::<_, APIError, _>(|| {
+        let inserted_article = diesel::insert_into(articles::table)
+            .values((
+                articles::body
+                    .eq(serde_json::to_string(&article.content).map_err(|_| APIError::default())?),
+                articles::headline.eq(article.content.headline.clone()),
+                articles::slug.eq(slug),
+                articles::writer_id.eq(article.writer_id),
+                articles::section.eq(article.section),
+                articles::publication_date.eq(Utc::now().naive_utc()),
+                articles::focus.eq(article.focus),
+                articles::image_url.eq(article.image_url.map(crate::images::normalize_url)),
+                articles::drive_file_id.eq(article.drive_file_id),
+                articles::featured.eq(article.featured.unwrap_or(false)),
+            ))
+            .get_results::<DBArticle>(db_connection)?
+            .swap_remove(0);
+
+        actor.record(
+            db_connection,
+            "create",
+            Target::Article(inserted_article.id),
+            Change::created(&inserted_article),
+        )?;
+        Ok(inserted_article)
+    })?;
+
+    let ret_article = ServerArticle::with_content(
+        db_connection,
+        inserted_article,
+        article.into_inner().content,
+        writer,
+        Some(&user),
+    )?;
 
     let location = uri!("/api", get_article(ret_article.id)).to_string();
 
@@ -399,7 +799,7 @@ pub fn post_articles(
 #[get("/articles/featured")]
 pub fn get_featured_article(
     db_connection: &State<Mutex<PgConnection>>,
-    user: Option<AdminUser>,
+    user: Option<Permitted<perm::ArticleEdit>>,
 ) -> APIResult<Json<ServerArticle>> {
     use crate::schema::articles::dsl::{articles, featured};
     use crate::schema::writers::dsl::writers;
@@ -417,7 +817,12 @@ pub fn get_featured_article(
             _ => APIError::from(err),
         })?;
 
-    let server_article = ServerArticle::new(featured_article.0, featured_article.1, user)?;
+    let server_article = ServerArticle::new(
+        db_connection,
+        featured_article.0,
+        featured_article.1,
+        user.as_ref(),
+    )?;
     Ok(Json(server_article))
 }
 
@@ -436,7 +841,8 @@ pub fn patch_article_by_id(
     db_connection: &State<Mutex<PgConnection>>,
     new_article: Option<Json<ArticlePatchArguments>>,
     id: i32,
-    user: Option<AdminUser>,
+    user: Option<Permitted<perm::ArticleEdit>>,
+    actor: Actor,
 ) -> APIResult<()> {
     use crate::schema::articles;
 
@@ -450,7 +856,7 @@ pub fn patch_article_by_id(
         featured: Option<bool>,
     }
 
-    user.ok_or_else(APIError::unauthorized)?;
+    let user = user.ok_or_else(APIError::unauthorized)?;
 
     let new_article = match new_article {
         Some(article) => article,
@@ -464,31 +870,53 @@ pub fn patch_article_by_id(
 
     let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
 
-    let body = if let Some(body) = &new_article.body {
-        Some(serde_json::to_string(body).map_err(|_| APIError::default())?)
-    } else {
-        None
-    };
-
-    let patch = PatchArticle {
-        body,
-        section: new_article.section,
-        writer_id: new_article.writer_id,
-        image_url: new_article.image_url.clone(),
-        featured: new_article.featured,
-    };
-
-    diesel::update(articles::table.find(id))
-        .set(patch)
-        .execute(db_connection)
-        .map_err(|err| match err {
-            DieselError::NotFound => {
-                APIError::new(Status::NotFound, format!("No article with {id}."))
-            }
-            _ => APIError::from(err),
-        })?;
+    db_connection.transaction::<_, APIError, _>(|| {
+        let before = articles::table
+            .find(id)
+            .first::<DBArticle>(db_connection)
+            .map_err(|err| match err {
+                DieselError::NotFound => {
+                    APIError::new(Status::NotFound, format!("No article with {id}."))
+                }
+                _ => APIError::from(err),
+            })?;
+        // Moving an article to another section takes permission for both.
+        user.require(before.section)?;
+        if let Some(section) = new_article.section {
+            user.require(section)?;
+        }
 
-    Ok(())
+        let body = if let Some(body) = &new_article.body {
+            Some(serde_json::to_string(body).map_err(|_| APIError::default())?)
+        } else {
+            None
+        };
+
+        let patch = PatchArticle {
+            body,
+            section: new_article.section,
+            writer_id: new_article.writer_id,
+            image_url: new_article.image_url.as_deref().map(images::normalize_url),
+            featured: new_article.featured,
+        };
+
+        let after = diesel::update(articles::table.find(id))
+            .set(patch)
+            .get_result::<DBArticle>(db_connection)
+            .map_err(|err| match err {
+                DieselError::NotFound => {
+                    APIError::new(Status::NotFound, format!("No article with {id}."))
+                }
+                _ => APIError::from(err),
+            })?;
+        actor.record(
+            db_connection,
+            "update",
+            Target::Article(id),
+            Change::updated(&before, &after),
+        )?;
+        Ok(())
+    })
 }
 
 #[get("/articles?<limit>&<page>", rank = 2)]
@@ -496,7 +924,7 @@ pub fn get_articles(
     db_connection: &State<Mutex<PgConnection>>,
     limit: Option<i64>,
     page: Option<i64>,
-    user: Option<AdminUser>,
+    user: Option<Permitted<perm::ArticleEdit>>,
 ) -> Result<Paginated<Vec<ServerArticle>>, APIError> {
     use crate::schema::articles::dsl::{articles, publication_date};
     use crate::schema::writers::dsl::writers;
@@ -521,11 +949,7 @@ pub fn get_articles(
         .limit(limit)
         .load::<(DBArticle, DBWriter)>(db_connection)?;
 
-    let mut output = Vec::with_capacity(ret_articles.len());
-
-    for (article, writer) in ret_articles {
-        output.push(ServerArticle::new(article, writer, user)?);
-    }
+    let output = ServerArticle::list(db_connection, ret_articles, user.as_ref())?;
 
     Ok(Paginated::new(output, limit, page, article_count))
 }
@@ -536,7 +960,7 @@ pub fn get_articles_by_section(
     section: Option<Section>,
     limit: Option<i64>,
     page: Option<i64>,
-    user: Option<AdminUser>,
+    user: Option<Permitted<perm::ArticleEdit>>,
 ) -> APIResult<Paginated<Vec<ServerArticle>>> {
     use crate::schema::articles::dsl::{articles, publication_date, section as articleSection};
     use crate::schema::writers::dsl::writers;
@@ -569,11 +993,7 @@ pub fn get_articles_by_section(
         .limit(limit)
         .load::<(DBArticle, DBWriter)>(db_connection)?;
 
-    let mut output = Vec::with_capacity(ret_articles.len());
-
-    for (article, writer) in ret_articles {
-        output.push(ServerArticle::new(article, writer, user)?);
-    }
+    let output = ServerArticle::list(db_connection, ret_articles, user.as_ref())?;
 
     Ok(Paginated::new(output, limit, page, article_count))
 }
@@ -582,39 +1002,193 @@ pub fn get_articles_by_section(
 pub fn delete_article(
     db_connection: &State<Mutex<PgConnection>>,
     id: i32,
-    user: Option<AdminUser>,
+    user: Option<Permitted<perm::ArticlePublish>>,
+    actor: Actor,
 ) -> Result<status::Accepted<()>, APIError> {
     use crate::schema::articles::dsl::{articles, id as article_id};
     use std::cmp::Ordering;
 
-    user.ok_or_else(APIError::unauthorized)?;
+    let user = user.ok_or_else(APIError::unauthorized)?;
 
     let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
 
-    let deleted_count =
-        diesel::delete(articles.filter(article_id.eq(id))).execute(db_connection)?;
+    let article = articles
+        .find(id)
+        .first::<DBArticle>(db_connection)
+        .map_err(|err| match err {
+            DieselError::NotFound => {
+                APIError::new(Status::NotFound, format!("No article with id {id}"))
+            }
+            _ => APIError::from(err),
+        })?;
+    user.require(article.section)?;
+
+    db_connection.transaction::<_, APIError, _>(|| {
+        let deleted_count =
+            diesel::delete(articles.filter(article_id.eq(id))).execute(db_connection)?;
 
-    match deleted_count.cmp(&1) {
-        Ordering::Greater => {
-            println!("Deleted more than 1 article... something has gone very wrong");
+        match deleted_count.cmp(&1) {
+            Ordering::Greater => {
+                println!("Deleted more than 1 article... something has gone very wrong");
+            }
+            Ordering::Less => {
+                return Err(APIError::new(
+                    Status::NotFound,
+                    format!("No article with id {id}"),
+                ))
+            }
+            Ordering::Equal => {}
         }
-        Ordering::Less => {
-            return Err(APIError::new(
-                Status::NotFound,
-                format!("No article with id {id}"),
-            ))
+        actor.record(
+            db_connection,
+            "delete",
+            Target::Article(id),
+            Change::deleted(&article),
+        )?;
+        Ok(status::Accepted(Some(())))
+    })
+}
+
+#[derive(Serialize)]
+pub struct LinkCleanupReport {
+    articles_checked: usize,
+    articles_updated: Vec<i32>,
+}
+
+/// Rewrites the links in every stored article the same way new imports are cleaned, unwrapping
+/// Google redirects and dropping tracking parameters.
+#[post("/maintenance/clean-links")]
+pub fn clean_article_links(
+    db_connection: &State<Mutex<PgConnection>>,
+    user: Option<Permitted<perm::SiteMaintain>>,
+    actor: Actor,
+) -> APIResult<Json<LinkCleanupReport>> {
+    use crate::schema::articles::dsl::{articles, body, id};
+
+    user.ok_or_else(APIError::unauthorized)?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+
+    let report = db_connection.transaction::<_, APIError, _>(|| {
+        let bodies = articles
+            .select((id, body))
+            .load::<(i32, String)>(db_connection)?;
+
+        let mut articles_updated = Vec::new();
+        for (article_id, article_body) in &bodies {
+            let Ok(mut content) = serde_json::from_str::<ArticleContent>(article_body) else {
+                println!("Skipping article {article_id}, its body could not be parsed");
+                continue;
+            };
+
+            if links::clean_article_links(&mut content) {
+                let new_body = serde_json::to_string(&content).map_err(|_| APIError::default())?;
+                diesel::update(articles.find(article_id))
+                    .set(body.eq(new_body))
+                    .execute(db_connection)?;
+                articles_updated.push(*article_id);
+            }
+        }
+
+        let report = LinkCleanupReport {
+            articles_checked: bodies.len(),
+            articles_updated,
+        };
+        actor.record(
+            db_connection,
+            "clean_links",
+            Target::Site,
+            Change::result(&report),
+        )?;
+        Ok(report)
+    })?;
+
+    Ok(Json(report))
+}
+
+/// Strips location and camera metadata from every stored image, see
+/// [`images::strip_stored_metadata`].
+#[post("/maintenance/strip-image-metadata")]
+pub async fn strip_image_metadata(
+    db_connection: &State<Mutex<PgConnection>>,
+    user: Option<Permitted<perm::SiteMaintain>>,
+    actor: Actor,
+) -> APIResult<Json<images::MetadataCleanupReport>> {
+    user.ok_or_else(APIError::unauthorized)?;
+
+    let report = rocket::tokio::task::spawn_blocking(images::strip_stored_metadata)
+        .await
+        .map_err(|_| APIError::default())?
+        .map_err(|_| APIError::default())?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    db_connection.transaction::<_, APIError, _>(|| {
+        for (url, variants) in &report.variants {
+            library::set_variants(db_connection, url, variants).map_err(|_| APIError::default())?;
         }
-        Ordering::Equal => {}
+        actor.record(
+            db_connection,
+            "strip_image_metadata",
+            Target::Site,
+            Change::result(&report),
+        )?;
+        Ok(())
+    })?;
+
+    Ok(Json(report))
+}
+
+/// Reports stored images that nothing references and moves those past the grace period to the
+/// trash. Nothing is moved unless `dry_run=false` is given.
+#[post("/maintenance/collect-orphaned-images?<dry_run>&<grace_days>")]
+pub fn collect_orphaned_images(
+    db_connection: &State<Mutex<PgConnection>>,
+    dry_run: Option<bool>,
+    grace_days: Option<i64>,
+    user: Option<Permitted<perm::SiteMaintain>>,
+    actor: Actor,
+) -> APIResult<Json<gc::GcReport>> {
+    user.ok_or_else(APIError::unauthorized)?;
+
+    let grace_days = grace_days.unwrap_or(gc::DEFAULT_GRACE_PERIOD_DAYS);
+    if grace_days < 0 {
+        return Err(APIError::new(
+            Status::BadRequest,
+            "Grace period can't be negative".into(),
+        ));
     }
 
-    Ok(status::Accepted(Some(())))
+    let options = gc::GcOptions {
+        dry_run: dry_run.unwrap_or(true),
+        grace_period: chrono::Duration::days(grace_days),
+    };
+
+    let references = {
+        let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+        gc::referenced_images(db_connection)?
+    };
+
+    // Listing and moving files can take a while, so it's done without holding the connection.
+    let report = gc::collect_orphans(&references, &options)?;
+
+    if !options.dry_run {
+        let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+        actor.record(
+            db_connection,
+            "collect_orphaned_images",
+            Target::Site,
+            Change::result(&report),
+        )?;
+    }
+
+    Ok(Json(report))
 }
 
 #[get("/articles/<id>", rank = 1)]
 pub fn get_article(
     db_connection: &State<Mutex<PgConnection>>,
     id: i32,
-    user: Option<AdminUser>,
+    user: Option<Permitted<perm::ArticleEdit>>,
 ) -> Result<Json<ServerArticle>, APIError> {
     use crate::schema::articles::dsl::{articles, id as article_id};
     use crate::schema::writers::dsl::writers;
@@ -633,17 +1207,51 @@ pub fn get_article(
         })?;
 
     Ok(Json(ServerArticle::new(
+        db_connection,
         ret_article.0,
         ret_article.1,
-        user,
+        user.as_ref(),
     )?))
 }
 
+#[get("/articles/<id>/export?<format>")]
+pub fn export_article(
+    db_connection: &State<Mutex<PgConnection>>,
+    id: i32,
+    format: Option<ExportFormat>,
+) -> APIResult<ExportedArticle> {
+    use crate::schema::articles::dsl::{articles, id as article_id};
+
+    let Some(format) = format else {
+        return Err(APIError::new(
+            Status::BadRequest,
+            "Format must be one of md, txt, docx or html".into(),
+        ));
+    };
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+
+    let article = articles
+        .filter(article_id.eq(id))
+        .first::<DBArticle>(db_connection)
+        .map_err(|err| match err {
+            DieselError::NotFound => {
+                APIError::new(Status::NotFound, format!("No article with id {}.", id))
+            }
+            _ => APIError::from(err),
+        })?;
+
+    let content: ArticleContent =
+        serde_json::from_str(&article.body).map_err(|_| APIError::default())?;
+
+    ExportedArticle::new(&content, &article.slug, format).map_err(APIError::from)
+}
+
 #[get("/articles/<slug>", rank = 3)]
 pub fn get_article_by_slug(
     db_connection: &State<Mutex<PgConnection>>,
     slug: &str,
-    user: Option<AdminUser>,
+    user: Option<Permitted<perm::ArticleEdit>>,
 ) -> Result<Json<ServerArticle>, APIError> {
     use crate::schema::articles::dsl::{articles, slug as article_slug};
     use crate::schema::writers::dsl::writers;
@@ -662,22 +1270,92 @@ pub fn get_article_by_slug(
         })?;
 
     Ok(Json(ServerArticle::new(
+        db_connection,
         ret_article.0,
         ret_article.1,
-        user,
+        user.as_ref(),
     )?))
 }
 
-#[allow(clippy::let_unit_value)]
-#[post("/logout")]
-pub fn logout(jar: &CookieJar<'_>) {
+fn set_session_cookies(
+    jar: &CookieJar<'_>,
+    cookies: CookieConfig,
+    access_token: String,
+    refresh_token: String,
+) {
+    jar.add(
+        Cookie::build(COOKIE_SESSION_TOKEN, access_token)
+            .secure(cookies.secure)
+            .http_only(true)
+            .same_site(cookies.same_site)
+            .finish(),
+    );
+    // Only sent to the API, which is the only place it is used.
+    jar.add(
+        Cookie::build(COOKIE_REFRESH_TOKEN, refresh_token)
+            .path("/api")
+            .max_age(rocket::time::Duration::days(refresh::REFRESH_TOKEN_DAYS))
+            .secure(cookies.secure)
+            .http_only(true)
+            .same_site(cookies.same_site)
+            .finish(),
+    );
+}
+
+fn remove_session_cookies(jar: &CookieJar<'_>) {
     jar.remove(Cookie::named(COOKIE_SESSION_TOKEN));
+    jar.remove(
+        Cookie::build(COOKIE_REFRESH_TOKEN, "")
+            .path("/api")
+            .finish(),
+    );
+}
+
+/// Ends the current session, or with `everywhere` every session of the same user.
+#[post("/logout?<everywhere>")]
+pub fn logout(
+    db_connection: &State<Mutex<PgConnection>>,
+    jar: &CookieJar<'_>,
+    session: Option<CurrentSession>,
+    everywhere: Option<bool>,
+    actor: Actor,
+) -> APIResult<()> {
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    match (session, everywhere.unwrap_or(false)) {
+        (Some(CurrentSession(session)), true) => {
+            db_connection.transaction::<_, DieselError, _>(|| {
+                session::revoke_all(db_connection, &session.username)?;
+                let target = Target::User(&session.username);
+                actor.record(db_connection, "logout_everywhere", target, Change::none())
+            })?;
+        }
+        (Some(CurrentSession(session)), false) => {
+            db_connection.transaction::<_, DieselError, _>(|| {
+                session::revoke(db_connection, &session.id)?;
+                let target = Target::User(&session.username);
+                actor.record(db_connection, "logout", target, Change::none())
+            })?;
+        }
+        // The access token may have expired, but the refresh token still names the session.
+        (None, _) => {
+            if let Some(refresh_token) = jar.get(COOKIE_REFRESH_TOKEN) {
+                refresh::revoke(db_connection, refresh_token.value())?;
+            }
+        }
+    }
+    remove_session_cookies(jar);
+    Ok(())
 }
 
 #[post("/login", data = "<login_info>")]
 pub fn login(
+    db_connection: &State<Mutex<PgConnection>>,
+    config: &State<AppConfig>,
     jar: &CookieJar<'_>,
     login_info: Option<Json<LoginInfo<'_>>>,
+    throttle: &State<LoginThrottle>,
+    ip: ClientIp,
+    actor: Actor,
 ) -> Result<&'static str, APIError> {
     let login_info = match login_info {
         Some(login_info) => login_info,
@@ -689,61 +1367,694 @@ pub fn login(
         }
     };
 
-    let admin_username = std::env::var("ADMIN_USERNAME").expect("ADMIN_USERNAME must be defined");
-    let admin_password = std::env::var("ADMIN_PASSWORD").expect("ADMIN_PASSWORD must be defined");
+    let ClientIp(ip) = ip;
+    if let Some(wait) = throttle.check(ip, login_info.username, Instant::now()) {
+        return Err(APIError::too_many_requests(wait));
+    }
 
-    let editor_username =
-        std::env::var("EDITOR_USERNAME").expect("EDITOR_USERNAME must be defined");
-    let editor_password =
-        std::env::var("EDITOR_PASSWORD").expect("EDITOR_PASSWORD must be defined");
+    let admin = login_info.matches(&config.admin);
+    let editor = login_info.matches(&config.editor);
+    let writer = !admin && !editor && {
+        let account = {
+            let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+            account::find_by_username(db_connection, login_info.username)?
+        };
+        // Checking a password takes a while, so it's done without holding the connection.
+        block_in_place(|| account::authenticate(account, login_info.password)).is_some()
+    };
 
-    if login_info.username == admin_username && login_info.password == admin_password {
-        create_jwt(Role::Admin)
-            .map(|token| {
-                let cookie = Cookie::build(COOKIE_SESSION_TOKEN, token)
-                    .secure(true)
-                    .http_only(true)
-                    .same_site(SameSite::Strict);
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
 
-                jar.add(cookie.finish());
+    let fail = |message: &str| {
+        let failures = throttle.record_failure(ip, login_info.username, Instant::now());
+        println!(
+            "Failed login as {:?} from {}, {failures} in a row",
+            login_info.username,
+            ip.map_or_else(|| "an unknown address".into(), |ip| ip.to_string())
+        );
+        let target = Target::User(login_info.username);
+        if let Err(err) = actor.record(db_connection, "login_failed", target, Change::none()) {
+            return APIError::from(err);
+        }
 
-                "Admin"
-            })
-            .map_err(|_| APIError::default())
-    } else if login_info.username == editor_username && login_info.password == editor_password {
-        create_jwt(Role::Editor)
-            .map(|token| {
-                let cookie = Cookie::build(COOKIE_SESSION_TOKEN, token)
-                    .secure(true)
-                    .http_only(true)
-                    .same_site(SameSite::Strict);
-
-                jar.add(cookie.finish());
-
-                "Editor"
-            })
-            .map_err(|_| APIError::default())
+        APIError::new(Status::Unauthorized, message.into())
+    };
+
+    let role = if admin {
+        Role::Admin
+    } else if editor {
+        Role::Editor
+    } else if writer {
+        Role::Writer
     } else {
-        Err(APIError::new(
-            Status::Unauthorized,
-            "Invalid username or password.".into(),
-        ))
+        return Err(fail("Invalid username or password."));
+    };
+
+    let verified = two_factor::is_enabled(db_connection, login_info.username)?;
+    if verified {
+        let code = login_info.code.ok_or_else(APIError::two_factor_required)?;
+        if !two_factor::verify(db_connection, login_info.username, code, Utc::now())? {
+            return Err(fail("Invalid two-factor code."));
+        }
+    }
+    throttle.record_success(ip, login_info.username);
+
+    let (session, refresh_token) = db_connection.transaction::<_, DieselError, _>(|| {
+        let (session, refresh_token) =
+            refresh::issue(db_connection, login_info.username, role, verified)?;
+        let target = Target::User(&session.username);
+        actor
+            .logged_in(&session)
+            .record(db_connection, "login", target, Change::none())?;
+        Ok((session, refresh_token))
+    })?;
+    let access_token = create_jwt(&config.jwt_keys, &session).map_err(|_| APIError::default())?;
+    set_session_cookies(jar, config.cookies, access_token, refresh_token);
+
+    Ok(role.as_str())
+}
+
+/// Swaps the refresh token cookie for a new one along with a new access token, returning the
+/// role the session is for.
+#[post("/refresh")]
+pub fn refresh_session(
+    db_connection: &State<Mutex<PgConnection>>,
+    config: &State<AppConfig>,
+    jar: &CookieJar<'_>,
+) -> APIResult<&'static str> {
+    let refresh_token = jar
+        .get(COOKIE_REFRESH_TOKEN)
+        .ok_or_else(APIError::session_ended)?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    match refresh::rotate(db_connection, refresh_token.value())? {
+        Rotation::Rotated { token, session } => {
+            let access_token =
+                create_jwt(&config.jwt_keys, &session).map_err(|_| APIError::default())?;
+            set_session_cookies(jar, config.cookies, access_token, token);
+            Ok(session.role.as_str())
+        }
+        // Another request refreshed the session first and set the new cookies, so try again.
+        Rotation::AlreadyRotated => Err(APIError::session_expired()),
+        Rotation::Invalid => {
+            remove_session_cookies(jar);
+            Err(APIError::session_ended())
+        }
     }
 }
 
 #[get("/current")]
 pub fn current_role(user: User) -> &'static str {
-    match user.0 {
-        Role::Admin => "Admin",
-        Role::Editor => "Editor",
-        Role::Default => "Default",
+    user.0.as_str()
+}
+
+/// The token writes from the current session have to send back in the `X-CSRF-Token` header, see
+/// [`crate::csrf`].
+#[get("/csrf-token")]
+pub fn get_csrf_token(session: Option<CurrentSession>) -> APIResult<String> {
+    let CurrentSession(session) = session.ok_or_else(APIError::unauthorized)?;
+    Ok(session.csrf_token)
+}
+
+/// Lists the active sessions, or only those of `username`.
+#[get("/sessions?<username>")]
+pub fn get_sessions(
+    db_connection: &State<Mutex<PgConnection>>,
+    user: Option<Permitted<perm::SessionManage>>,
+    username: Option<&str>,
+) -> APIResult<Json<Vec<DBSession>>> {
+    user.ok_or_else(APIError::unauthorized)?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    Ok(Json(session::list_active(db_connection, username)?))
+}
+
+#[delete("/sessions/<id>")]
+pub fn delete_session(
+    db_connection: &State<Mutex<PgConnection>>,
+    user: Option<Permitted<perm::SessionManage>>,
+    id: &str,
+    actor: Actor,
+) -> APIResult<status::Accepted<()>> {
+    user.ok_or_else(APIError::unauthorized)?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    db_connection.transaction::<_, APIError, _>(|| {
+        if session::revoke(db_connection, id)? {
+            actor.record(db_connection, "delete", Target::Session(id), Change::none())?;
+            Ok(status::Accepted(Some(())))
+        } else {
+            Err(APIError::new(
+                Status::NotFound,
+                format!("No session with id {id}"),
+            ))
+        }
+    })
+}
+
+/// Logs `username` out everywhere, returning how many sessions were ended.
+#[delete("/sessions?<username>")]
+pub fn delete_user_sessions(
+    db_connection: &State<Mutex<PgConnection>>,
+    user: Option<Permitted<perm::SessionManage>>,
+    username: &str,
+    actor: Actor,
+) -> APIResult<Json<usize>> {
+    user.ok_or_else(APIError::unauthorized)?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    let revoked = db_connection.transaction::<_, DieselError, _>(|| {
+        let revoked = session::revoke_all(db_connection, username)?;
+        let target = Target::User(username);
+        actor.record(
+            db_connection,
+            "delete_sessions",
+            target,
+            Change::result(&revoked),
+        )?;
+        Ok(revoked)
+    })?;
+
+    Ok(Json(revoked))
+}
+
+/// The permissions of whoever is logged in, for the client to decide what to show.
+#[get("/current/permissions")]
+pub fn current_permissions(
+    db_connection: &State<Mutex<PgConnection>>,
+    session: CurrentSession,
+) -> APIResult<Json<Vec<GrantedPermission>>> {
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    Ok(Json(permission::permissions_of(db_connection, &session.0)?))
+}
+
+#[get("/permissions")]
+pub fn get_permission_grants(
+    db_connection: &State<Mutex<PgConnection>>,
+    user: Option<Permitted<perm::PermissionManage>>,
+) -> APIResult<Json<Vec<DBPermissionGrant>>> {
+    use crate::schema::permission_grants::dsl::{id, permission_grants};
+
+    user.ok_or_else(APIError::unauthorized)?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    Ok(Json(permission_grants.order(id).load(db_connection)?))
+}
+
+/// Grants a permission to every account with a role, or to one account.
+#[post("/permissions", data = "<grant>")]
+pub fn post_permission_grant(
+    db_connection: &State<Mutex<PgConnection>>,
+    grant: Option<Json<ClientPermissionGrant>>,
+    user: Option<Permitted<perm::PermissionManage>>,
+    actor: Actor,
+) -> APIResult<status::Created<Json<DBPermissionGrant>>> {
+    use crate::schema::permission_grants;
+
+    user.ok_or_else(APIError::unauthorized)?;
+
+    let Some(grant) = grant else {
+        return Err(APIError::new(
+            Status::BadRequest,
+            "Invalid permission grant format.".into(),
+        ));
+    };
+    if grant.role.is_some() == grant.username.is_some() {
+        return Err(APIError::new(
+            Status::BadRequest,
+            "A permission is granted to either a role or a username.".into(),
+        ));
+    }
+    if grant.section.is_some() && !grant.permission.is_sectioned() {
+        return Err(APIError::new(
+            Status::BadRequest,
+            format!(
+                "Permission {} can't be limited to a section.",
+                grant.permission.as_str()
+            ),
+        ));
+    }
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    let inserted = db_connection.transaction::<_, DieselError, _>(|| {
+        let inserted = diesel::insert_into(permission_grants::table)
+            .values(grant.into_inner())
+            .get_result::<DBPermissionGrant>(db_connection)?;
+        let target = Target::PermissionGrant(inserted.id);
+        actor.record(db_connection, "create", target, Change::created(&inserted))?;
+        Ok(inserted)
+    })?;
+
+    let location = format!("/api/permissions/{}", inserted.id);
+    Ok(status::Created::new(location).body(Json(inserted)))
+}
+
+#[delete("/permissions/<id>")]
+pub fn delete_permission_grant(
+    db_connection: &State<Mutex<PgConnection>>,
+    id: i32,
+    user: Option<Permitted<perm::PermissionManage>>,
+    actor: Actor,
+) -> APIResult<status::Accepted<()>> {
+    use crate::schema::permission_grants::dsl::permission_grants;
+
+    user.ok_or_else(APIError::unauthorized)?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    db_connection.transaction::<_, APIError, _>(|| {
+        let deleted = diesel::delete(permission_grants.find(id))
+            .get_result::<DBPermissionGrant>(db_connection)
+            .optional()?
+            .ok_or_else(|| {
+                APIError::new(
+                    Status::NotFound,
+                    format!("No permission grant with id {id}"),
+                )
+            })?;
+        let target = Target::PermissionGrant(id);
+        actor.record(db_connection, "delete", target, Change::deleted(&deleted))?;
+        Ok(())
+    })?;
+
+    Ok(status::Accepted(Some(())))
+}
+
+/// Whether whoever is logged in has two-factor authentication, and whether they have to.
+#[get("/two-factor")]
+pub fn get_two_factor(
+    db_connection: &State<Mutex<PgConnection>>,
+    session: Option<CurrentSession>,
+) -> APIResult<Json<two_factor::Status>> {
+    let CurrentSession(session) = session.ok_or_else(APIError::unauthorized)?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    Ok(Json(two_factor::status(db_connection, &session)?))
+}
+
+/// Starts setting up two-factor authentication with a new secret, which `/two-factor/confirm`
+/// turns on once the authenticator app has it.
+#[post("/two-factor/enroll")]
+pub fn post_two_factor_enroll(
+    db_connection: &State<Mutex<PgConnection>>,
+    session: Option<CurrentSession>,
+    actor: Actor,
+) -> APIResult<Json<Enrollment>> {
+    let CurrentSession(session) = session.ok_or_else(APIError::unauthorized)?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    db_connection.transaction::<_, APIError, _>(|| {
+        let enrollment =
+            two_factor::enroll(db_connection, &session.username)?.ok_or_else(|| {
+                APIError::new(
+                    Status::Conflict,
+                    "Two-factor authentication is already on.".into(),
+                )
+            })?;
+        // The enrollment holds the new secret, so only the fact it was made is recorded.
+        let target = Target::TwoFactor(&session.username);
+        actor.record(db_connection, "enroll", target, Change::none())?;
+        Ok(Json(enrollment))
+    })
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorCode<'a> {
+    pub code: &'a str,
+}
+
+impl<'a> TwoFactorCode<'a> {
+    fn from_body(body: Option<Json<TwoFactorCode<'a>>>) -> APIResult<&'a str> {
+        body.map(|body| body.code)
+            .ok_or_else(|| APIError::new(Status::BadRequest, "Missing two-factor code.".into()))
+    }
+}
+
+fn invalid_two_factor_code() -> APIError {
+    APIError::new(Status::BadRequest, "Invalid two-factor code.".into())
+}
+
+/// Checks and uses up a code from whoever is logged in, throttled like logging in so codes can't be
+/// guessed by trying them all. Call it in the transaction that goes on to use the code.
+fn verify_two_factor_code(
+    db_connection: &PgConnection,
+    throttle: &LoginThrottle,
+    ip: &ClientIp,
+    username: &str,
+    code: &str,
+) -> APIResult<()> {
+    let ip = ip.0;
+    if let Some(wait) = throttle.check(ip, username, Instant::now()) {
+        return Err(APIError::too_many_requests(wait));
+    }
+    if !two_factor::verify(db_connection, username, code, Utc::now())? {
+        throttle.record_failure(ip, username, Instant::now());
+        return Err(invalid_two_factor_code());
+    }
+    throttle.record_success(ip, username);
+    Ok(())
+}
+
+/// Turns on two-factor authentication with a code from the secret being enrolled, returning the
+/// recovery codes. The current session counts as verified from then on.
+#[post("/two-factor/confirm", data = "<code>")]
+pub fn post_two_factor_confirm(
+    db_connection: &State<Mutex<PgConnection>>,
+    session: Option<CurrentSession>,
+    code: Option<Json<TwoFactorCode<'_>>>,
+    actor: Actor,
+) -> APIResult<Json<Vec<String>>> {
+    let CurrentSession(session) = session.ok_or_else(APIError::unauthorized)?;
+    let code = TwoFactorCode::from_body(code)?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    db_connection.transaction::<_, APIError, _>(|| {
+        let recovery_codes =
+            two_factor::confirm(db_connection, &session.username, code, Utc::now())?
+                .ok_or_else(invalid_two_factor_code)?;
+        session::mark_two_factor(db_connection, &session.id)?;
+        let target = Target::TwoFactor(&session.username);
+        actor.record(db_connection, "enable", target, Change::none())?;
+        Ok(Json(recovery_codes))
+    })
+}
+
+/// Replaces the recovery codes of whoever is logged in, given a code.
+#[post("/two-factor/recovery-codes", data = "<code>")]
+pub fn post_two_factor_recovery_codes(
+    db_connection: &State<Mutex<PgConnection>>,
+    session: Option<CurrentSession>,
+    code: Option<Json<TwoFactorCode<'_>>>,
+    throttle: &State<LoginThrottle>,
+    ip: ClientIp,
+    actor: Actor,
+) -> APIResult<Json<Vec<String>>> {
+    let CurrentSession(session) = session.ok_or_else(APIError::unauthorized)?;
+    let code = TwoFactorCode::from_body(code)?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    db_connection.transaction::<_, APIError, _>(|| {
+        verify_two_factor_code(db_connection, throttle, &ip, &session.username, code)?;
+        let recovery_codes = two_factor::replace_recovery_codes(db_connection, &session.username)?;
+        let target = Target::TwoFactor(&session.username);
+        actor.record(
+            db_connection,
+            "replace_recovery_codes",
+            target,
+            Change::none(),
+        )?;
+        Ok(Json(recovery_codes))
+    })
+}
+
+/// Turns off two-factor authentication for whoever is logged in, given a code, unless the policy
+/// requires it for their role.
+#[delete("/two-factor", data = "<code>")]
+pub fn delete_two_factor(
+    db_connection: &State<Mutex<PgConnection>>,
+    session: Option<CurrentSession>,
+    code: Option<Json<TwoFactorCode<'_>>>,
+    throttle: &State<LoginThrottle>,
+    ip: ClientIp,
+    actor: Actor,
+) -> APIResult<status::Accepted<()>> {
+    let CurrentSession(session) = session.ok_or_else(APIError::unauthorized)?;
+    let code = TwoFactorCode::from_body(code)?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    if two_factor::required_for(db_connection, session.role)? {
+        return Err(APIError::new(
+            Status::Forbidden,
+            format!(
+                "Two-factor authentication is required for the {} role.",
+                session.role.as_str()
+            ),
+        ));
+    }
+    db_connection.transaction::<_, APIError, _>(|| {
+        verify_two_factor_code(db_connection, throttle, &ip, &session.username, code)?;
+        two_factor::disable(db_connection, &session.username)?;
+        let target = Target::TwoFactor(&session.username);
+        actor.record(db_connection, "disable", target, Change::none())?;
+        Ok(())
+    })?;
+
+    Ok(status::Accepted(Some(())))
+}
+
+/// Turns off two-factor authentication for someone who lost their authenticator and recovery
+/// codes, so they can log in with just their password and enroll again.
+#[delete("/two-factor/users/<username>")]
+pub fn delete_user_two_factor(
+    db_connection: &State<Mutex<PgConnection>>,
+    username: &str,
+    user: Option<Permitted<perm::PermissionManage>>,
+    actor: Actor,
+) -> APIResult<status::Accepted<()>> {
+    user.ok_or_else(APIError::unauthorized)?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    db_connection.transaction::<_, APIError, _>(|| {
+        if !two_factor::disable(db_connection, username)? {
+            return Err(APIError::new(
+                Status::NotFound,
+                format!("{username} doesn't have two-factor authentication."),
+            ));
+        }
+        let target = Target::TwoFactor(username);
+        actor.record(db_connection, "reset", target, Change::none())?;
+        Ok(status::Accepted(Some(())))
+    })
+}
+
+/// The roles whose sessions get no permissions unless they logged in with two-factor
+/// authentication.
+#[derive(Serialize, Deserialize)]
+pub struct TwoFactorPolicy {
+    pub required_roles: Vec<Role>,
+}
+
+#[get("/two-factor/policy")]
+pub fn get_two_factor_policy(
+    db_connection: &State<Mutex<PgConnection>>,
+    user: Option<Permitted<perm::PermissionManage>>,
+) -> APIResult<Json<TwoFactorPolicy>> {
+    user.ok_or_else(APIError::unauthorized)?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    Ok(Json(TwoFactorPolicy {
+        required_roles: two_factor::required_roles(db_connection)?,
+    }))
+}
+
+/// Sets which roles have to use two-factor authentication. Requiring it for your own role takes
+/// having logged in with it, so nobody takes away their own permissions by accident.
+#[put("/two-factor/policy", data = "<policy>")]
+pub fn put_two_factor_policy(
+    db_connection: &State<Mutex<PgConnection>>,
+    policy: Option<Json<TwoFactorPolicy>>,
+    user: Option<Permitted<perm::PermissionManage>>,
+    actor: Actor,
+) -> APIResult<Json<TwoFactorPolicy>> {
+    let user = user.ok_or_else(APIError::unauthorized)?;
+
+    let Some(policy) = policy else {
+        return Err(APIError::new(
+            Status::BadRequest,
+            "Invalid two-factor policy format.".into(),
+        ));
+    };
+    if let Principal::Session(session) = &user.principal {
+        if policy.required_roles.contains(&session.role) && !session.two_factor {
+            return Err(APIError::new(
+                Status::Conflict,
+                format!(
+                    "Log in with two-factor authentication before requiring it for the {} role.",
+                    session.role.as_str()
+                ),
+            ));
+        }
+    }
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    db_connection.transaction::<_, APIError, _>(|| {
+        let before = TwoFactorPolicy {
+            required_roles: two_factor::required_roles(db_connection)?,
+        };
+        two_factor::set_required_roles(db_connection, &policy.required_roles)?;
+        let after = TwoFactorPolicy {
+            required_roles: two_factor::required_roles(db_connection)?,
+        };
+        let target = Target::TwoFactorPolicy;
+        actor.record(
+            db_connection,
+            "update",
+            target,
+            Change::updated(&before, &after),
+        )?;
+        Ok(Json(after))
+    })
+}
+
+#[get("/api-keys")]
+pub fn get_api_keys(
+    db_connection: &State<Mutex<PgConnection>>,
+    user: Option<Permitted<perm::PermissionManage>>,
+) -> APIResult<Json<Vec<DBApiKey>>> {
+    user.ok_or_else(APIError::unauthorized)?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    Ok(Json(api_key::list(db_connection)?))
+}
+
+/// A new API key, along with the key itself, which is never shown again.
+#[derive(Serialize)]
+pub struct CreatedApiKey {
+    pub api_key: DBApiKey,
+    pub key: String,
+}
+
+/// Creates an API key with the given permissions, which the creator has to have in every section.
+/// Keys can't create other keys, so one that leaks can be revoked without wondering what else it
+/// made.
+#[post("/api-keys", data = "<new_key>")]
+pub fn post_api_key(
+    db_connection: &State<Mutex<PgConnection>>,
+    new_key: Option<Json<ClientApiKey>>,
+    user: Option<Permitted<perm::PermissionManage>>,
+    actor: Actor,
+) -> APIResult<status::Created<Json<CreatedApiKey>>> {
+    let user = user.ok_or_else(APIError::unauthorized)?;
+    let Principal::Session(session) = &user.principal else {
+        return Err(APIError::new(
+            Status::Forbidden,
+            "API keys can't create other API keys.".into(),
+        ));
+    };
+
+    let Some(new_key) = new_key else {
+        return Err(APIError::new(
+            Status::BadRequest,
+            "Invalid API key format.".into(),
+        ));
+    };
+    if new_key.name.trim().is_empty() {
+        return Err(APIError::new(
+            Status::BadRequest,
+            "An API key needs a name.".into(),
+        ));
+    }
+    if new_key.permissions.is_empty() {
+        return Err(APIError::new(
+            Status::BadRequest,
+            "An API key needs at least one permission.".into(),
+        ));
+    }
+    if new_key.expires_at <= Utc::now() {
+        return Err(APIError::new(
+            Status::BadRequest,
+            "An API key has to expire in the future.".into(),
+        ));
+    }
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    let granted = permission::permissions_of(db_connection, session)?;
+    api_key::check_permissions(&new_key, &granted)
+        .map_err(|message| APIError::new(Status::Forbidden, message))?;
+    let (created, key) = db_connection.transaction::<_, DieselError, _>(|| {
+        let (created, key) = api_key::create(db_connection, &new_key, &session.username)?;
+        let target = Target::ApiKey(created.id);
+        actor.record(db_connection, "create", target, Change::created(&created))?;
+        Ok((created, key))
+    })?;
+
+    let location = format!("/api/api-keys/{}", created.id);
+    Ok(status::Created::new(location).body(Json(CreatedApiKey {
+        api_key: created,
+        key,
+    })))
+}
+
+/// Revokes an API key, which stops working straight away.
+#[delete("/api-keys/<id>")]
+pub fn delete_api_key(
+    db_connection: &State<Mutex<PgConnection>>,
+    id: i32,
+    user: Option<Permitted<perm::PermissionManage>>,
+    actor: Actor,
+) -> APIResult<status::Accepted<()>> {
+    user.ok_or_else(APIError::unauthorized)?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    db_connection.transaction::<_, APIError, _>(|| {
+        if !api_key::revoke(db_connection, id)? {
+            return Err(APIError::new(
+                Status::NotFound,
+                format!("No active API key with id {id}"),
+            ));
+        }
+        actor.record(db_connection, "revoke", Target::ApiKey(id), Change::none())?;
+        Ok(status::Accepted(Some(())))
+    })
+}
+
+/// Browses the audit log, newest first. `since` and `until` are RFC 3339 timestamps.
+#[allow(clippy::too_many_arguments)]
+#[get("/audit?<actor>&<action>&<target_type>&<target_id>&<since>&<until>&<limit>&<page>")]
+pub fn get_audit_log(
+    db_connection: &State<Mutex<PgConnection>>,
+    actor: Option<&str>,
+    action: Option<&str>,
+    target_type: Option<&str>,
+    target_id: Option<&str>,
+    since: Option<&str>,
+    until: Option<&str>,
+    limit: Option<i64>,
+    page: Option<i64>,
+    user: Option<Permitted<perm::AuditRead>>,
+) -> APIResult<Paginated<Vec<DBAuditEntry>>> {
+    user.ok_or_else(APIError::unauthorized)?;
+
+    let limit = limit.unwrap_or(50).min(MAX_AUDIT_ENTRIES_PER_PAGE);
+    let page = page.unwrap_or(1);
+    if page <= 0 || limit <= 0 {
+        return Err(APIError::new(
+            Status::BadRequest,
+            "Page and limit must be positive".into(),
+        ));
     }
+
+    let parse_time = |time: Option<&str>| {
+        time.map(|time| {
+            chrono::DateTime::parse_from_rfc3339(time)
+                .map(|time| time.with_timezone(&Utc))
+                .map_err(|_| {
+                    APIError::new(
+                        Status::BadRequest,
+                        format!("\"{time}\" is not an RFC 3339 timestamp."),
+                    )
+                })
+        })
+        .transpose()
+    };
+    let filter = AuditFilter {
+        actor,
+        action,
+        target_type,
+        target_id,
+        since: parse_time(since)?,
+        until: parse_time(until)?,
+    };
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    let (entries, count) = audit::list(db_connection, &filter, limit, page)?;
+
+    Ok(Paginated::new(entries, limit, page, count))
 }
 
 #[get("/drive/drafts")]
 pub async fn get_drive_drafts(
     files_service: &State<FilesService>,
-    user: Option<AdminUser>,
+    user: Option<Permitted<perm::DriveRead>>,
 ) -> APIResult<Json<Vec<ServerDriveFile>>> {
     user.ok_or_else(APIError::unauthorized)?;
 
@@ -756,7 +2067,7 @@ pub async fn get_drive_drafts(
 #[get("/drive/finals")]
 pub async fn get_drive_finals(
     files_service: &State<FilesService>,
-    user: Option<AdminUser>,
+    user: Option<Permitted<perm::DriveRead>>,
 ) -> APIResult<Json<Vec<ServerDriveFile>>> {
     user.ok_or_else(APIError::unauthorized)?;
 
@@ -768,9 +2079,11 @@ pub async fn get_drive_finals(
 
 #[post("/drive/final/<file_id>")]
 pub async fn move_draft_to_final(
+    db_connection: &State<Mutex<PgConnection>>,
     files_service: &State<FilesService>,
     file_id: &str,
-    user: Option<AdminUser>,
+    user: Option<Permitted<perm::DriveMove>>,
+    actor: Actor,
 ) -> APIResult<Json<ServerDriveFile>> {
     user.ok_or_else(APIError::unauthorized)?;
 
@@ -784,17 +2097,26 @@ pub async fn move_draft_to_final(
         ));
     }
 
-    gdrive::move_file_to_final(files_service, file_id)
-        .await
-        .map_err(Into::into)
-        .map(Json)
+    let file = gdrive::move_file_to_final(files_service, file_id).await?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    actor.record(
+        db_connection,
+        "move_to_final",
+        Target::DriveFile(file_id),
+        Change::none(),
+    )?;
+
+    Ok(Json(file))
 }
 
 #[post("/drive/draft/<file_id>")]
 pub async fn move_final_to_draft(
+    db_connection: &State<Mutex<PgConnection>>,
     files_service: &State<FilesService>,
     file_id: &str,
-    user: Option<AdminUser>,
+    user: Option<Permitted<perm::DriveMove>>,
+    actor: Actor,
 ) -> APIResult<Json<ServerDriveFile>> {
     user.ok_or_else(APIError::unauthorized)?;
 
@@ -808,90 +2130,276 @@ pub async fn move_final_to_draft(
         ));
     }
 
-    gdrive::move_file_to_draft(files_service, file_id)
-        .await
-        .map_err(Into::into)
-        .map(Json)
+    let file = gdrive::move_file_to_draft(files_service, file_id).await?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    actor.record(
+        db_connection,
+        "move_to_draft",
+        Target::DriveFile(file_id),
+        Change::none(),
+    )?;
+
+    Ok(Json(file))
 }
 
 #[get("/drive/content/<file_id>")]
 pub async fn get_file_content(
+    db_connection: &State<Mutex<PgConnection>>,
     files_service: &State<FilesService>,
     file_id: &str,
-    user: Option<EditorUser>,
+    user: Option<Permitted<perm::ArticleDraft>>,
+    actor: Actor,
 ) -> APIResult<Json<ArticleContent>> {
-    user.ok_or_else(APIError::unauthorized)?;
+    let user = user.ok_or_else(APIError::unauthorized)?;
 
-    gdrive::get_article_content(files_service, file_id)
+    let export = gdrive::export_article(files_service, file_id)
         .await
-        .map(Json)
-        .map_err(|_| APIError::default())
+        .map_err(|_| APIError::default())?;
+
+    let store = ImageStore {
+        db_connection,
+        uploader: user.principal.role_name(),
+        actor: &actor,
+    };
+    let content = block_in_place(|| gdrive::article_from_export(&export, &store));
+    let mut content = content.map_err(|err| match err.downcast_ref::<ParseError>() {
+        Some(parse_error) => APIError::new(Status::UnprocessableEntity, parse_error.to_string()),
+        None => APIError::default(),
+    })?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    db_connection.transaction::<_, APIError, _>(|| {
+        library::record_article_images(db_connection, &mut content, user.principal.role_name())?;
+        actor.record(
+            db_connection,
+            "import",
+            Target::DriveFile(file_id),
+            Change::none(),
+        )?;
+        Ok(())
+    })?;
+
+    Ok(Json(content))
 }
 
+/// Lists the submissions in the sections the reviewer covers, or a writer's own submissions.
 #[get("/submission")]
 pub fn get_article_submissions(
     db_connection: &State<Mutex<PgConnection>>,
-    user: Option<AdminUser>,
+    user: Option<Permitted<perm::SubmissionReview>>,
+    writer: Option<WriterUser>,
 ) -> APIResult<Json<Vec<ServerArticleSubmission>>> {
     use crate::schema::article_submission;
 
-    user.ok_or_else(APIError::unauthorized)?;
-
     let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
 
-    article_submission::table
-        .load::<DBArticleSubmission>(db_connection)
-        .map(Json)
-        .map_err(APIError::from)
+    // Writers only ever see their own work, whatever else they were granted.
+    let submissions = match (user, writer) {
+        (_, Some(writer)) => article_submission::table
+            .filter(article_submission::author_id.eq(writer.writer_id))
+            .load::<DBArticleSubmission>(db_connection)?,
+        (Some(user), None) => {
+            let mut submissions =
+                article_submission::table.load::<DBArticleSubmission>(db_connection)?;
+            submissions.retain(|submission| user.allows(submission.section));
+            submissions
+        }
+        (None, None) => return Err(APIError::unauthorized()),
+    };
+
+    Ok(Json(submissions))
 }
 
+/// Submits an article. Submissions from writers are always credited to them.
 #[post("/submission", data = "<submission>")]
 pub fn post_article_submission(
     db_connection: &State<Mutex<PgConnection>>,
     submission: Option<Json<ClientArticleSubmission>>,
-    user: Option<EditorUser>,
+    user: Option<Permitted<perm::SubmissionCreate>>,
+    writer: Option<WriterUser>,
+    actor: Actor,
 ) -> APIResult<Json<ServerArticleSubmission>> {
     use crate::schema::article_submission;
 
-    user.ok_or_else(APIError::unauthorized)?;
-
     let Some(submission) = submission else {
         return Err(APIError::new(
         Status::BadRequest,
         "Invalid writer format.".into()))
     };
+    let mut submission = submission.into_inner();
+    match (&user, &writer) {
+        (_, Some(writer)) => submission.author_id = writer.writer_id,
+        (Some(user), None) => user.require(submission.section)?,
+        (None, None) => return Err(APIError::unauthorized()),
+    }
 
     let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
 
-    let inserted = diesel::insert_into(article_submission::table)
-        .values(submission.into_inner())
-        .get_results::<DBArticleSubmission>(db_connection)?
-        .swap_remove(0);
+    let inserted = db_connection.transaction::<_, DieselError, _>(|| {
+        let inserted = diesel::insert_into(article_submission::table)
+            .values(submission)
+            .get_results::<DBArticleSubmission>(db_connection)?
+            .swap_remove(0);
+        actor.record(
+            db_connection,
+            "create",
+            Target::Submission(inserted.id),
+            Change::created(&inserted),
+        )?;
+        Ok(inserted)
+    })?;
 
     Ok(Json(inserted))
 }
 
+#[derive(Deserialize)]
+pub struct SubmissionReview {
+    pub status: SubmissionStatus,
+}
+
+/// Accepts or rejects a submission, which its writer then sees.
+#[patch("/submission/<id>", data = "<review>")]
+pub fn patch_article_submission(
+    db_connection: &State<Mutex<PgConnection>>,
+    id: i32,
+    review: Option<Json<SubmissionReview>>,
+    user: Option<Permitted<perm::SubmissionReview>>,
+    actor: Actor,
+) -> APIResult<Json<ServerArticleSubmission>> {
+    use crate::schema::article_submission;
+
+    let user = user.ok_or_else(APIError::unauthorized)?;
+    let review =
+        review.ok_or_else(|| APIError::new(Status::BadRequest, "Invalid review format.".into()))?;
+
+    let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
+    let not_found = || {
+        APIError::new(
+            Status::NotFound,
+            format!("No submission with id {id} found."),
+        )
+    };
+
+    db_connection.transaction::<_, APIError, _>(|| {
+        let before = article_submission::table
+            .find(id)
+            .first::<DBArticleSubmission>(db_connection)
+            .optional()?
+            .ok_or_else(not_found)?;
+        user.require(before.section)?;
+
+        let after = diesel::update(article_submission::table.find(id))
+            .set(article_submission::status.eq(review.status))
+            .get_result::<DBArticleSubmission>(db_connection)
+            .map_err(|err| match err {
+                DieselError::NotFound => not_found(),
+                _ => APIError::from(err),
+            })?;
+        let change = Change::updated(&before, &after);
+        actor.record(db_connection, "update", Target::Submission(id), change)?;
+        Ok(Json(after))
+    })
+}
+
 #[delete("/submission/<id>")]
 pub fn delete_article_submission(
     db_connection: &State<Mutex<PgConnection>>,
     id: i32,
-    user: Option<AdminUser>,
+    user: Option<Permitted<perm::SubmissionReview>>,
+    actor: Actor,
 ) -> APIResult<()> {
     use crate::schema::article_submission;
-    user.ok_or_else(APIError::unauthorized)?;
+    let user = user.ok_or_else(APIError::unauthorized)?;
 
     let db_connection = &*db_connection.lock().map_err(|_| APIError::default())?;
 
-    diesel::delete(article_submission::table.filter(article_submission::id.eq(id)))
-        .execute(db_connection)?;
+    db_connection.transaction::<_, APIError, _>(|| {
+        let submission = article_submission::table
+            .find(id)
+            .first::<DBArticleSubmission>(db_connection)
+            .optional()?;
+        if let Some(submission) = &submission {
+            user.require(submission.section)?;
+        }
 
-    Ok(())
+        diesel::delete(article_submission::table.filter(article_submission::id.eq(id)))
+            .execute(db_connection)?;
+        if let Some(submission) = submission {
+            let change = Change::deleted(&submission);
+            actor.record(db_connection, "delete", Target::Submission(id), change)?;
+        }
+        Ok(())
+    })
+}
+
+/// Serves stored images from whichever storage backend is configured, resized and converted when
+/// `w`, `h`, `fit` or `format` are given.
+#[get("/<path..>?<w>&<h>&<fit>&<format>")]
+pub async fn get_image(
+    path: PathBuf,
+    w: Option<u32>,
+    h: Option<u32>,
+    fit: Option<Fit>,
+    format: Option<&str>,
+    headers: ImageRequestHeaders<'_>,
+) -> APIResult<ImageResponse> {
+    let transform = Transform::from_query(w, h, fit, format)
+        .map_err(|err| APIError::new(Status::BadRequest, err.to_string()))?;
+
+    let url = images::stored_url(&path.to_string_lossy());
+    let Some(key) = images::storage_key(&url).map(str::to_owned) else {
+        return Ok(ImageResponse::NotFound);
+    };
+    if transform.is_none() {
+        if let Some(not_modified) = ImageResponse::not_modified(&key, &headers) {
+            return Ok(not_modified);
+        }
+    }
+
+    let read_key = key.clone();
+    let bytes = rocket::tokio::task::spawn_blocking(move || match transform {
+        Some(transform) => transform.apply(&read_key),
+        None => Ok(images::storage::storage().get(&read_key)?),
+    })
+    .await
+    .map_err(|_| APIError::default())?;
+
+    match bytes {
+        Ok(bytes) => Ok(ImageResponse::new(&key, bytes, &headers)),
+        Err(err) => match err.downcast_ref::<std::io::Error>() {
+            Some(io_err) if io_err.kind() == std::io::ErrorKind::NotFound => {
+                Ok(ImageResponse::NotFound)
+            }
+            _ => match err.downcast_ref::<TransformError>() {
+                Some(transform_err) => {
+                    Err(APIError::new(Status::BadRequest, transform_err.to_string()))
+                }
+                None => {
+                    println!("Could not read image {url}: {err}");
+                    Err(APIError::default())
+                }
+            },
+        },
+    }
 }
 
-// TODO: This isn't really an API so this probably isn't the best 404 response
+/// Paths that can't be images, such as hidden files, get the same 404 image as missing ones.
 #[get("/<_..>", rank = 9999)]
-pub fn image_fallback() -> APIError {
-    APIError::new(Status::NotFound, "Unable to locate resource".into())
+pub fn image_fallback() -> ImageResponse {
+    ImageResponse::NotFound
+}
+
+/// Where [`Csrf`](crate::csrf::Csrf) sends writes without the session's CSRF token.
+#[get("/csrf-rejected")]
+pub fn csrf_rejected(_rejected: CsrfRejected) -> APIError {
+    APIError::csrf_rejected()
+}
+
+/// Where [`RateLimit`](crate::rate_limit::RateLimit) sends requests over the limit.
+#[get("/rate-limited")]
+pub fn rate_limited(limited: RateLimited) -> APIError {
+    APIError::too_many_requests(limited.0)
 }
 
 #[get("/<_..>", rank = 9999)]
@@ -10,6 +10,10 @@ pub struct APIError {
     timestamp: DateTime<Utc>,
     status: Status,
     message: String,
+    /// Tells clients apart errors they handle differently, like an expired session.
+    error_code: Option<&'static str>,
+    /// Sent as `Retry-After`, in seconds.
+    retry_after: Option<u64>,
 }
 impl APIError {
     pub fn new(status: Status, message: String) -> Self {
@@ -17,15 +21,75 @@ impl APIError {
             timestamp: Utc::now(),
             status,
             message,
+            error_code: None,
+            retry_after: None,
         }
     }
 
     pub fn unauthorized() -> Self {
         APIError::new(
             Status::Unauthorized,
-            "Must be an admin or higher to access this endpoint.".into(),
+            "Must be logged in with permission to access this endpoint.".into(),
         )
     }
+
+    /// The access token has expired, and a new one can be had from `/api/refresh`.
+    pub fn session_expired() -> Self {
+        APIError {
+            error_code: Some("session_expired"),
+            ..APIError::new(
+                Status::Unauthorized,
+                "Session expired, refresh it to continue.".into(),
+            )
+        }
+    }
+
+    /// The refresh token is missing, expired or revoked, so the user has to log in again.
+    pub fn session_ended() -> Self {
+        APIError {
+            error_code: Some("session_ended"),
+            ..APIError::new(Status::Unauthorized, "Session ended, log in again.".into())
+        }
+    }
+
+    /// The username and password were right, but the account needs a two-factor code too.
+    pub fn two_factor_required() -> Self {
+        APIError {
+            error_code: Some("two_factor_required"),
+            ..APIError::new(
+                Status::Unauthorized,
+                "Enter a code from your authenticator app, or a recovery code.".into(),
+            )
+        }
+    }
+
+    /// A write made with the session cookie didn't send the session's CSRF token back.
+    pub fn csrf_rejected() -> Self {
+        APIError {
+            error_code: Some("csrf_token_invalid"),
+            ..APIError::new(
+                Status::Forbidden,
+                "Missing or invalid CSRF token, get the current one from /api/csrf-token.".into(),
+            )
+        }
+    }
+
+    /// Too many requests, or failed logins, in too short a time.
+    pub fn too_many_requests(retry_after: std::time::Duration) -> Self {
+        // Round up, so retrying right on time isn't refused again.
+        let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        APIError {
+            error_code: Some("too_many_requests"),
+            retry_after: Some(seconds),
+            ..APIError::new(
+                Status::TooManyRequests,
+                format!(
+                    "Too many attempts, try again in {seconds} second{}.",
+                    if seconds == 1 { "" } else { "s" }
+                ),
+            )
+        }
+    }
 }
 impl Default for APIError {
     fn default() -> Self {
@@ -33,6 +97,8 @@ impl Default for APIError {
             timestamp: Utc::now(),
             status: Status::InternalServerError,
             message: "Something went wrong processing this request".into(),
+            error_code: None,
+            retry_after: None,
         }
     }
 }
@@ -52,11 +118,15 @@ impl Serialize for APIError {
         S: serde::Serializer,
     {
         use serde::ser::SerializeStruct;
-        let mut backend_error = serializer.serialize_struct("BackendError", 4)?;
+        let mut backend_error = serializer.serialize_struct("BackendError", 5)?;
         backend_error.serialize_field("timestamp", &self.timestamp)?;
         backend_error.serialize_field("code", &self.status.code)?;
         backend_error.serialize_field("error", &self.status.reason())?;
         backend_error.serialize_field("message", &self.message)?;
+        match self.error_code {
+            Some(error_code) => backend_error.serialize_field("error_code", error_code)?,
+            None => backend_error.skip_field("error_code")?,
+        }
         backend_error.end()
     }
 }
@@ -64,10 +134,23 @@ impl Error for APIError {}
 
 impl<'r> rocket::response::Responder<'r, 'static> for APIError {
     fn respond_to(self, request: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
-        let status = self.status;
-        rocket::Response::build_from(rocket::serde::json::Json(self).respond_to(request)?)
-            .status(status)
-            .ok()
+        // Let the client know it can refresh its session rather than being logged out.
+        let error = if self.status == Status::Unauthorized
+            && self.error_code.is_none()
+            && crate::auth::session_expired(request)
+        {
+            APIError::session_expired()
+        } else {
+            self
+        };
+        let status = error.status;
+        let retry_after = error.retry_after;
+        let mut response =
+            rocket::Response::build_from(rocket::serde::json::Json(error).respond_to(request)?);
+        if let Some(seconds) = retry_after {
+            response.raw_header("Retry-After", seconds.to_string());
+        }
+        response.status(status).ok()
     }
 }
 
@@ -0,0 +1,736 @@
+//! Conversion of stored [`ArticleContent`] into formats that can be handed off outside the site.
+
+use crate::article::{ArticleContent, ArticleParagraph, ArticleSpan, SpanContent};
+use crate::images;
+use anyhow::Result;
+use quick_xml::escape::escape;
+use rocket::http::{ContentType, Header};
+use rocket::response::Responder;
+use rocket::{FromFormField, Response};
+use std::collections::HashMap;
+use std::io::{Cursor, Write as _};
+use std::path::Path;
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, FromFormField)]
+pub enum ExportFormat {
+    #[field(value = "md")]
+    Markdown,
+    #[field(value = "txt")]
+    Text,
+    #[field(value = "docx")]
+    Docx,
+    #[field(value = "html")]
+    Html,
+}
+
+impl ExportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Markdown => "md",
+            ExportFormat::Text => "txt",
+            ExportFormat::Docx => "docx",
+            ExportFormat::Html => "html",
+        }
+    }
+
+    fn content_type(self) -> ContentType {
+        match self {
+            ExportFormat::Markdown => ContentType::Markdown,
+            ExportFormat::Text => ContentType::Plain,
+            ExportFormat::Docx => ContentType::new(
+                "application",
+                "vnd.openxmlformats-officedocument.wordprocessingml.document",
+            ),
+            ExportFormat::Html => ContentType::HTML,
+        }
+    }
+}
+
+/// An article converted into one of the [`ExportFormat`]s, sent as a download.
+pub struct ExportedArticle {
+    format: ExportFormat,
+    file_name: String,
+    bytes: Vec<u8>,
+}
+
+impl ExportedArticle {
+    pub fn new(content: &ArticleContent, slug: &str, format: ExportFormat) -> Result<Self> {
+        let bytes = match format {
+            ExportFormat::Markdown => to_markdown(content).into_bytes(),
+            ExportFormat::Text => to_text(content).into_bytes(),
+            ExportFormat::Html => to_html(content).into_bytes(),
+            ExportFormat::Docx => to_docx(content)?,
+        };
+
+        Ok(ExportedArticle {
+            format,
+            file_name: format!("{slug}.{}", format.extension()),
+            bytes,
+        })
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ExportedArticle {
+    fn respond_to(self, request: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
+        Response::build_from(self.bytes.respond_to(request)?)
+            .header(self.format.content_type())
+            .header(Header::new(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", self.file_name),
+            ))
+            .ok()
+    }
+}
+
+/// Moves leading and trailing whitespace outside of inline markup, since Markdown emphasis can't
+/// start or end with a space.
+fn split_whitespace_edges(text: &str) -> (&str, &str, &str) {
+    let trimmed_start = text.trim_start();
+    let trimmed = trimmed_start.trim_end();
+    let leading = &text[..text.len() - trimmed_start.len()];
+    let trailing = &trimmed_start[trimmed.len()..];
+    (leading, trimmed, trailing)
+}
+
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(
+            c,
+            '\\' | '*' | '_' | '[' | ']' | '`' | '<' | '>' | '~' | '#'
+        ) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Escapes a url for use as the destination of a Markdown link or image, so it can't end the link
+/// early or add markup of its own. Whitespace, which would also end it, is percent-encoded.
+fn escape_markdown_url(url: &str) -> String {
+    let mut escaped = String::with_capacity(url.len());
+    for c in url.chars() {
+        match c {
+            '\\' | '(' | ')' | '<' | '>' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if c.is_whitespace() || c.is_control() => {
+                let mut buffer = [0; 4];
+                for byte in c.encode_utf8(&mut buffer).bytes() {
+                    escaped.push_str(&format!("%{byte:02X}"));
+                }
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn is_bold(span: &ArticleSpan) -> bool {
+    matches!(span.font_weight.parse::<u32>(), Ok(weight) if weight >= 600)
+}
+
+fn markdown_span(span: &ArticleSpan, out: &mut String) {
+    let bold = is_bold(span);
+    let italic = span.font_style == "italic";
+    let underline = span.text_decoration.contains("underline");
+    let strikethrough = span.text_decoration.contains("line-through");
+
+    for content in &span.content {
+        let (text, href) = match content {
+            SpanContent::text { content } => (content, None),
+            SpanContent::anchor { href, content } => (content, Some(href)),
+            SpanContent::image { src, alt, .. } => {
+                out.push_str(&format!(
+                    "![{}]({})",
+                    escape_markdown(alt),
+                    escape_markdown_url(src)
+                ));
+                continue;
+            }
+        };
+
+        let (leading, text, trailing) = split_whitespace_edges(text);
+        out.push_str(leading);
+        if !text.is_empty() {
+            let mut formatted = escape_markdown(text);
+            if let Some(href) = href {
+                formatted = format!("[{formatted}]({})", escape_markdown_url(href));
+            }
+            if underline {
+                formatted = format!("<u>{formatted}</u>");
+            }
+            if strikethrough {
+                formatted = format!("~~{formatted}~~");
+            }
+            if italic {
+                formatted = format!("*{formatted}*");
+            }
+            if bold {
+                formatted = format!("**{formatted}**");
+            }
+            out.push_str(&formatted);
+        }
+        out.push_str(trailing);
+    }
+}
+
+pub fn to_markdown(content: &ArticleContent) -> String {
+    let mut out = format!("# {}\n", escape_markdown(&content.headline));
+
+    for paragraph in &content.paragraphs {
+        let mut line = String::new();
+        for span in &paragraph.spans {
+            markdown_span(span, &mut line);
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        out.push('\n');
+        // Hard line breaks inside a paragraph need a trailing backslash in Markdown.
+        out.push_str(&line.trim().replace('\n', "\\\n"));
+        out.push('\n');
+    }
+
+    out
+}
+
+pub fn to_text(content: &ArticleContent) -> String {
+    let mut out = format!("{}\n", content.headline);
+
+    for paragraph in &content.paragraphs {
+        let mut line = String::new();
+        for content in paragraph.spans.iter().flat_map(|span| &span.content) {
+            match content {
+                SpanContent::text { content } => line.push_str(content),
+                SpanContent::anchor { href, content } => {
+                    line.push_str(&format!("{content} ({href})"))
+                }
+                SpanContent::image { src, alt, .. } if alt.is_empty() => {
+                    line.push_str(&format!("[Image: {src}]"))
+                }
+                SpanContent::image { src, alt, .. } => {
+                    line.push_str(&format!("[Image: {alt} ({src})]"))
+                }
+            }
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        out.push('\n');
+        out.push_str(line.trim());
+        out.push('\n');
+    }
+
+    out
+}
+
+fn html_paragraph(paragraph: &ArticleParagraph, out: &mut String) {
+    out.push_str(&format!(
+        "<p style=\"margin-left:{};margin-right:{};text-align:{};text-indent:{}\">",
+        escape(&paragraph.margin_left),
+        escape(&paragraph.margin_right),
+        escape(&paragraph.text_alignment),
+        escape(&paragraph.text_indent),
+    ));
+
+    for span in &paragraph.spans {
+        out.push_str(&format!(
+            "<span style=\"font-style:{};text-decoration:{};color:{};font-weight:{}\">",
+            escape(&span.font_style),
+            escape(&span.text_decoration),
+            escape(&span.color),
+            escape(&span.font_weight),
+        ));
+
+        for content in &span.content {
+            match content {
+                SpanContent::text { content } => {
+                    out.push_str(&escape(content).replace('\n', "<br>"))
+                }
+                SpanContent::anchor { href, content } => out.push_str(&format!(
+                    "<a href=\"{}\">{}</a>",
+                    escape(href),
+                    escape(content)
+                )),
+                SpanContent::image {
+                    src,
+                    width,
+                    height,
+                    alt,
+                } => out.push_str(&format!(
+                    "<img src=\"{}\" alt=\"{}\" style=\"width:{};height:{}\">",
+                    escape(src),
+                    escape(alt),
+                    escape(width),
+                    escape(height),
+                )),
+            }
+        }
+
+        out.push_str("</span>");
+    }
+
+    out.push_str("</p>\n");
+}
+
+pub fn to_html(content: &ArticleContent) -> String {
+    let headline = escape(&content.headline);
+
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{headline}</title>\n</head>\n<body>\n<h1>{headline}</h1>\n"
+    );
+    for paragraph in &content.paragraphs {
+        html_paragraph(paragraph, &mut out);
+    }
+    out.push_str("</body>\n</html>\n");
+
+    out
+}
+
+/// Converts a CSS length such as `36pt` into twentieths of a point, the unit Word uses for
+/// indentation.
+fn css_length_to_twips(length: &str) -> Option<i64> {
+    let length = length.trim();
+    let split = length
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-'))
+        .unwrap_or(length.len());
+    let (value, unit) = length.split_at(split);
+    let value = value.parse::<f64>().ok()?;
+
+    let twips = match unit {
+        "pt" => value * 20.0,
+        "px" | "" => value * 15.0,
+        "in" => value * 1440.0,
+        "cm" => value * 567.0,
+        _ => return None,
+    };
+    Some(twips.round() as i64)
+}
+
+/// English Metric Units per CSS pixel, which is how drawing sizes are stored.
+const EMU_PER_PIXEL: f64 = 9525.0;
+
+fn css_length_to_emu(length: &str) -> Option<i64> {
+    css_length_to_twips(length).map(|twips| (twips as f64 / 15.0 * EMU_PER_PIXEL) as i64)
+}
+
+fn image_content_type(extension: &str) -> &'static str {
+    match extension {
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        _ => "image/png",
+    }
+}
+
+/// Collects the relationships and embedded files referenced from `word/document.xml`.
+#[derive(Default)]
+struct DocxPackage {
+    relationships: Vec<String>,
+    links: HashMap<String, String>,
+    media: Vec<(String, Vec<u8>)>,
+    drawing_count: usize,
+}
+
+impl DocxPackage {
+    fn next_relationship_id(&self) -> String {
+        format!("rId{}", self.relationships.len() + 1)
+    }
+
+    fn link(&mut self, href: &str) -> String {
+        if let Some(id) = self.links.get(href) {
+            return id.clone();
+        }
+
+        let id = self.next_relationship_id();
+        self.relationships.push(format!(
+            "<Relationship Id=\"{id}\" Type=\"http://schemas.openxmlformats.org/officeDocument/2006/relationships/hyperlink\" Target=\"{}\" TargetMode=\"External\"/>",
+            escape(href)
+        ));
+        self.links.insert(href.to_owned(), id.clone());
+        id
+    }
+
+    fn image(&mut self, extension: &str, bytes: Vec<u8>) -> String {
+        let id = self.next_relationship_id();
+        let name = format!("image{}.{extension}", self.media.len() + 1);
+        self.relationships.push(format!(
+            "<Relationship Id=\"{id}\" Type=\"http://schemas.openxmlformats.org/officeDocument/2006/relationships/image\" Target=\"media/{name}\"/>"
+        ));
+        self.media.push((name, bytes));
+        id
+    }
+}
+
+fn docx_run(text: &str, properties: &str) -> String {
+    let mut run = format!("<w:r><w:rPr>{properties}</w:rPr>");
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            run.push_str("<w:br/>");
+        }
+        run.push_str(&format!(
+            "<w:t xml:space=\"preserve\">{}</w:t>",
+            escape(line)
+        ));
+    }
+    run.push_str("</w:r>");
+    run
+}
+
+/// The colour Word gives hyperlinks.
+const DOCX_LINK_COLOR: &str = "0563C1";
+
+/// The run properties of a span, in the order Word's schema requires, each appearing at most once.
+/// Links are coloured and underlined like Word's own hyperlinks, whatever the span's colour.
+fn docx_run_properties(span: &ArticleSpan, link: bool) -> String {
+    let mut properties = String::new();
+    if is_bold(span) {
+        properties.push_str("<w:b/>");
+    }
+    if span.font_style == "italic" {
+        properties.push_str("<w:i/>");
+    }
+    if span.text_decoration.contains("line-through") {
+        properties.push_str("<w:strike/>");
+    }
+    let color = match span.color.strip_prefix('#') {
+        _ if link => Some(DOCX_LINK_COLOR),
+        Some(color) if color.len() == 6 && color.chars().all(|c| c.is_ascii_hexdigit()) => {
+            Some(color)
+        }
+        _ => None,
+    };
+    if let Some(color) = color {
+        properties.push_str(&format!("<w:color w:val=\"{color}\"/>"));
+    }
+    if link || span.text_decoration.contains("underline") {
+        properties.push_str("<w:u w:val=\"single\"/>");
+    }
+    properties
+}
+
+fn docx_image(
+    package: &mut DocxPackage,
+    src: &str,
+    width: &str,
+    height: &str,
+    alt: &str,
+) -> Option<String> {
+    let key = images::storage_key(src)?;
+    let bytes = images::storage::storage().get(key).ok()?;
+    let extension = Path::new(key)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_else(|| "png".into());
+
+    // Images without a stored size are given a 4:3 box six inches wide.
+    let cx = css_length_to_emu(width).unwrap_or(576 * EMU_PER_PIXEL as i64);
+    let cy = css_length_to_emu(height).unwrap_or(cx * 3 / 4);
+
+    let id = package.image(&extension, bytes);
+    package.drawing_count += 1;
+    let n = package.drawing_count;
+    let alt = escape(alt);
+
+    Some(format!(
+        "<w:r><w:drawing><wp:inline distT=\"0\" distB=\"0\" distL=\"0\" distR=\"0\">\
+<wp:extent cx=\"{cx}\" cy=\"{cy}\"/><wp:docPr id=\"{n}\" name=\"Picture {n}\" descr=\"{alt}\"/>\
+<a:graphic><a:graphicData uri=\"http://schemas.openxmlformats.org/drawingml/2006/picture\">\
+<pic:pic><pic:nvPicPr><pic:cNvPr id=\"{n}\" name=\"Picture {n}\" descr=\"{alt}\"/><pic:cNvPicPr/></pic:nvPicPr>\
+<pic:blipFill><a:blip r:embed=\"{id}\"/><a:stretch><a:fillRect/></a:stretch></pic:blipFill>\
+<pic:spPr><a:xfrm><a:off x=\"0\" y=\"0\"/><a:ext cx=\"{cx}\" cy=\"{cy}\"/></a:xfrm>\
+<a:prstGeom prst=\"rect\"><a:avLst/></a:prstGeom></pic:spPr></pic:pic>\
+</a:graphicData></a:graphic></wp:inline></w:drawing></w:r>"
+    ))
+}
+
+fn docx_paragraph(package: &mut DocxPackage, paragraph: &ArticleParagraph) -> String {
+    let alignment = match paragraph.text_alignment.as_str() {
+        "center" => "center",
+        "right" => "right",
+        "justify" => "both",
+        _ => "left",
+    };
+
+    let mut indent = String::new();
+    if let Some(left) = css_length_to_twips(&paragraph.margin_left) {
+        indent.push_str(&format!(" w:left=\"{left}\""));
+    }
+    if let Some(right) = css_length_to_twips(&paragraph.margin_right) {
+        indent.push_str(&format!(" w:right=\"{right}\""));
+    }
+    match css_length_to_twips(&paragraph.text_indent) {
+        Some(first_line) if first_line < 0 => {
+            indent.push_str(&format!(" w:hanging=\"{}\"", -first_line))
+        }
+        Some(first_line) => indent.push_str(&format!(" w:firstLine=\"{first_line}\"")),
+        None => {}
+    }
+
+    let mut xml = format!("<w:p><w:pPr><w:ind{indent}/><w:jc w:val=\"{alignment}\"/></w:pPr>");
+
+    for span in &paragraph.spans {
+        let properties = docx_run_properties(span, false);
+        let link_properties = docx_run_properties(span, true);
+        for content in &span.content {
+            match content {
+                SpanContent::text { content } => xml.push_str(&docx_run(content, &properties)),
+                SpanContent::anchor { href, content } => {
+                    let id = package.link(href);
+                    xml.push_str(&format!(
+                        "<w:hyperlink r:id=\"{id}\">{}</w:hyperlink>",
+                        docx_run(content, &link_properties)
+                    ));
+                }
+                SpanContent::image {
+                    src,
+                    width,
+                    height,
+                    alt,
+                } => match docx_image(package, src, width, height, alt) {
+                    Some(drawing) => xml.push_str(&drawing),
+                    // Images that aren't stored locally are linked instead of embedded.
+                    None => {
+                        let id = package.link(src);
+                        let text = if alt.is_empty() { src } else { alt };
+                        xml.push_str(&format!(
+                            "<w:hyperlink r:id=\"{id}\">{}</w:hyperlink>",
+                            docx_run(text, &link_properties)
+                        ));
+                    }
+                },
+            }
+        }
+    }
+
+    xml.push_str("</w:p>");
+    xml
+}
+
+pub fn to_docx(content: &ArticleContent) -> Result<Vec<u8>> {
+    let mut package = DocxPackage::default();
+
+    let mut body = format!(
+        "<w:p><w:pPr><w:jc w:val=\"center\"/></w:pPr>{}</w:p>",
+        docx_run(&content.headline, "<w:b/><w:sz w:val=\"32\"/>")
+    );
+    for paragraph in &content.paragraphs {
+        body.push_str(&docx_paragraph(&mut package, paragraph));
+    }
+
+    let document = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\
+<w:document xmlns:w=\"http://schemas.openxmlformats.org/wordprocessingml/2006/main\" \
+xmlns:r=\"http://schemas.openxmlformats.org/officeDocument/2006/relationships\" \
+xmlns:wp=\"http://schemas.openxmlformats.org/drawingml/2006/wordprocessingDrawing\" \
+xmlns:a=\"http://schemas.openxmlformats.org/drawingml/2006/main\" \
+xmlns:pic=\"http://schemas.openxmlformats.org/drawingml/2006/picture\">\
+<w:body>{body}</w:body></w:document>"
+    );
+
+    let relationships = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\
+<Relationships xmlns=\"http://schemas.openxmlformats.org/package/2006/relationships\">{}</Relationships>",
+        package.relationships.concat()
+    );
+
+    let mut extensions = package
+        .media
+        .iter()
+        .filter_map(|(name, _)| Path::new(name).extension()?.to_str())
+        .collect::<Vec<_>>();
+    extensions.sort_unstable();
+    extensions.dedup();
+    let image_defaults = extensions
+        .into_iter()
+        .map(|ext| {
+            format!(
+                "<Default Extension=\"{ext}\" ContentType=\"{}\"/>",
+                image_content_type(ext)
+            )
+        })
+        .collect::<String>();
+
+    let content_types = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\
+<Types xmlns=\"http://schemas.openxmlformats.org/package/2006/content-types\">\
+<Default Extension=\"rels\" ContentType=\"application/vnd.openxmlformats-package.relationships+xml\"/>\
+<Default Extension=\"xml\" ContentType=\"application/xml\"/>{image_defaults}\
+<Override PartName=\"/word/document.xml\" ContentType=\"application/vnd.openxmlformats-officedocument.wordprocessingml.document.main+xml\"/>\
+</Types>"
+    );
+
+    let package_relationships = "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\
+<Relationships xmlns=\"http://schemas.openxmlformats.org/package/2006/relationships\">\
+<Relationship Id=\"rId1\" Type=\"http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument\" Target=\"word/document.xml\"/>\
+</Relationships>";
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = FileOptions::default();
+
+    zip.start_file("[Content_Types].xml", options)?;
+    zip.write_all(content_types.as_bytes())?;
+    zip.start_file("_rels/.rels", options)?;
+    zip.write_all(package_relationships.as_bytes())?;
+    zip.start_file("word/document.xml", options)?;
+    zip.write_all(document.as_bytes())?;
+    zip.start_file("word/_rels/document.xml.rels", options)?;
+    zip.write_all(relationships.as_bytes())?;
+    for (name, bytes) in &package.media {
+        zip.start_file(format!("word/media/{name}"), options)?;
+        zip.write_all(bytes)?;
+    }
+
+    Ok(zip.finish()?.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quick_xml::events::Event;
+    use quick_xml::Reader;
+    use std::io::Read as _;
+    use zip::ZipArchive;
+
+    fn span(content: SpanContent, font_weight: &str, color: &str, decoration: &str) -> ArticleSpan {
+        ArticleSpan {
+            content: vec![content],
+            font_style: "normal".into(),
+            text_decoration: decoration.into(),
+            color: color.into(),
+            font_weight: font_weight.into(),
+        }
+    }
+
+    fn article() -> ArticleContent {
+        let text = |content: &str| SpanContent::text {
+            content: content.into(),
+        };
+        ArticleContent {
+            headline: "Cats & dogs".into(),
+            paragraphs: vec![ArticleParagraph {
+                margin_left: "36pt".into(),
+                margin_right: "0".into(),
+                text_alignment: "justify".into(),
+                text_indent: "0".into(),
+                spans: vec![
+                    span(text("Read "), "400", "#000000", "none"),
+                    span(text("this"), "700", "#000000", "none"),
+                    span(text(" and "), "400", "#000000", "none"),
+                    span(
+                        SpanContent::anchor {
+                            href: "https://example.com/a b) <c>?d=1&e=2".into(),
+                            content: "that".into(),
+                        },
+                        "400",
+                        "#FF0000",
+                        "underline",
+                    ),
+                    span(text("."), "400", "#000000", "none"),
+                ],
+            }],
+        }
+    }
+
+    #[test]
+    fn exports_markdown() {
+        assert_eq!(
+            to_markdown(&article()),
+            "# Cats & dogs\n\n\
+             Read **this** and <u>[that](https://example.com/a%20b\\)%20\\<c\\>?d=1&e=2)</u>.\n"
+        );
+    }
+
+    #[test]
+    fn escapes_markdown_image_sources() {
+        let mut out = String::new();
+        let image = SpanContent::image {
+            src: "x.png)![y](javascript:alert(1)".into(),
+            width: String::new(),
+            height: String::new(),
+            alt: "A [cat]".into(),
+        };
+        markdown_span(&span(image, "400", "#000000", "none"), &mut out);
+        assert_eq!(
+            out,
+            "![A \\[cat\\]](x.png\\)![y]\\(javascript:alert\\(1\\))"
+        );
+    }
+
+    #[test]
+    fn exports_text() {
+        assert_eq!(
+            to_text(&article()),
+            "Cats & dogs\n\nRead this and that (https://example.com/a b) <c>?d=1&e=2).\n"
+        );
+    }
+
+    #[test]
+    fn exports_html() {
+        let html = to_html(&article());
+        assert!(html.contains("<title>Cats &amp; dogs</title>"));
+        assert!(html.contains("<h1>Cats &amp; dogs</h1>"));
+        assert!(html.contains("text-align:justify"));
+        assert!(
+            html.contains("<a href=\"https://example.com/a b) &lt;c&gt;?d=1&amp;e=2\">that</a>")
+        );
+    }
+
+    #[test]
+    fn exports_valid_docx() {
+        let docx = to_docx(&article()).unwrap();
+        let mut zip = ZipArchive::new(Cursor::new(docx)).unwrap();
+        let mut read = |name: &str| {
+            let mut xml = String::new();
+            zip.by_name(name).unwrap().read_to_string(&mut xml).unwrap();
+            xml
+        };
+        assert!(read("[Content_Types].xml").contains("/word/document.xml"));
+        assert!(read("_rels/.rels").contains("word/document.xml"));
+        let relationships = read("word/_rels/document.xml.rels");
+        let target = "Target=\"https://example.com/a b) &lt;c&gt;?d=1&amp;e=2\"";
+        assert!(relationships.contains(target));
+        let document = read("word/document.xml");
+
+        // Word refuses files where a run property appears twice.
+        let mut reader = Reader::from_str(&document);
+        let mut run_properties: Option<Vec<Vec<u8>>> = None;
+        let mut runs = Vec::new();
+        loop {
+            match reader.read_event().unwrap() {
+                Event::Start(tag) if tag.local_name().as_ref() == b"rPr" => {
+                    run_properties = Some(Vec::new())
+                }
+                Event::Empty(tag) => {
+                    if let Some(properties) = &mut run_properties {
+                        properties.push(tag.local_name().as_ref().to_vec());
+                    }
+                }
+                Event::End(tag) if tag.local_name().as_ref() == b"rPr" => {
+                    runs.push(run_properties.take().unwrap())
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+        for properties in &runs {
+            let mut unique = properties.clone();
+            unique.sort();
+            unique.dedup();
+            assert_eq!(unique.len(), properties.len(), "{document}");
+        }
+
+        let link = String::from_utf8(runs[4].concat()).unwrap();
+        assert_eq!(link, "coloru");
+        assert!(document.contains("<w:color w:val=\"0563C1\"/>"));
+        assert!(document.contains("<w:jc w:val=\"both\"/>"));
+        assert!(document.contains("Cats &amp; dogs"));
+    }
+}
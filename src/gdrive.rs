@@ -1,19 +1,16 @@
 pub mod drive_v3_types;
+pub mod parser;
 
-use crate::article::{ArticleContent, ArticleParagraph, ArticleSpan, SpanContent};
-use anyhow::anyhow;
+use crate::article::ArticleContent;
+use crate::config::DriveConfig;
+use crate::images::library::ImageStore;
 use async_google_apis_common as common;
-use chrono::Datelike;
 use drive::FilesService;
 use drive_v3_types as drive;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs;
-use std::io::{Read as _, Write};
-use std::path::{Path, PathBuf};
-use tl::ParserOptions;
-use uuid::Uuid;
-use zip::result::ZipResult;
+use std::io::Read as _;
+use std::path::Path;
 
 pub const DRAFTS_FOLDER_ID: &str = "1BELyMOBd1Orod-Iwn0_Jf7ZHOEydsJb7";
 pub const FINALS_FOLDER_ID: &str = "1gDcjDPnt9SU8uM0kAS_H6Ubx0QubjVdw";
@@ -64,16 +61,17 @@ impl ServerDriveFile {
     }
 }
 
-pub async fn make_files_service(client_secret_path: impl AsRef<Path>) -> FilesService {
+pub async fn make_files_service(config: &DriveConfig) -> FilesService {
     let https_client = https_client();
 
-    let service_account_key = common::yup_oauth2::read_service_account_key(client_secret_path)
-        .await
-        .expect("Client secret could not be read");
+    let service_account_key =
+        common::yup_oauth2::read_service_account_key(&config.client_secret_path)
+            .await
+            .expect("Client secret could not be read");
 
     let auth = common::yup_oauth2::ServiceAccountAuthenticator::builder(service_account_key)
         .hyper_client(https_client.clone())
-        .persist_tokens_to_disk("tokencache.json")
+        .persist_tokens_to_disk(config.token_cache_path.clone())
         .build()
         .await
         .unwrap();
@@ -202,42 +200,14 @@ pub async fn move_file_to_draft(
     ServerDriveFile::new(move_file(files_service, file_id, DRAFTS_FOLDER_ID).await?)
 }
 
-fn get_style_attributes(tag: &tl::HTMLTag) -> HashMap<String, String> {
-    let mut map = HashMap::new();
-
-    let styles = tag.attributes().get("style");
-
-    let styles = if let Some(styles) = styles {
-        styles
-    } else {
-        return map;
-    };
-    let styles = if let Some(styles) = styles {
-        styles
-    } else {
-        return map;
-    };
-
-    for style in styles.as_bytes().split(|b| *b == b';') {
-        let mut split = style.split(|b| *b == b':');
-
-        match (split.next(), split.next()) {
-            (Some(key), Some(value)) => map.insert(
-                String::from_utf8_lossy(key).trim().into(),
-                String::from_utf8_lossy(value).trim().into(),
-            ),
-            (_, _) => continue,
-        };
-    }
-    map
-}
-
-type ImagePathParts = (i32, u32, String, String);
-fn unzip_and_store(zipped_bytes: &[u8]) -> ZipResult<(String, HashMap<String, ImagePathParts>)> {
+fn unzip_and_store(
+    zipped_bytes: &[u8],
+    store: &ImageStore,
+) -> anyhow::Result<(Vec<u8>, HashMap<String, String>)> {
     let reader = std::io::Cursor::new(zipped_bytes);
     let mut zip = zip::ZipArchive::new(reader)?;
 
-    let mut html_string = String::new();
+    let mut html = Vec::new();
     let mut file_map = HashMap::new();
 
     for i in 0..zip.len() {
@@ -247,112 +217,27 @@ fn unzip_and_store(zipped_bytes: &[u8]) -> ZipResult<(String, HashMap<String, Im
             let mut zip_file_bytes = Vec::with_capacity(zip_file.size() as usize);
             zip_file.read_to_end(&mut zip_file_bytes)?;
 
-            let image_dir = std::env::var("ARTICLE_IMAGE_PATH")
-                .expect("environment variable ARTICLE_IMAGE_PATH should be set");
-
-            let extension = std::path::Path::new(zip_file.name())
+            let extension = Path::new(zip_file.name())
                 .extension()
-                .unwrap_or_else(|| "png".as_ref());
-
-            // Using v3 uuid means that there will be no duplicate images stored
-            let file_name = Uuid::new_v3(&Uuid::NAMESPACE_URL, &zip_file_bytes).to_string();
-
-            let utc_now = chrono::Utc::now();
-            let (year, month) = (utc_now.year(), utc_now.month());
-
-            // image path: images/<year>/<month>/<name>.<extension>
-            let mut path = PathBuf::from(image_dir);
-            path.push(year.to_string());
-            path.push(month.to_string());
-            path.push(file_name.clone());
-            path.set_extension(extension);
-
-            // Create the image directory if it doesn't exist.
-            if let Some(parent) = path.parent() {
-                fs::create_dir_all(parent).unwrap();
-            }
-
-            let mut dest_file = fs::File::create(&path).unwrap();
-
-            dest_file.write_all(zip_file_bytes.as_slice()).unwrap();
-            file_map.insert(
-                zip_file.name().to_owned(),
-                (
-                    year,
-                    month,
-                    file_name,
-                    extension.to_string_lossy().into_owned(),
-                ),
-            );
+                .map(|ext| ext.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "png".into());
+
+            let url = store.store_url(&zip_file_bytes, &extension)?;
+            file_map.insert(zip_file.name().to_owned(), url);
         } else if file_name.ends_with(".html") {
-            html_string.reserve(zip_file.size() as usize);
-            zip_file.read_to_string(&mut html_string).unwrap();
+            html.reserve(zip_file.size() as usize);
+            zip_file.read_to_end(&mut html)?;
         }
     }
 
-    Ok((html_string, file_map))
-}
-
-fn make_a_span(tag: &mut tl::HTMLTag, dom: &tl::VDom) -> SpanContent {
-    let href = tag
-        .attributes_mut()
-        .remove("href")
-        .and_then(|it| it)
-        .unwrap_or_else(tl::Bytes::new)
-        .as_utf8_str()
-        .into_owned();
-
-    SpanContent::anchor {
-        href,
-        content: tag.inner_text(dom.parser()).into_owned(),
-    }
+    Ok((html, file_map))
 }
 
-fn make_image_span(
-    tag: &mut tl::HTMLTag,
-    image_map: &HashMap<String, ImagePathParts>,
-) -> SpanContent {
-    let src = tag
-        .attributes_mut()
-        .remove("src")
-        .and_then(|it| it)
-        .unwrap_or_else(tl::Bytes::new)
-        .as_utf8_str()
-        .into_owned();
-    let server_src = image_map.get(&src);
-
-    let src = if let Some((year, month, name, extension)) = server_src {
-        format!("/image/{year}/{month}/{name}.{extension}")
-    } else {
-        src
-    };
-
-    let alt = tag
-        .attributes_mut()
-        .remove("alt")
-        .and_then(|it| it)
-        .unwrap_or_else(tl::Bytes::new)
-        .as_utf8_str()
-        .into_owned();
-
-    let mut styles = get_style_attributes(tag);
-
-    let width = styles.remove("width").unwrap_or_default();
-    let height = styles.remove("height").unwrap_or_default();
-
-    SpanContent::image {
-        src,
-        alt,
-        width,
-        height,
-    }
-}
-
-pub async fn get_article_content(
+/// Downloads a Doc as a zip of its HTML and images, which [`article_from_export`] reads.
+pub async fn export_article(
     files_service: &FilesService,
     file_id: impl Into<String>,
-) -> Result<ArticleContent, common::Error> {
-    let format_error = || anyhow!("Provided file has invalid format");
+) -> Result<Vec<u8>, common::Error> {
     let file_id = file_id.into();
     let file_export_params = drive::FilesExportParams {
         file_id,
@@ -367,101 +252,15 @@ pub async fn get_article_content(
         return Err(common::Error::msg("Not good"));
     }
 
-    let (parsed_string, image_map) = unzip_and_store(&html_bytes)?;
-
-    let dom = tl::parse(&parsed_string, ParserOptions::default())?;
-
-    let mut paragraphs = dom.query_selector("p").ok_or_else(format_error)?;
-
-    let headline = paragraphs
-        .next()
-        .and_then(|p| p.get(dom.parser()))
-        .and_then(|p| p.children())
-        .and_then(|children| children.all(dom.parser()).get(0))
-        .ok_or_else(format_error)?
-        .inner_text(dom.parser())
-        .into_owned();
-
-    let mut article_paragraphs = Vec::new();
-
-    for paragraph in paragraphs {
-        let paragraph = paragraph
-            .get(dom.parser())
-            .and_then(|p| p.as_tag())
-            .ok_or_else(format_error)?;
-        let mut styles = get_style_attributes(paragraph);
-
-        let text_alignment = styles.remove("text-align").unwrap_or_else(|| "left".into());
-        let text_indent = styles.remove("text-indent").unwrap_or_else(|| "0".into());
-        let margin_left = styles.remove("margin-left").unwrap_or_else(|| "0".into());
-        let margin_right = styles.remove("margin-right").unwrap_or_else(|| "0".into());
-
-        let mut article_spans = Vec::new();
-        let spans = paragraph
-            .query_selector(dom.parser(), "span")
-            .ok_or_else(format_error)?;
-        for span in spans {
-            let span = span
-                .get(dom.parser())
-                .and_then(|s| s.as_tag())
-                .ok_or_else(format_error)?;
-            let mut styles = get_style_attributes(span);
-
-            let mut content = Vec::new();
-            for child in span.children().top().iter() {
-                let mut node = child.get(dom.parser()).ok_or_else(format_error)?.clone();
-                match &mut node {
-                    tl::Node::Tag(tag) if tag.name() == "a" => {
-                        let a = make_a_span(tag, &dom);
-                        content.push(a);
-                    }
-                    tl::Node::Tag(tag) if tag.name() == "img" => {
-                        let image = make_image_span(tag, &image_map);
-                        content.push(image);
-                    }
-                    tl::Node::Raw(text) => {
-                        content.push(SpanContent::text {
-                            content: text.as_utf8_str().into_owned(),
-                        });
-                    }
-                    _ => {}
-                }
-            }
-
-            let font_style = styles
-                .remove("font-style")
-                .unwrap_or_else(|| "normal".into());
-            let text_decoration = styles
-                .remove("text-decoration")
-                .unwrap_or_else(|| "none".into());
-            let color = styles.remove("color").unwrap_or_else(|| "#000000".into());
-            let font_weight = styles.remove("font-weight").unwrap_or_else(|| "400".into());
-
-            let article_span = ArticleSpan {
-                content,
-                font_style,
-                text_decoration,
-                color,
-                font_weight,
-            };
-
-            article_spans.push(article_span);
-        }
-
-        let article_paragraph = ArticleParagraph {
-            margin_left,
-            margin_right,
-            text_alignment,
-            text_indent,
-            spans: article_spans,
-        };
-        article_paragraphs.push(article_paragraph);
-    }
+    Ok(html_bytes)
+}
 
-    let article_content = ArticleContent {
-        headline,
-        paragraphs: article_paragraphs,
-    };
+/// Stores the images from an exported Doc and converts its HTML into article content.
+pub fn article_from_export(
+    zipped_bytes: &[u8],
+    store: &ImageStore,
+) -> Result<ArticleContent, common::Error> {
+    let (html, image_map) = unzip_and_store(zipped_bytes, store)?;
 
-    Ok(article_content)
+    Ok(parser::parse_html(&html, &image_map)?)
 }
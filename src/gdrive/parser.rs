@@ -0,0 +1,515 @@
+//! Conversion of the HTML that Google Docs exports into [`ArticleContent`].
+//!
+//! This is kept free of any Drive or filesystem access so that it can be tested against saved
+//! exports. Images are expected to have been stored already, with `images` mapping each `src` in
+//! the export (e.g. `images/image1.png`) to the url it is now served from.
+
+use crate::article::{ArticleContent, ArticleParagraph, ArticleSpan, SpanContent};
+use crate::import::finish_paragraph;
+use crate::links;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Display;
+use stylesheet::Stylesheet;
+use tl::{Node, NodeHandle, Parser, ParserOptions};
+
+mod stylesheet;
+
+/// Why an exported document couldn't be converted into an article.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// The export isn't valid UTF-8.
+    NotUtf8,
+    /// The export is too large to be parsed.
+    TooLarge,
+    /// The export has no paragraphs at all.
+    NoParagraphs,
+    /// None of the paragraphs have any text to use as the headline.
+    NoHeadline,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            ParseError::NotUtf8 => "Document is not valid UTF-8",
+            ParseError::TooLarge => "Document is too large",
+            ParseError::NoParagraphs => "Document has no paragraphs",
+            ParseError::NoHeadline => "Document has no text to use as a headline",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// CSS properties that carry over from a paragraph or span to the text inside it.
+const INHERITED_PROPERTIES: [&str; 4] = ["font-style", "font-weight", "color", "text-decoration"];
+
+type Styles = HashMap<String, String>;
+
+/// Converts an exported document into article content.
+///
+/// Every `<p>`, heading and list item becomes a paragraph, and the first one with any text becomes
+/// the headline.
+pub fn parse_html(
+    html: &[u8],
+    images: &HashMap<String, String>,
+) -> Result<ArticleContent, ParseError> {
+    let html = std::str::from_utf8(html).map_err(|_| ParseError::NotUtf8)?;
+    let dom = tl::parse(html, ParserOptions::default()).map_err(|_| ParseError::TooLarge)?;
+    let parser = dom.parser();
+
+    let mut stylesheet = Stylesheet::default();
+    if let Some(styles) = dom.query_selector("style") {
+        for style in styles.filter_map(|handle| handle.get(parser)) {
+            stylesheet.add(&style.inner_text(parser));
+        }
+    }
+
+    let mut blocks = Vec::new();
+    collect_blocks(dom.children(), parser, &mut blocks);
+
+    if blocks.is_empty() {
+        return Err(ParseError::NoParagraphs);
+    }
+
+    let mut headline = None;
+    let mut paragraphs = Vec::new();
+    for block in blocks {
+        let mut paragraph = Some(make_paragraph(block, parser, &stylesheet, images));
+        finish_paragraph(&mut paragraph, &mut headline, &mut paragraphs);
+    }
+
+    let headline = headline.ok_or(ParseError::NoHeadline)?;
+
+    Ok(ArticleContent {
+        headline,
+        paragraphs,
+    })
+}
+
+fn is_block(name: &[u8]) -> bool {
+    matches!(
+        name,
+        b"p" | b"h1" | b"h2" | b"h3" | b"h4" | b"h5" | b"h6" | b"li"
+    )
+}
+
+/// Finds the paragraph-level elements of the document, in document order.
+///
+/// Blocks nested inside other blocks, which is how the parser ends up representing a `<p>` that
+/// was never closed, are collected after their parent rather than merged into it.
+fn collect_blocks<'p, 'a>(
+    handles: &[NodeHandle],
+    parser: &'p Parser<'a>,
+    blocks: &mut Vec<&'p tl::HTMLTag<'a>>,
+) {
+    for handle in handles {
+        let Some(Node::Tag(tag)) = handle.get(parser) else {
+            continue;
+        };
+
+        let name = tag.name().as_bytes();
+        if matches!(name, b"head" | b"style" | b"script") {
+            continue;
+        }
+        if is_block(name) {
+            blocks.push(tag);
+        }
+        collect_blocks(tag.children().top().as_slice(), parser, blocks);
+    }
+}
+
+/// The styles of `tag` layered over those inherited from its parent.
+///
+/// Docs marks links with `color: inherit`, which keeps the parent's value rather than replacing it.
+fn inherit_styles(parent: &Styles, tag: &tl::HTMLTag, stylesheet: &Stylesheet) -> Styles {
+    let mut styles: Styles = parent
+        .iter()
+        .filter(|(key, _)| INHERITED_PROPERTIES.contains(&key.as_str()))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    styles.extend(
+        stylesheet
+            .styles_for(tag)
+            .into_iter()
+            .filter(|(_, value)| value != "inherit"),
+    );
+    styles
+}
+
+fn make_paragraph(
+    tag: &tl::HTMLTag,
+    parser: &Parser,
+    stylesheet: &Stylesheet,
+    images: &HashMap<String, String>,
+) -> ArticleParagraph {
+    let mut styles = stylesheet.styles_for(tag);
+
+    let mut spans = Vec::new();
+    collect_spans(
+        tag.children().top().as_slice(),
+        parser,
+        stylesheet,
+        &styles,
+        images,
+        &mut spans,
+    );
+
+    ArticleParagraph {
+        text_alignment: styles.remove("text-align").unwrap_or_else(|| "left".into()),
+        text_indent: styles.remove("text-indent").unwrap_or_else(|| "0".into()),
+        margin_left: styles.remove("margin-left").unwrap_or_else(|| "0".into()),
+        margin_right: styles.remove("margin-right").unwrap_or_else(|| "0".into()),
+        spans,
+    }
+}
+
+fn make_span(content: Vec<SpanContent>, styles: &Styles) -> ArticleSpan {
+    let style = |key: &str, default: &str| {
+        styles
+            .get(key)
+            .cloned()
+            .unwrap_or_else(|| default.to_owned())
+    };
+
+    ArticleSpan {
+        content,
+        font_style: style("font-style", "normal"),
+        text_decoration: style("text-decoration", "none"),
+        color: style("color", "#000000"),
+        font_weight: style("font-weight", "400"),
+    }
+}
+
+/// Turns the inline content of an element into spans.
+///
+/// Text, links and images directly inside an element share one span with that element's styles.
+/// Nested elements such as `<span>`s start a new span with their own styles layered on top.
+fn collect_spans(
+    handles: &[NodeHandle],
+    parser: &Parser,
+    stylesheet: &Stylesheet,
+    styles: &Styles,
+    images: &HashMap<String, String>,
+    spans: &mut Vec<ArticleSpan>,
+) {
+    let mut content = Vec::new();
+
+    for handle in handles {
+        match handle.get(parser) {
+            Some(Node::Raw(text)) => {
+                let text = text.as_utf8_str();
+                // Line breaks between tags are only formatting of the export itself.
+                if text.trim().is_empty() && text.contains('\n') {
+                    continue;
+                }
+                content.push(SpanContent::text {
+                    content: collapse_whitespace(&decode_entities(&text)),
+                });
+            }
+            Some(Node::Tag(tag)) if is_block(tag.name().as_bytes()) => {}
+            Some(Node::Tag(tag)) => match tag.name().as_bytes() {
+                b"a" => content.push(make_a_span(tag, parser)),
+                b"img" => content.push(make_image_span(tag, stylesheet, images)),
+                b"br" => content.push(SpanContent::text {
+                    content: "\n".into(),
+                }),
+                _ => {
+                    if !content.is_empty() {
+                        spans.push(make_span(std::mem::take(&mut content), styles));
+                    }
+                    let styles = inherit_styles(styles, tag, stylesheet);
+                    collect_spans(
+                        tag.children().top().as_slice(),
+                        parser,
+                        stylesheet,
+                        &styles,
+                        images,
+                        spans,
+                    );
+                }
+            },
+            Some(Node::Comment(_)) | None => {}
+        }
+    }
+
+    if !content.is_empty() {
+        spans.push(make_span(content, styles));
+    }
+}
+
+fn attribute(tag: &tl::HTMLTag, name: &str) -> String {
+    tag.attributes()
+        .get(name)
+        .flatten()
+        .map(|value| decode_entities(&value.as_utf8_str()).into_owned())
+        .unwrap_or_default()
+}
+
+fn make_a_span(tag: &tl::HTMLTag, parser: &Parser) -> SpanContent {
+    SpanContent::anchor {
+        href: links::clean_link(&attribute(tag, "href")),
+        content: collapse_whitespace(&decode_entities(&tag.inner_text(parser))),
+    }
+}
+
+fn make_image_span(
+    tag: &tl::HTMLTag,
+    stylesheet: &Stylesheet,
+    images: &HashMap<String, String>,
+) -> SpanContent {
+    let src = attribute(tag, "src");
+    let src = images.get(&src).cloned().unwrap_or(src);
+
+    let alt = attribute(tag, "alt");
+
+    let mut styles = stylesheet.styles_for(tag);
+
+    let width = styles.remove("width").unwrap_or_default();
+    let height = styles.remove("height").unwrap_or_default();
+
+    SpanContent::image {
+        src,
+        alt,
+        width,
+        height,
+    }
+}
+
+/// Collapses runs of spaces and line breaks into a single space, as a browser would. Non-breaking
+/// spaces are left alone since Docs uses them for deliberate spacing.
+fn collapse_whitespace(text: &str) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut in_whitespace = false;
+    for c in text.chars() {
+        if c.is_ascii_whitespace() {
+            if !in_whitespace {
+                collapsed.push(' ');
+            }
+            in_whitespace = true;
+        } else {
+            collapsed.push(c);
+            in_whitespace = false;
+        }
+    }
+    collapsed
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some('\u{a0}'),
+        "lsquo" => Some('\u{2018}'),
+        "rsquo" => Some('\u{2019}'),
+        "ldquo" => Some('\u{201c}'),
+        "rdquo" => Some('\u{201d}'),
+        "ndash" => Some('\u{2013}'),
+        "mdash" => Some('\u{2014}'),
+        "hellip" => Some('\u{2026}'),
+        _ => {
+            let number = entity.strip_prefix('#')?;
+            let code = match number.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => number.parse().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+/// The longest character reference [`decode_entity`] knows, without the `&` and `;`, so that the
+/// search for the `;` can stop early instead of scanning the rest of the text after every `&`.
+const MAX_ENTITY_LENGTH: usize = "#x10FFFF".len();
+
+/// Replaces HTML character references, which the parser leaves as-is.
+fn decode_entities(text: &str) -> Cow<'_, str> {
+    if !text.contains('&') {
+        return Cow::Borrowed(text);
+    }
+
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let entity = rest
+            .bytes()
+            .take(MAX_ENTITY_LENGTH + 2)
+            .position(|b| b == b';')
+            .and_then(|end| Some((decode_entity(&rest[1..end])?, end)));
+        match entity {
+            Some((c, end)) => {
+                decoded.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+
+    Cow::Owned(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn fixtures_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/gdrive")
+    }
+
+    /// Maps `images/imageN.<ext>` in an export to a fixed url, standing in for stored images.
+    fn fixture_images(html: &[u8]) -> HashMap<String, String> {
+        let html = String::from_utf8_lossy(html);
+        let mut images = HashMap::new();
+        for src in html.split("src=\"").skip(1) {
+            let src = &src[..src.find('"').unwrap_or(src.len())];
+            if let Some(name) = src.strip_prefix("images/") {
+                images.insert(src.to_owned(), format!("/image/fixtures/{name}"));
+            }
+        }
+        images
+    }
+
+    /// Every `<name>.html` fixture is parsed and compared with `<name>.json`, which holds either
+    /// the expected article or `{"error": "<ParseError variant>"}`.
+    ///
+    /// Run with `UPDATE_GOLDEN=1` to rewrite the golden files after an intended change.
+    #[test]
+    fn fixtures_match_golden_output() {
+        let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+
+        let mut fixtures = fs::read_dir(fixtures_dir())
+            .expect("fixtures directory should exist")
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "html"))
+            .collect::<Vec<_>>();
+        fixtures.sort();
+        assert!(!fixtures.is_empty());
+
+        let mut mismatches = Vec::new();
+        for fixture in fixtures {
+            let html = fs::read(&fixture).unwrap();
+            let actual = match parse_html(&html, &fixture_images(&html)) {
+                Ok(content) => serde_json::to_value(content).unwrap(),
+                Err(err) => serde_json::json!({ "error": format!("{err:?}") }),
+            };
+
+            let golden_path = fixture.with_extension("json");
+            if update {
+                let mut golden = serde_json::to_string_pretty(&actual).unwrap();
+                golden.push('\n');
+                fs::write(&golden_path, golden).unwrap();
+                continue;
+            }
+
+            let golden = fs::read_to_string(&golden_path)
+                .unwrap_or_else(|_| panic!("missing golden file {}", golden_path.display()));
+            let expected: serde_json::Value = serde_json::from_str(&golden).unwrap();
+            if actual != expected {
+                mismatches.push(fixture.display().to_string());
+            }
+        }
+
+        assert!(
+            mismatches.is_empty(),
+            "output differs from golden files for: {mismatches:?}"
+        );
+    }
+
+    #[test]
+    fn decodes_character_references() {
+        assert_eq!(decode_entities("a &amp; b"), "a & b");
+        assert_eq!(decode_entities("&#39;quoted&#x27;"), "'quoted'");
+        assert_eq!(decode_entities("AT&T &unknown; &"), "AT&T &unknown; &");
+        assert_eq!(
+            decode_entities("&#x10FFFF;&#0000000039;"),
+            "\u{10ffff}&#0000000039;"
+        );
+    }
+
+    #[test]
+    fn decodes_long_runs_of_ampersands_quickly() {
+        let text = format!("{};", "&".repeat(1_000_000));
+        let started = std::time::Instant::now();
+        assert_eq!(decode_entities(&text), text);
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    fn parse(html: &str) -> Result<serde_json::Value, ParseError> {
+        parse_html(html.as_bytes(), &HashMap::new())
+            .map(|content| serde_json::to_value(content).unwrap())
+    }
+
+    #[test]
+    fn rejects_exports_without_paragraphs() {
+        assert_eq!(parse("").unwrap_err(), ParseError::NoParagraphs);
+        assert_eq!(
+            parse("<html><body><div>Text outside of any paragraph</div></body></html>")
+                .unwrap_err(),
+            ParseError::NoParagraphs
+        );
+        assert_eq!(
+            parse_html(b"<p>caf\xe9</p>", &HashMap::new()).unwrap_err(),
+            ParseError::NotUtf8
+        );
+    }
+
+    #[test]
+    fn inherits_styles_through_nested_spans() {
+        let content = parse(concat!(
+            "<p>Headline</p>",
+            r#"<p style="color:#333333">loose <span style="font-style:italic">italic "#,
+            r#"<span style="font-weight:700">bold</span></span><br>after</p>"#,
+            r#"<p><span><span style="text-decoration:underline">deep</span></span></p>"#,
+        ))
+        .unwrap();
+
+        let spans = &content["paragraphs"][0]["spans"];
+        assert_eq!(spans[0]["content"][0]["text"]["content"], "loose ");
+        assert_eq!(spans[1]["font_style"], "italic");
+        assert_eq!(spans[1]["font_weight"], "400");
+        assert_eq!(spans[2]["font_style"], "italic");
+        assert_eq!(spans[2]["font_weight"], "700");
+        assert_eq!(spans[2]["color"], "#333333");
+        assert_eq!(spans[3]["content"][0]["text"]["content"], "\n");
+        assert_eq!(spans[3]["content"][1]["text"]["content"], "after");
+        let deep = &content["paragraphs"][1]["spans"][0];
+        assert_eq!(deep["text_decoration"], "underline");
+        assert_eq!(deep["content"][0]["text"]["content"], "deep");
+    }
+
+    #[test]
+    fn recovers_from_unclosed_tags() {
+        let content = parse(concat!(
+            r#"<p><span style="font-weight:700">Unclosed Headline<p><span>Second with "#,
+            r#"<a href="https://example.com">a link</span></p>"#,
+        ))
+        .unwrap();
+
+        assert_eq!(content["headline"], "Unclosed Headline");
+        let spans = &content["paragraphs"][0]["spans"];
+        assert_eq!(spans[0]["content"][0]["text"]["content"], "Second with ");
+        assert_eq!(
+            spans[0]["content"][1]["anchor"]["href"],
+            "https://example.com/"
+        );
+    }
+
+    #[test]
+    fn collapses_source_whitespace() {
+        assert_eq!(collapse_whitespace("Test\n            Body"), "Test Body");
+        assert_eq!(collapse_whitespace("a\u{a0}\u{a0}b"), "a\u{a0}\u{a0}b");
+    }
+}
@@ -0,0 +1,131 @@
+//! Just enough CSS to resolve the `<style>` block Google Docs puts at the top of every export.
+//!
+//! Docs only ever styles content with single class selectors (`.c1`) and the occasional type
+//! selector (`h2`, `p`), so those are the only rules that are kept. Anything more involved, such as
+//! the list marker rules (`.lst-kix_abc-0>li:before`) or at-rules, is skipped.
+
+use super::Styles;
+use std::cmp::Reverse;
+
+enum Selector {
+    Type(String),
+    Class(String),
+}
+
+struct Rule {
+    selector: Selector,
+    declarations: Styles,
+}
+
+#[derive(Default)]
+pub struct Stylesheet {
+    rules: Vec<Rule>,
+}
+
+impl Stylesheet {
+    /// Parses the contents of a `<style>` element, adding its rules after any already read.
+    pub fn add(&mut self, css: &str) {
+        let mut rest = css;
+
+        while let Some(open) = rest.find('{') {
+            let prelude = &rest[..open];
+            // `@import url(...);` has no block of its own, so it ends up in front of the next rule.
+            let prelude = prelude.rsplit(';').next().unwrap_or(prelude).trim();
+            rest = &rest[open + 1..];
+
+            let Some(close) = block_end(rest) else {
+                break;
+            };
+            let block = &rest[..close];
+            rest = &rest[close + 1..];
+
+            // At-rules such as `@media` wrap other rules, none of which apply to an export.
+            if prelude.starts_with('@') {
+                continue;
+            }
+
+            let declarations = parse_declarations(block);
+            for selector in prelude.split(',').filter_map(parse_selector) {
+                self.rules.push(Rule {
+                    selector,
+                    declarations: declarations.clone(),
+                });
+            }
+        }
+    }
+
+    /// The styles that apply to `tag` itself, with its inline `style` attribute taking precedence
+    /// over class rules, and class rules over type rules. Rules of the same kind apply in the order
+    /// they appear in the stylesheet, as in a browser.
+    pub fn styles_for(&self, tag: &tl::HTMLTag) -> Styles {
+        let name = tag.name().as_utf8_str();
+        let classes = tag
+            .attributes()
+            .class()
+            .map(|classes| classes.as_utf8_str().into_owned())
+            .unwrap_or_default();
+        let classes = classes.split_ascii_whitespace().collect::<Vec<_>>();
+
+        let mut matching = self
+            .rules
+            .iter()
+            .filter(|rule| match &rule.selector {
+                Selector::Type(type_name) => type_name.eq_ignore_ascii_case(&name),
+                Selector::Class(class) => classes.contains(&class.as_str()),
+            })
+            .collect::<Vec<_>>();
+        // A stable sort keeps stylesheet order within each kind of selector.
+        matching.sort_by_key(|rule| Reverse(matches!(rule.selector, Selector::Type(_))));
+
+        let mut styles = Styles::new();
+        for rule in matching {
+            styles.extend(rule.declarations.clone());
+        }
+        if let Some(Some(inline)) = tag.attributes().get("style") {
+            styles.extend(parse_declarations(&super::decode_entities(
+                &inline.as_utf8_str(),
+            )));
+        }
+        styles
+    }
+}
+
+/// Finds the `}` closing a block, skipping over any blocks nested inside it.
+fn block_end(css: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in css.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+fn parse_selector(selector: &str) -> Option<Selector> {
+    let selector = selector.trim();
+    let is_name = |name: &str| {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    };
+
+    match selector.strip_prefix('.') {
+        Some(class) if is_name(class) => Some(Selector::Class(class.to_owned())),
+        None if is_name(selector) => Some(Selector::Type(selector.to_ascii_lowercase())),
+        _ => None,
+    }
+}
+
+/// Parses a list of `property: value` declarations, as found in a rule or a `style` attribute.
+pub fn parse_declarations(declarations: &str) -> Styles {
+    declarations
+        .split(';')
+        .filter_map(|declaration| declaration.split_once(':'))
+        .map(|(key, value)| (key.trim().to_ascii_lowercase(), value.trim().to_owned()))
+        .filter(|(key, _)| !key.is_empty())
+        .collect()
+}
@@ -0,0 +1,569 @@
+pub mod gc;
+pub mod library;
+pub mod serve;
+pub mod storage;
+pub mod transform;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder};
+use img_parts::jpeg::{markers, Jpeg};
+use img_parts::png::Png;
+use img_parts::webp::{WebP, CHUNK_XMP};
+use img_parts::{Bytes, ImageEXIF};
+use rocket::http::ContentType;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt::Display;
+use std::io::{self, Cursor};
+use storage::storage;
+
+/// The largest width or height an uploaded image may have, in pixels.
+pub const MAX_IMAGE_DIMENSION: u32 = 8192;
+/// The largest number of pixels an uploaded image may have, so that an image within
+/// [`MAX_IMAGE_DIMENSION`] can't still take up gigabytes once decoded.
+pub const MAX_IMAGE_PIXELS: u64 = 40_000_000;
+
+/// Widths, in pixels, of the scaled down copies made of every stored image that is wider.
+pub const VARIANT_WIDTHS: [u32; 3] = [320, 640, 1280];
+/// Quality used for the scaled down copies, out of 100.
+const VARIANT_QUALITY: u8 = 80;
+/// Quality used when an original has to be re-encoded to rotate it, out of 100.
+const REENCODE_QUALITY: u8 = 92;
+/// The width and height of writer headshots, in pixels.
+pub const HEADSHOT_SIZE: u32 = 512;
+/// Quality of stored headshots, out of 100.
+const HEADSHOT_QUALITY: u8 = 90;
+/// Where headshots are stored, under a directory per writer.
+const HEADSHOT_PREFIX: &str = "writers";
+
+/// The image formats the site accepts and serves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    Gif,
+    WebP,
+    Avif,
+}
+
+impl ImageFormat {
+    /// Works out the format of an image from its leading bytes.
+    pub fn sniff(bytes: &[u8]) -> Option<Self> {
+        use imagesize::ImageType;
+
+        match imagesize::image_type(bytes).ok()? {
+            ImageType::Jpeg => Some(ImageFormat::Jpeg),
+            ImageType::Png => Some(ImageFormat::Png),
+            ImageType::Gif => Some(ImageFormat::Gif),
+            ImageType::Webp => Some(ImageFormat::WebP),
+            ImageType::Avif => Some(ImageFormat::Avif),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpeg",
+            ImageFormat::Png => "png",
+            ImageFormat::Gif => "gif",
+            ImageFormat::WebP => "webp",
+            ImageFormat::Avif => "avif",
+        }
+    }
+
+    pub fn content_type(self) -> ContentType {
+        match self {
+            ImageFormat::Jpeg => ContentType::JPEG,
+            ImageFormat::Png => ContentType::PNG,
+            ImageFormat::Gif => ContentType::GIF,
+            ImageFormat::WebP => ContentType::WEBP,
+            ImageFormat::Avif => ContentType::AVIF,
+        }
+    }
+
+    fn matches_content_type(self, content_type: &ContentType) -> bool {
+        let sub = content_type.sub().as_str().to_ascii_lowercase();
+        match self {
+            // `image/jpg` and `image/pjpeg` are still sent by some older clients.
+            ImageFormat::Jpeg => matches!(sub.as_str(), "jpeg" | "jpg" | "pjpeg"),
+            _ => sub == self.content_type().sub().as_str(),
+        }
+    }
+
+    /// The matching format of the `image` crate, for the formats it is built to decode.
+    fn decoder_format(self) -> Option<image::ImageFormat> {
+        match self {
+            ImageFormat::Jpeg => Some(image::ImageFormat::Jpeg),
+            ImageFormat::Png => Some(image::ImageFormat::Png),
+            ImageFormat::Gif => Some(image::ImageFormat::Gif),
+            ImageFormat::WebP => Some(image::ImageFormat::WebP),
+            ImageFormat::Avif => None,
+        }
+    }
+}
+
+/// Why an uploaded image was rejected.
+#[derive(Debug)]
+pub enum ImageError {
+    /// The file isn't in one of the [`ImageFormat`]s.
+    Unsupported,
+    /// The upload claimed to be one format but its contents are another.
+    Mismatch {
+        declared: String,
+        actual: ImageFormat,
+    },
+    /// The file starts like an image but can't be read.
+    Corrupt,
+    TooLarge {
+        width: u32,
+        height: u32,
+    },
+}
+
+impl Display for ImageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageError::Unsupported => {
+                f.write_str("Image must be a JPEG, PNG, WebP, GIF or AVIF file")
+            }
+            ImageError::Mismatch { declared, actual } => write!(
+                f,
+                "Image was uploaded as {declared} but is actually {}",
+                actual.content_type()
+            ),
+            ImageError::Corrupt => f.write_str("Image file is corrupt"),
+            ImageError::TooLarge { width, height } => write!(
+                f,
+                "Image is {width}x{height} pixels, images can be at most \
+                 {MAX_IMAGE_DIMENSION}x{MAX_IMAGE_DIMENSION} and {MAX_IMAGE_PIXELS} pixels"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+/// Checks that `bytes` really is an image in a supported format, that it matches the content type
+/// it was uploaded with, if any, and that it isn't too large.
+///
+/// The dimensions are read from the header before anything is decoded. Formats the server can
+/// decode are then decoded in full to catch truncated or corrupt files; AVIF only has its header
+/// checked.
+pub fn validate_image(
+    bytes: &[u8],
+    declared: Option<&ContentType>,
+) -> Result<ImageFormat, ImageError> {
+    let format = ImageFormat::sniff(bytes).ok_or(ImageError::Unsupported)?;
+
+    // Clients that don't know the type send `application/octet-stream`, which says nothing.
+    if let Some(declared) = declared.filter(|declared| declared.top() == "image") {
+        if !format.matches_content_type(declared) {
+            return Err(ImageError::Mismatch {
+                declared: declared.to_string(),
+                actual: format,
+            });
+        }
+    }
+
+    let size = imagesize::blob_size(bytes).map_err(|_| ImageError::Corrupt)?;
+    let (width, height) = (size.width as u32, size.height as u32);
+    if width == 0 || height == 0 {
+        return Err(ImageError::Corrupt);
+    }
+    if width > MAX_IMAGE_DIMENSION
+        || height > MAX_IMAGE_DIMENSION
+        || u64::from(width) * u64::from(height) > MAX_IMAGE_PIXELS
+    {
+        return Err(ImageError::TooLarge { width, height });
+    }
+
+    if let Some(decoder_format) = format.decoder_format() {
+        let mut limits = image::Limits::default();
+        limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+        limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+
+        let mut reader = image::ImageReader::with_format(Cursor::new(bytes), decoder_format);
+        reader.limits(limits);
+        reader.decode().map_err(|_| ImageError::Corrupt)?;
+    }
+
+    Ok(format)
+}
+
+/// The prefix of the urls stored images are recorded and served under.
+const URL_PREFIX: &str = "/image/";
+
+/// The storage key of the image recorded as `url`, e.g. `2022/9/<name>.jpeg` for
+/// `/image/2022/9/<name>.jpeg`. Urls pointing at the public base url are accepted too.
+///
+/// Keys that could escape the storage root, or refer to hidden files, are rejected.
+pub fn storage_key(url: &str) -> Option<&str> {
+    let public_base_url = storage().public_base_url().trim_end_matches('/');
+    let key = url.strip_prefix(URL_PREFIX).or_else(|| {
+        url.strip_prefix(public_base_url)
+            .filter(|_| !public_base_url.is_empty())?
+            .strip_prefix('/')
+    })?;
+
+    let is_safe = key
+        .split('/')
+        .all(|part| !part.is_empty() && !part.starts_with('.') && !part.contains('\\'));
+    is_safe.then_some(key)
+}
+
+/// The url an image is recorded as, given its storage key.
+pub fn stored_url(key: &str) -> String {
+    format!("{URL_PREFIX}{key}")
+}
+
+/// Turns a recorded `/image/...` url into the one clients should load it from, which differs when
+/// `IMAGE_PUBLIC_BASE_URL` is set. Anything else is returned as-is.
+pub fn public_url(url: &str) -> String {
+    match storage_key(url) {
+        Some(key) => storage().public_url(key),
+        None => url.to_owned(),
+    }
+}
+
+/// The inverse of [`public_url`], for urls sent back by clients, so that images are always
+/// recorded as `/image/...` whichever backend and base url are in use.
+pub fn normalize_url(url: &str) -> String {
+    match storage_key(url) {
+        Some(key) => stored_url(key),
+        None => url.to_owned(),
+    }
+}
+
+/// An image with its metadata stripped, ready to be stored.
+pub struct PreparedImage {
+    pub bytes: Vec<u8>,
+    /// Hex encoded SHA-256 of `bytes`, which names the stored file.
+    pub hash: String,
+}
+
+/// Strips the metadata from an image, see [`strip_metadata`], and hashes what is left.
+///
+/// Hashing after stripping means photos that only differ in their metadata, such as the same
+/// photo exported twice, are stored once.
+pub fn prepare_image(bytes: &[u8]) -> io::Result<PreparedImage> {
+    let bytes = strip_metadata(bytes)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+        .bytes;
+    let hash = format!("{:x}", Sha256::digest(&bytes));
+    Ok(PreparedImage { bytes, hash })
+}
+
+/// A newly stored image, along with the resized copies made of it.
+pub(crate) struct StoredImage {
+    pub url: String,
+    pub variants: Vec<ImageVariant>,
+}
+
+/// Stores a prepared image, along with resized copies of it, see [`store_variants`].
+///
+/// The image is stored at `<first two hex digits of the hash>/<hash>.<extension>`, so identical
+/// images always end up in the same place. Use [`library::ImageStore`], which also reuses images
+/// stored under older names and records the variants, rather than calling this directly.
+pub(crate) fn store_prepared(image: &PreparedImage, extension: &str) -> io::Result<StoredImage> {
+    let key = format!("{}/{}.{extension}", &image.hash[..2], image.hash);
+
+    storage().put(&key, &image.bytes, &content_type_of(&image.bytes))?;
+
+    // The original is enough to show the image, so failing to make smaller copies isn't fatal.
+    let variants = store_variants(&key, &image.bytes).unwrap_or_else(|err| {
+        println!("Could not create variants of {key}: {err}");
+        Vec::new()
+    });
+
+    Ok(StoredImage {
+        url: stored_url(&key),
+        variants,
+    })
+}
+
+/// Crops a writer's photo to a square around its centre, scales it down to [`HEADSHOT_SIZE`] and
+/// stores it as a JPEG under `writers/<writer_id>/`, returning the url it is recorded as.
+///
+/// The photo is re-encoded, so none of its metadata is kept. AVIF photos can't be decoded here and
+/// are rejected.
+pub fn store_headshot(bytes: &[u8], writer_id: i32) -> anyhow::Result<String> {
+    let decoder_format = ImageFormat::sniff(bytes)
+        .and_then(ImageFormat::decoder_format)
+        .ok_or_else(|| anyhow::anyhow!("Headshots must be JPEG, PNG, WebP or GIF images"))?;
+
+    let mut decoder =
+        image::ImageReader::with_format(Cursor::new(bytes), decoder_format).into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    let side = image.width().min(image.height());
+    let mut headshot = image.crop_imm(
+        (image.width() - side) / 2,
+        (image.height() - side) / 2,
+        side,
+        side,
+    );
+    if side > HEADSHOT_SIZE {
+        headshot = headshot.resize_exact(HEADSHOT_SIZE, HEADSHOT_SIZE, FilterType::Lanczos3);
+    }
+
+    let bytes = encode(&headshot, ImageFormat::Jpeg, HEADSHOT_QUALITY)?;
+    let file_name = format!("{:x}", Sha256::digest(&bytes));
+    let key = format!("{HEADSHOT_PREFIX}/{writer_id}/{file_name}.jpeg");
+    storage().put(&key, &bytes, &ImageFormat::Jpeg.content_type().to_string())?;
+
+    Ok(stored_url(&key))
+}
+
+/// Deletes a headshot stored by [`store_headshot`]. Anything else, such as a photo set by hand that
+/// may also be used in an article, is left alone.
+pub fn delete_headshot(url: &str) -> io::Result<()> {
+    match storage_key(url) {
+        Some(key) if key.starts_with(&format!("{HEADSHOT_PREFIX}/")) => storage().delete(key),
+        _ => Ok(()),
+    }
+}
+
+fn content_type_of(bytes: &[u8]) -> String {
+    ImageFormat::sniff(bytes)
+        .map(|format| format.content_type().to_string())
+        .unwrap_or_else(|| "application/octet-stream".into())
+}
+
+/// A scaled down or re-encoded copy of a stored image, for use in a `srcset`.
+///
+/// Variants are recorded along with their image in the library, with `url` in the `/image/...`
+/// form, and handed to clients with the url they should load them from, see [`public_url`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageVariant {
+    pub url: String,
+    pub width: u32,
+    pub content_type: String,
+}
+
+/// Where the variant of the image at `original` with the given width and format is stored.
+///
+/// `2022/9/<name>.jpeg` has its 640 pixel wide copies at `2022/9/<name>-640w.jpeg` and
+/// `2022/9/<name>-640w.webp`, and a full size WebP copy at `2022/9/<name>.webp`.
+fn variant_key(original: &str, width: Option<u32>, format: ImageFormat) -> Option<String> {
+    let (stem, _) = original.rsplit_once('.')?;
+    if stem.is_empty() || stem.ends_with('/') {
+        return None;
+    }
+    Some(match width {
+        Some(width) => format!("{stem}-{width}w.{}", format.extension()),
+        None => format!("{stem}.{}", format.extension()),
+    })
+}
+
+/// Makes the resized and WebP copies of a newly stored image and returns them, narrowest first.
+///
+/// Animated GIFs would lose their animation and AVIF can't be decoded here, so only JPEG, PNG and
+/// WebP images get variants.
+fn store_variants(key: &str, bytes: &[u8]) -> anyhow::Result<Vec<ImageVariant>> {
+    let format = match ImageFormat::sniff(bytes) {
+        Some(format @ (ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP)) => format,
+        _ => return Ok(Vec::new()),
+    };
+    let image = image::load_from_memory(bytes)?;
+    let mut variants = Vec::new();
+
+    for width in VARIANT_WIDTHS {
+        if width >= image.width() {
+            break;
+        }
+        let resized = image.resize(width, u32::MAX, FilterType::Lanczos3);
+
+        let mut formats = vec![format];
+        if format != ImageFormat::WebP {
+            formats.push(ImageFormat::WebP);
+        }
+        for variant_format in formats {
+            if let Some(variant_key) = variant_key(key, Some(width), variant_format) {
+                let content_type = variant_format.content_type().to_string();
+                storage().put(
+                    &variant_key,
+                    &encode(&resized, variant_format, VARIANT_QUALITY)?,
+                    &content_type,
+                )?;
+                variants.push(ImageVariant {
+                    url: stored_url(&variant_key),
+                    width,
+                    content_type,
+                });
+            }
+        }
+    }
+
+    if format != ImageFormat::WebP {
+        if let Some(webp_key) = variant_key(key, None, ImageFormat::WebP) {
+            let content_type = ImageFormat::WebP.content_type().to_string();
+            storage().put(
+                &webp_key,
+                &encode(&image, ImageFormat::WebP, VARIANT_QUALITY)?,
+                &content_type,
+            )?;
+            variants.push(ImageVariant {
+                url: stored_url(&webp_key),
+                width: image.width(),
+                content_type,
+            });
+        }
+    }
+
+    Ok(variants)
+}
+
+fn encode(image: &DynamicImage, format: ImageFormat, quality: u8) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    match format {
+        ImageFormat::Jpeg => {
+            let encoder = JpegEncoder::new_with_quality(&mut bytes, quality);
+            image.to_rgb8().write_with_encoder(encoder)?;
+        }
+        ImageFormat::WebP => {
+            let rgba = image.to_rgba8();
+            let encoded = webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+                .encode(f32::from(quality));
+            bytes.extend_from_slice(&encoded);
+        }
+        _ => {
+            let format = format
+                .decoder_format()
+                .ok_or_else(|| anyhow::anyhow!("Cannot encode {format:?} images"))?;
+            image.write_to(&mut Cursor::new(&mut bytes), format)?;
+        }
+    }
+    Ok(bytes)
+}
+
+struct StrippedImage {
+    bytes: Vec<u8>,
+    /// Whether the pixels were rotated or flipped to apply the EXIF orientation.
+    reoriented: bool,
+}
+
+/// Removes location, camera and other metadata from an image. The EXIF orientation is lost along
+/// with everything else, so it is applied to the pixels first.
+///
+/// AVIF is stored as uploaded since it can't be decoded here.
+fn strip_metadata(bytes: &[u8]) -> anyhow::Result<StrippedImage> {
+    let format = ImageFormat::sniff(bytes);
+    let (Some(format), Some(decoder_format)) = (format, format.and_then(|f| f.decoder_format()))
+    else {
+        return Ok(StrippedImage {
+            bytes: bytes.to_vec(),
+            reoriented: false,
+        });
+    };
+
+    let mut decoder =
+        image::ImageReader::with_format(Cursor::new(bytes), decoder_format).into_decoder()?;
+    let orientation = decoder.orientation()?;
+
+    if orientation != Orientation::NoTransforms {
+        // Re-encoding doesn't carry over any metadata.
+        let mut image = DynamicImage::from_decoder(decoder)?;
+        image.apply_orientation(orientation);
+        return Ok(StrippedImage {
+            bytes: encode(&image, format, REENCODE_QUALITY)?,
+            reoriented: true,
+        });
+    }
+
+    let bytes = Bytes::copy_from_slice(bytes);
+    let stripped = match format {
+        ImageFormat::Jpeg => {
+            let mut jpeg = Jpeg::from_bytes(bytes)?;
+            // APP1 holds EXIF and XMP, APP13 holds IPTC. The colour profile and Adobe colour
+            // transform segments are kept since they change how the image looks.
+            jpeg.remove_segments_by_marker(markers::APP1);
+            jpeg.remove_segments_by_marker(markers::APP13);
+            jpeg.encoder().bytes()
+        }
+        ImageFormat::Png => {
+            let mut png = Png::from_bytes(bytes)?;
+            png.set_exif(None);
+            // Text chunks hold XMP and free-form fields such as the author or software used.
+            for chunk in [*b"tEXt", *b"zTXt", *b"iTXt", *b"tIME"] {
+                png.remove_chunks_by_type(chunk);
+            }
+            png.encoder().bytes()
+        }
+        ImageFormat::WebP => {
+            let mut webp = WebP::from_bytes(bytes)?;
+            webp.set_exif(None);
+            webp.remove_chunks_by_id(CHUNK_XMP);
+            webp.encoder().bytes()
+        }
+        // GIFs can't carry EXIF.
+        ImageFormat::Gif | ImageFormat::Avif => bytes,
+    };
+
+    Ok(StrippedImage {
+        bytes: stripped.to_vec(),
+        reoriented: false,
+    })
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct MetadataCleanupReport {
+    images_checked: usize,
+    images_rewritten: Vec<String>,
+    failed: Vec<String>,
+    /// The new variants of the images that had to be rotated, by url, for the library to record.
+    #[serde(skip)]
+    pub variants: Vec<(String, Vec<ImageVariant>)>,
+}
+
+/// Strips the metadata from every image already in storage, for images that were stored before
+/// [`prepare_image`] did so itself.
+///
+/// Images that had to be rotated get their variants made again, since those were made from the
+/// unrotated pixels.
+///
+/// Images named by their hash are left alone. They went through [`prepare_image`] already, and
+/// they are served on the promise that their bytes never change, so rewriting them would leave
+/// clients with stale copies.
+pub fn strip_stored_metadata() -> io::Result<MetadataCleanupReport> {
+    let mut report = MetadataCleanupReport::default();
+
+    for object in storage().list("")? {
+        let key = object.key;
+        if serve::hash_in_name(&key).is_some() {
+            continue;
+        }
+        let bytes = storage().get(&key)?;
+        if ImageFormat::sniff(&bytes).is_none() {
+            continue;
+        }
+        report.images_checked += 1;
+
+        let stripped = match strip_metadata(&bytes) {
+            Ok(stripped) => stripped,
+            Err(err) => {
+                println!("Could not strip metadata from {key}: {err}");
+                report.failed.push(stored_url(&key));
+                continue;
+            }
+        };
+        if stripped.bytes == bytes {
+            continue;
+        }
+
+        storage().put(&key, &stripped.bytes, &content_type_of(&stripped.bytes))?;
+
+        if stripped.reoriented {
+            match store_variants(&key, &stripped.bytes) {
+                Ok(variants) => report.variants.push((stored_url(&key), variants)),
+                Err(err) => println!("Could not create variants of {key}: {err}"),
+            }
+        }
+        report.images_rewritten.push(stored_url(&key));
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,185 @@
+//! Garbage collection of stored images that nothing references any more, such as variants left
+//! behind or files stored before the image library kept track of them. Anything with a row in the
+//! library is kept, as the library is where images are chosen for new articles.
+//!
+//! Orphans are moved under a `.trash` prefix in image storage rather than deleted, so anything
+//! collected by mistake can be moved back. The trash isn't served since it is hidden.
+
+use super::storage::storage;
+use super::{normalize_url, stored_url};
+use crate::article::{ArticleContent, SpanContent};
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use serde::Serialize;
+use std::collections::HashSet;
+
+/// How long a file has to go unreferenced before it is collected, so images that were just
+/// uploaded or imported for an article that hasn't been published yet are left alone.
+pub const DEFAULT_GRACE_PERIOD_DAYS: i64 = 7;
+
+const TRASH_DIR: &str = ".trash";
+
+pub struct GcOptions {
+    /// Only report the orphans, without moving anything.
+    pub dry_run: bool,
+    pub grace_period: Duration,
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        GcOptions {
+            dry_run: false,
+            grace_period: Duration::days(DEFAULT_GRACE_PERIOD_DAYS),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Orphan {
+    pub url: String,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+    /// Whether the file was modified too recently to be collected yet.
+    pub in_grace_period: bool,
+    pub trashed: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct GcReport {
+    pub dry_run: bool,
+    pub files_scanned: usize,
+    pub orphans: Vec<Orphan>,
+}
+
+/// The part of an image url shared by the original and all of its variants, e.g. `/image/2022/9/x`
+/// for both `/image/2022/9/x.jpeg` and `/image/2022/9/x-640w.webp`.
+fn original_key(url: &str) -> &str {
+    let file_name = url.rsplit_once('/').map_or(url, |(_, file_name)| file_name);
+    let stem = file_name
+        .rsplit_once('.')
+        .map_or(file_name, |(stem, _)| stem);
+    let stem = match stem.rsplit_once('-') {
+        Some((original, width))
+            if width.len() > 1
+                && width.ends_with('w')
+                && width[..width.len() - 1].bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            original
+        }
+        _ => stem,
+    };
+
+    &url[..url.len() - file_name.len() + stem.len()]
+}
+
+/// Turns a stored reference, which may be a full url, into the `/image/...` path it points at.
+fn image_path(reference: &str) -> Option<&str> {
+    let start = reference.find("/image/")?;
+    let path = &reference[start..];
+    Some(path.split(['?', '#']).next().unwrap_or(path))
+}
+
+/// Collects every image path in the image library or referenced by articles, writers and
+/// submissions.
+///
+/// An article body that can't be parsed could be hiding references, so it fails the whole
+/// collection rather than letting its images be collected.
+pub fn referenced_images(db_connection: &PgConnection) -> anyhow::Result<HashSet<String>> {
+    use crate::schema::article_submission::dsl::{article_submission, thumbnail_url};
+    use crate::schema::articles::dsl::{articles, body, id, image_url as article_image_url};
+    use crate::schema::images::dsl::{images, path as library_path};
+    use crate::schema::writers::dsl::{image_url as writer_image_url, writers};
+
+    let mut references: Vec<String> = images.select(library_path).load(db_connection)?;
+    references.extend(
+        articles
+            .select(article_image_url)
+            .load::<Option<String>>(db_connection)?
+            .into_iter()
+            .flatten(),
+    );
+    references.extend(
+        writers
+            .select(writer_image_url)
+            .load::<Option<String>>(db_connection)?
+            .into_iter()
+            .flatten(),
+    );
+    references.extend(
+        article_submission
+            .select(thumbnail_url)
+            .load::<Option<String>>(db_connection)?
+            .into_iter()
+            .flatten(),
+    );
+
+    let bodies = articles
+        .select((id, body))
+        .load::<(i32, String)>(db_connection)?;
+    for (article_id, article_body) in bodies {
+        let content = serde_json::from_str::<ArticleContent>(&article_body).map_err(|err| {
+            anyhow::anyhow!("The body of article {article_id} could not be parsed: {err}")
+        })?;
+        let sources = content
+            .paragraphs
+            .into_iter()
+            .flat_map(|paragraph| paragraph.spans)
+            .flat_map(|span| span.content)
+            .filter_map(|content| match content {
+                SpanContent::image { src, .. } => Some(src),
+                _ => None,
+            });
+        references.extend(sources);
+    }
+
+    Ok(references
+        .iter()
+        .map(|reference| normalize_url(reference))
+        .filter_map(|reference| image_path(&reference).map(|path| original_key(path).to_owned()))
+        .collect())
+}
+
+/// Finds stored images that aren't in `references`, from [`referenced_images`], and unless this is
+/// a dry run, moves those older than the grace period to the trash.
+///
+/// This only talks to image storage, so it can run without holding a database connection. Images
+/// referenced after `references` were loaded are safe as long as they are newer than the grace
+/// period.
+pub fn collect_orphans(
+    references: &HashSet<String>,
+    options: &GcOptions,
+) -> anyhow::Result<GcReport> {
+    let objects = storage().list("")?;
+    let cutoff = Utc::now() - options.grace_period;
+
+    let mut report = GcReport {
+        dry_run: options.dry_run,
+        files_scanned: objects.len(),
+        orphans: Vec::new(),
+    };
+
+    for object in objects {
+        let url = stored_url(&object.key);
+        if references.contains(original_key(&url)) {
+            continue;
+        }
+
+        let in_grace_period = object.modified > cutoff;
+
+        let mut trashed = false;
+        if !options.dry_run && !in_grace_period {
+            storage().rename(&object.key, &format!("{TRASH_DIR}/{}", object.key))?;
+            trashed = true;
+        }
+
+        report.orphans.push(Orphan {
+            url,
+            size: object.size,
+            modified: object.modified,
+            in_grace_period,
+            trashed,
+        });
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,305 @@
+//! The `images` table, which keeps a record of every stored image along with the credit, caption
+//! and alt text editors give it, so photos can be found and reused.
+
+use super::storage::storage;
+use super::{prepare_image, public_url, storage_key, store_prepared, ImageFormat, ImageVariant};
+use crate::article::{ArticleContent, SpanContent};
+use crate::audit::{Actor, Change, Target};
+use crate::schema::images;
+use chrono::{DateTime, Utc};
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::sql_types::Bool;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+#[derive(Queryable, Identifiable, Debug, Serialize)]
+#[table_name = "images"]
+pub struct DBImage {
+    pub id: i32,
+    /// The url the image is served from, e.g. `/image/2022/9/<name>.jpeg`.
+    pub path: String,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    /// Hex encoded SHA-256 of the stored file.
+    pub hash: String,
+    /// The role of whoever uploaded or imported the image.
+    pub uploader: String,
+    pub credit: Option<String>,
+    pub caption: Option<String>,
+    pub alt_text: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// The resized and WebP copies made when the image was stored, see [`ImageVariant`].
+    pub variants: Value,
+}
+
+impl DBImage {
+    /// The image's variants, with the urls clients should load them from.
+    pub fn variants(&self) -> Vec<ImageVariant> {
+        public_variants(self.variants.clone())
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "images"]
+struct NewImage<'a> {
+    path: &'a str,
+    width: Option<i32>,
+    height: Option<i32>,
+    hash: String,
+    uploader: &'a str,
+    alt_text: Option<&'a str>,
+    variants: Value,
+}
+
+/// The metadata editors can change on an image.
+#[derive(Deserialize, AsChangeset, Debug)]
+#[table_name = "images"]
+pub struct ImagePatch {
+    pub credit: Option<String>,
+    pub caption: Option<String>,
+    pub alt_text: Option<String>,
+}
+
+impl ImagePatch {
+    /// Whether the patch leaves everything as it is.
+    pub fn is_empty(&self) -> bool {
+        self.credit.is_none() && self.caption.is_none() && self.alt_text.is_none()
+    }
+}
+
+/// Matches images whose caption, credit, alt text or path contain `search`, ignoring case.
+pub fn matches_search(
+    search: &str,
+) -> Box<dyn BoxableExpression<images::table, Pg, SqlType = Bool>> {
+    use crate::schema::images::dsl::{alt_text, caption, credit, path};
+
+    // Escape LIKE wildcards so they are matched literally.
+    let escaped = search
+        .trim()
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    let pattern = format!("%{escaped}%");
+
+    Box::new(
+        caption
+            .ilike(pattern.clone())
+            .or(credit.ilike(pattern.clone()))
+            .or(alt_text.ilike(pattern.clone()))
+            .or(path.ilike(pattern)),
+    )
+}
+
+/// Stores images on behalf of an uploader, reusing any identical image already in the library.
+///
+/// The database is only locked to look up and record images, not while they are decoded, resized
+/// and written to storage. That still blocks, so stores from a request handler should be made in
+/// [`rocket::tokio::task::block_in_place`].
+pub struct ImageStore<'a> {
+    pub db_connection: &'a Mutex<PgConnection>,
+    /// The role of whoever is uploading or importing the images.
+    pub uploader: &'a str,
+    /// Who images added to the library are recorded in the audit log as created by.
+    pub actor: &'a Actor,
+}
+
+impl ImageStore<'_> {
+    /// Stores an image, or finds the library's copy if it has been stored before, and returns its
+    /// row. `extension` is only used if the format of the image can't be worked out from its bytes.
+    ///
+    /// Images are matched by the hash of their contents, so an image stored under its old
+    /// `<year>/<month>/<uuid>` name is found just like one stored under its hash.
+    pub fn store(&self, bytes: &[u8], extension: &str) -> anyhow::Result<DBImage> {
+        use crate::schema::images::dsl::{hash, id, images, path};
+
+        let prepared = prepare_image(bytes)?;
+        let existing = images
+            .filter(hash.eq(&prepared.hash))
+            .order(id)
+            .first::<DBImage>(&*self.lock()?)
+            .optional()?;
+        if let Some(existing) = existing {
+            return Ok(existing);
+        }
+
+        let extension = match ImageFormat::sniff(&prepared.bytes) {
+            Some(format) => format.extension(),
+            None => extension,
+        };
+        let stored = store_prepared(&prepared, extension)?;
+        let url = stored.url;
+        let size = imagesize::blob_size(&prepared.bytes).ok();
+
+        let new_image = NewImage {
+            path: &url,
+            width: size.map(|size| size.width as i32),
+            height: size.map(|size| size.height as i32),
+            hash: prepared.hash,
+            uploader: self.uploader,
+            alt_text: None,
+            variants: serde_json::to_value(&stored.variants)?,
+        };
+
+        // Someone else storing the same image at the same time ends up with the same path.
+        let db_connection = &*self.lock()?;
+        db_connection.transaction::<_, anyhow::Error, _>(|| {
+            let inserted = diesel::insert_into(images)
+                .values(&new_image)
+                .on_conflict(path)
+                .do_nothing()
+                .get_result::<DBImage>(db_connection)
+                .optional()?;
+            let Some(image) = inserted else {
+                return Ok(images
+                    .filter(path.eq(&url))
+                    .first::<DBImage>(db_connection)?);
+            };
+            self.actor.record(
+                db_connection,
+                "create",
+                Target::Image(image.id),
+                Change::created(&image),
+            )?;
+            Ok(image)
+        })
+    }
+
+    /// Like [`ImageStore::store`], but only returns the url the image is recorded as.
+    pub fn store_url(&self, bytes: &[u8], extension: &str) -> anyhow::Result<String> {
+        Ok(self.store(bytes, extension)?.path)
+    }
+
+    fn lock(&self) -> anyhow::Result<MutexGuard<'_, PgConnection>> {
+        self.db_connection
+            .lock()
+            .map_err(|_| anyhow::anyhow!("The database connection lock is poisoned"))
+    }
+}
+
+/// Adds the image served at `url` to the library, if it isn't there already, and returns its row.
+pub fn record_image(
+    db_connection: &PgConnection,
+    url: &str,
+    uploader: &str,
+    alt_text: Option<&str>,
+) -> anyhow::Result<DBImage> {
+    use crate::schema::images::dsl::{images, path};
+
+    if let Some(existing) = images
+        .filter(path.eq(url))
+        .first::<DBImage>(db_connection)
+        .optional()?
+    {
+        return Ok(existing);
+    }
+
+    let key = storage_key(url).ok_or_else(|| anyhow::anyhow!("{url} is not a stored image"))?;
+    let bytes = storage().get(key)?;
+    let size = imagesize::blob_size(&bytes).ok();
+
+    let new_image = NewImage {
+        path: url,
+        width: size.map(|size| size.width as i32),
+        height: size.map(|size| size.height as i32),
+        hash: format!("{:x}", Sha256::digest(&bytes)),
+        uploader,
+        alt_text: alt_text.filter(|alt| !alt.trim().is_empty()),
+        variants: Value::Array(Vec::new()),
+    };
+
+    Ok(diesel::insert_into(images)
+        .values(&new_image)
+        .get_result(db_connection)?)
+}
+
+/// The variants recorded for the image served at `url`, with the urls clients should load them
+/// from. Images that aren't in the library have none.
+pub fn variants_of(db_connection: &PgConnection, url: &str) -> QueryResult<Vec<ImageVariant>> {
+    use crate::schema::images::dsl::{images, path, variants};
+
+    let recorded = images
+        .filter(path.eq(url))
+        .select(variants)
+        .first::<Value>(db_connection)
+        .optional()?;
+    Ok(recorded.map(public_variants).unwrap_or_default())
+}
+
+/// [`variants_of`] for every image in `urls` at once, keyed by url. Images that aren't in the
+/// library are left out.
+pub fn variants_for(
+    db_connection: &PgConnection,
+    urls: &[&str],
+) -> QueryResult<HashMap<String, Vec<ImageVariant>>> {
+    use crate::schema::images::dsl::{images, path, variants};
+
+    if urls.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let recorded = images
+        .filter(path.eq_any(urls))
+        .select((path, variants))
+        .load::<(String, Value)>(db_connection)?;
+    Ok(recorded
+        .into_iter()
+        .map(|(url, recorded)| (url, public_variants(recorded)))
+        .collect())
+}
+
+/// Replaces the variants recorded for the image served at `url`, after they have been made again.
+pub fn set_variants(
+    db_connection: &PgConnection,
+    url: &str,
+    new_variants: &[ImageVariant],
+) -> anyhow::Result<()> {
+    use crate::schema::images::dsl::{images, path, variants};
+
+    diesel::update(images.filter(path.eq(url)))
+        .set(variants.eq(serde_json::to_value(new_variants)?))
+        .execute(db_connection)?;
+    Ok(())
+}
+
+fn public_variants(recorded: Value) -> Vec<ImageVariant> {
+    let mut variants = serde_json::from_value::<Vec<ImageVariant>>(recorded).unwrap_or_default();
+    for variant in &mut variants {
+        variant.url = public_url(&variant.url);
+    }
+    variants
+}
+
+/// Records every stored image an imported article uses, and fills in missing alt text from the
+/// library for images that have been described before.
+pub fn record_article_images(
+    db_connection: &PgConnection,
+    content: &mut ArticleContent,
+    uploader: &str,
+) -> anyhow::Result<()> {
+    let spans = content
+        .paragraphs
+        .iter_mut()
+        .flat_map(|paragraph| &mut paragraph.spans)
+        .flat_map(|span| &mut span.content);
+
+    for span in spans {
+        let SpanContent::image { src, alt, .. } = span else {
+            continue;
+        };
+        if storage_key(src).is_none() {
+            continue;
+        }
+
+        let image = record_image(db_connection, src, uploader, Some(alt))?;
+        if alt.trim().is_empty() {
+            if let Some(alt_text) = image.alt_text {
+                *alt = alt_text;
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,347 @@
+//! Responses for `/image/...` requests.
+//!
+//! Every image gets a strong ETag, the SHA-256 of its bytes, and supports conditional and range
+//! requests. Images named by that hash, see [`super::store_prepared`], never change, so browsers
+//! are told to cache them for good and a matching `If-None-Match` is answered without reading
+//! anything from storage.
+
+use super::ImageFormat;
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::{self, Responder, Response};
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
+use std::path::Path;
+
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+/// Older images are named by uuid and can be rewritten in place, e.g. to strip their metadata.
+const REVALIDATE: &str = "public, max-age=3600";
+
+/// Shown in place of images that don't exist.
+const NOT_FOUND_SVG: &str = concat!(
+    r##"<svg xmlns="http://www.w3.org/2000/svg" width="640" height="360" viewBox="0 0 640 360">"##,
+    r##"<rect width="640" height="360" fill="#e5e5e5"/>"##,
+    r##"<text x="320" y="180" fill="#737373" font-family="sans-serif" font-size="24" "##,
+    r##"text-anchor="middle" dominant-baseline="middle">Image not found</text></svg>"##,
+);
+
+/// The request headers that decide what an image response holds.
+pub struct ImageRequestHeaders<'r> {
+    if_none_match: Option<&'r str>,
+    range: Option<&'r str>,
+    if_range: Option<&'r str>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ImageRequestHeaders<'r> {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = req.headers();
+        Outcome::Success(ImageRequestHeaders {
+            if_none_match: headers.get_one("If-None-Match"),
+            range: headers.get_one("Range"),
+            if_range: headers.get_one("If-Range"),
+        })
+    }
+}
+
+pub enum ImageResponse {
+    Full {
+        bytes: Vec<u8>,
+        content_type: ContentType,
+        etag: String,
+        cache_control: &'static str,
+    },
+    Partial {
+        bytes: Vec<u8>,
+        content_type: ContentType,
+        etag: String,
+        cache_control: &'static str,
+        /// The first and last byte sent, inclusive.
+        range: (usize, usize),
+        total: usize,
+    },
+    NotModified {
+        etag: String,
+        cache_control: &'static str,
+    },
+    RangeNotSatisfiable {
+        total: usize,
+    },
+    NotFound,
+}
+
+impl ImageResponse {
+    /// Answers a conditional request for the image stored at `key` without reading it, which is
+    /// only possible when its name says what its ETag is.
+    pub fn not_modified(key: &str, headers: &ImageRequestHeaders) -> Option<Self> {
+        let etag = etag_from_name(key)?;
+        etag_matches(headers.if_none_match?, &etag).then_some(ImageResponse::NotModified {
+            etag,
+            cache_control: IMMUTABLE,
+        })
+    }
+
+    /// Responds with the image stored at `key`, or the part of it asked for.
+    pub fn new(key: &str, bytes: Vec<u8>, headers: &ImageRequestHeaders) -> Self {
+        let hash = format!("{:x}", Sha256::digest(&bytes));
+        let cache_control = if hash_in_name(key) == Some(hash.as_str()) {
+            IMMUTABLE
+        } else {
+            REVALIDATE
+        };
+        let etag = format!("\"{hash}\"");
+
+        if let Some(if_none_match) = headers.if_none_match {
+            if etag_matches(if_none_match, &etag) {
+                return ImageResponse::NotModified {
+                    etag,
+                    cache_control,
+                };
+            }
+        }
+
+        let content_type = ImageFormat::sniff(&bytes)
+            .map(ImageFormat::content_type)
+            .or_else(|| {
+                let extension = Path::new(key).extension()?.to_str()?;
+                ContentType::from_extension(extension)
+            })
+            .unwrap_or(ContentType::Binary);
+
+        // A range is only for the version of the image named by `If-Range`, if there is one.
+        let range = headers
+            .range
+            .filter(|_| headers.if_range.is_none_or(|if_range| if_range == etag));
+        let total = bytes.len();
+        match range.map(|range| parse_range(range, total)) {
+            Some(RangeRequest::Satisfiable(start, end)) => ImageResponse::Partial {
+                bytes: bytes[start..=end].to_vec(),
+                content_type,
+                etag,
+                cache_control,
+                range: (start, end),
+                total,
+            },
+            Some(RangeRequest::Unsatisfiable) => ImageResponse::RangeNotSatisfiable { total },
+            Some(RangeRequest::Ignored) | None => ImageResponse::Full {
+                bytes,
+                content_type,
+                etag,
+                cache_control,
+            },
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ImageResponse {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response.raw_header("Accept-Ranges", "bytes");
+        response.raw_header("X-Content-Type-Options", "nosniff");
+
+        match self {
+            ImageResponse::Full {
+                bytes,
+                content_type,
+                etag,
+                cache_control,
+            } => response
+                .header(content_type)
+                .header(Header::new("ETag", etag))
+                .raw_header("Cache-Control", cache_control)
+                .sized_body(bytes.len(), Cursor::new(bytes)),
+            ImageResponse::Partial {
+                bytes,
+                content_type,
+                etag,
+                cache_control,
+                range: (start, end),
+                total,
+            } => response
+                .status(Status::PartialContent)
+                .header(content_type)
+                .header(Header::new("ETag", etag))
+                .raw_header("Cache-Control", cache_control)
+                .header(Header::new(
+                    "Content-Range",
+                    format!("bytes {start}-{end}/{total}"),
+                ))
+                .sized_body(bytes.len(), Cursor::new(bytes)),
+            ImageResponse::NotModified {
+                etag,
+                cache_control,
+            } => response
+                .status(Status::NotModified)
+                .header(Header::new("ETag", etag))
+                .raw_header("Cache-Control", cache_control),
+            ImageResponse::RangeNotSatisfiable { total } => response
+                .status(Status::RangeNotSatisfiable)
+                .header(Header::new("Content-Range", format!("bytes */{total}"))),
+            ImageResponse::NotFound => response
+                .status(Status::NotFound)
+                .header(ContentType::SVG)
+                .raw_header("Cache-Control", "no-store")
+                .sized_body(NOT_FOUND_SVG.len(), Cursor::new(NOT_FOUND_SVG)),
+        };
+
+        response.ok()
+    }
+}
+
+/// The hash `key` is named by, if any, e.g. `<hash>` for `ab/<hash>.jpeg` or
+/// `writers/3/<hash>.jpeg`. Resized variants such as `ab/<hash>-640w.webp` have none.
+pub(super) fn hash_in_name(key: &str) -> Option<&str> {
+    let file_name = key.rsplit('/').next()?;
+    let (stem, _) = file_name.split_once('.')?;
+    let is_hash = stem.len() == 64 && stem.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+    is_hash.then_some(stem)
+}
+
+/// The ETag of the image stored at `key`, if its name is enough to tell.
+///
+/// That is only the case for originals, which are named by the hash of their bytes. The full size
+/// WebP copy of a JPEG or PNG, `ab/<hash>.webp`, shares the original's name without holding the
+/// same bytes, and can't be told apart from a WebP original by its name, so WebP images are never
+/// trusted.
+fn etag_from_name(key: &str) -> Option<String> {
+    if key.ends_with(".webp") {
+        return None;
+    }
+    hash_in_name(key).map(|hash| format!("\"{hash}\""))
+}
+
+/// Whether an `If-None-Match` header matches `etag`. The comparison is weak, as RFC 9110 requires,
+/// so `W/"<hash>"` matches too.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+    })
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum RangeRequest {
+    /// The first and last byte to send, inclusive.
+    Satisfiable(usize, usize),
+    Unsatisfiable,
+    /// Malformed or multiple ranges, which are answered with the whole image as RFC 9110 allows.
+    Ignored,
+}
+
+fn parse_range(header: &str, total: usize) -> RangeRequest {
+    let Some(range) = header.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Ignored;
+    };
+    if range.contains(',') {
+        return RangeRequest::Ignored;
+    }
+    let Some((start, end)) = range.trim().split_once('-') else {
+        return RangeRequest::Ignored;
+    };
+
+    let (start, end) = match (start.trim(), end.trim()) {
+        // `bytes=-500` is the last 500 bytes.
+        ("", suffix) => match suffix.parse::<usize>() {
+            Ok(0) => return RangeRequest::Unsatisfiable,
+            Ok(suffix) => (total.saturating_sub(suffix), total.saturating_sub(1)),
+            Err(_) => return RangeRequest::Ignored,
+        },
+        (start, "") => match start.parse::<usize>() {
+            Ok(start) => (start, total.saturating_sub(1)),
+            Err(_) => return RangeRequest::Ignored,
+        },
+        (start, end) => match (start.parse::<usize>(), end.parse::<usize>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end.min(total.saturating_sub(1))),
+            _ => return RangeRequest::Ignored,
+        },
+    };
+
+    if total == 0 || start >= total {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Satisfiable(start, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(
+            parse_range("bytes=0-9", 100),
+            RangeRequest::Satisfiable(0, 9)
+        );
+        assert_eq!(
+            parse_range("bytes=90-", 100),
+            RangeRequest::Satisfiable(90, 99)
+        );
+        assert_eq!(
+            parse_range("bytes=-10", 100),
+            RangeRequest::Satisfiable(90, 99)
+        );
+        assert_eq!(
+            parse_range("bytes=-500", 100),
+            RangeRequest::Satisfiable(0, 99)
+        );
+        assert_eq!(
+            parse_range("bytes=50-500", 100),
+            RangeRequest::Satisfiable(50, 99)
+        );
+        assert_eq!(parse_range("bytes=100-", 100), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 100), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), RangeRequest::Ignored);
+        assert_eq!(parse_range("bytes=9-0", 100), RangeRequest::Ignored);
+        assert_eq!(parse_range("items=0-9", 100), RangeRequest::Ignored);
+    }
+
+    #[test]
+    fn matches_etags() {
+        let hash = "a".repeat(64);
+        let etag = etag_from_name(&format!("aa/{hash}.jpeg")).unwrap();
+        assert_eq!(etag, format!("\"{hash}\""));
+        assert_eq!(etag_from_name(&format!("aa/{hash}-640w.webp")), None);
+        assert_eq!(etag_from_name(&format!("aa/{hash}.webp")), None);
+        assert_eq!(
+            etag_from_name(&format!("writers/3/{hash}.jpeg")),
+            Some(etag.clone())
+        );
+        assert_eq!(etag_from_name("2022/9/0b5cb8a4-uuid.jpeg"), None);
+
+        assert!(etag_matches(&etag, &etag));
+        assert!(etag_matches(&format!("\"other\", W/{etag}"), &etag));
+        assert!(etag_matches("*", &etag));
+        assert!(!etag_matches("\"other\"", &etag));
+    }
+
+    #[test]
+    fn only_caches_images_holding_the_hash_they_are_named_by_forever() {
+        let headers = ImageRequestHeaders {
+            if_none_match: None,
+            range: None,
+            if_range: None,
+        };
+        let cache_control =
+            |key: &str, bytes: &[u8]| match ImageResponse::new(key, bytes.to_vec(), &headers) {
+                ImageResponse::Full { cache_control, .. } => cache_control,
+                _ => panic!("expected the whole image"),
+            };
+
+        let original = b"RIFF\0\0\0\0WEBPVP8 ";
+        let hash = format!("{:x}", Sha256::digest(original));
+        assert_eq!(
+            cache_control(&format!("ab/{hash}.webp"), original),
+            IMMUTABLE
+        );
+        assert_eq!(
+            cache_control(&format!("ab/{hash}.webp"), b"copy"),
+            REVALIDATE
+        );
+        assert_eq!(
+            cache_control("2022/9/0b5cb8a4-uuid.jpeg", original),
+            REVALIDATE
+        );
+    }
+}
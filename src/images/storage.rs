@@ -0,0 +1,246 @@
+//! Where stored images are kept.
+//!
+//! Images are addressed by a key relative to the storage root, such as `2022/9/<name>.jpeg`, and
+//! are always recorded in the database as `/image/<key>`, which the server serves whichever backend
+//! holds them. The url handed to clients can point somewhere else instead, such as a CDN in front
+//! of a bucket, by setting `IMAGE_PUBLIC_BASE_URL`.
+//!
+//! `IMAGE_STORAGE` picks the backend:
+//! - `filesystem` (the default) keeps images under `ARTICLE_IMAGE_PATH`.
+//! - `s3` keeps them in the S3-compatible bucket `S3_BUCKET` at `S3_ENDPOINT`, see [`S3Storage`].
+
+mod s3;
+
+pub use s3::S3Storage;
+
+use crate::config::{ImageConfig, ImageStorageConfig};
+use chrono::{DateTime, Utc};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::UNIX_EPOCH;
+
+/// The path images are served from by this server.
+pub const DEFAULT_PUBLIC_BASE_URL: &str = "/image";
+
+/// A stored image, or one of its variants.
+#[derive(Debug, Clone)]
+pub struct StoredObject {
+    pub key: String,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+}
+
+pub trait ImageStorage: Send + Sync {
+    /// Stores `bytes` at `key`, replacing anything already there. Readers never see a partly
+    /// written object.
+    fn put(&self, key: &str, bytes: &[u8], content_type: &str) -> io::Result<()>;
+
+    /// Reads the object at `key`, failing with [`io::ErrorKind::NotFound`] if there is none.
+    fn get(&self, key: &str) -> io::Result<Vec<u8>>;
+
+    /// Something that changes whenever the object at `key` is replaced, such as its ETag, failing
+    /// with [`io::ErrorKind::NotFound`] if there is none. Cheaper than reading the object.
+    fn version(&self, key: &str) -> io::Result<String>;
+
+    fn delete(&self, key: &str) -> io::Result<()>;
+
+    /// Moves the object at `from` to `to`.
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    /// Lists the objects whose keys start with `prefix`. Keys with a part starting with a `.`, such
+    /// as those in the trash, are hidden and never listed.
+    fn list(&self, prefix: &str) -> io::Result<Vec<StoredObject>>;
+
+    /// The url clients are given for stored images, which keys are appended to.
+    fn public_base_url(&self) -> &str;
+
+    fn public_url(&self, key: &str) -> String {
+        format!("{}/{key}", self.public_base_url().trim_end_matches('/'))
+    }
+}
+
+/// Whether a key has a part that starts with a `.`, which is never listed or served.
+pub fn is_hidden(key: &str) -> bool {
+    key.split('/').any(|part| part.starts_with('.'))
+}
+
+static STORAGE: OnceLock<Box<dyn ImageStorage>> = OnceLock::new();
+
+/// Sets up the configured storage, once at launch.
+pub fn init(config: &ImageConfig) {
+    let public_base_url = config.public_base_url.clone();
+    let storage: Box<dyn ImageStorage> = match &config.storage {
+        ImageStorageConfig::Filesystem(root) => Box::new(FsStorage::new(root, public_base_url)),
+        ImageStorageConfig::S3 {
+            endpoint,
+            bucket,
+            region,
+            access_key_id,
+            secret_access_key,
+        } => Box::new(
+            S3Storage::new(
+                endpoint,
+                bucket.clone(),
+                region.clone(),
+                access_key_id.clone(),
+                secret_access_key.clone(),
+                public_base_url,
+            )
+            .expect("S3_ENDPOINT should have been checked with the configuration"),
+        ),
+    };
+    if STORAGE.set(storage).is_err() {
+        panic!("image storage should only be set up once");
+    }
+}
+
+/// The storage set up by [`init`].
+pub fn storage() -> &'static dyn ImageStorage {
+    &**STORAGE
+        .get()
+        .expect("image storage should be set up at launch")
+}
+
+/// Keeps images in a local directory.
+pub struct FsStorage {
+    root: PathBuf,
+    public_base_url: String,
+}
+
+impl FsStorage {
+    pub fn new(root: impl Into<PathBuf>, public_base_url: String) -> Self {
+        FsStorage {
+            root: root.into(),
+            public_base_url,
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+impl ImageStorage for FsStorage {
+    fn put(&self, key: &str, bytes: &[u8], _content_type: &str) -> io::Result<()> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Write next to the destination and rename over it, so a crash can't leave half an image.
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(".partial");
+        fs::write(&temporary, bytes)?;
+        fs::rename(&temporary, &path)
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path(key))
+    }
+
+    /// The size and modification time, along with the inode where there is one, since `put`
+    /// always writes a new file and the time may not have moved on since the last write.
+    fn version(&self, key: &str) -> io::Result<String> {
+        let metadata = fs::metadata(self.path(key))?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(&metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+        Ok(format!(
+            "{}-{}-{inode}",
+            metadata.len(),
+            modified.as_nanos()
+        ))
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        fs::remove_file(self.path(key))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let to = self.path(to);
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(self.path(from), to)
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<StoredObject>> {
+        fn walk(root: &Path, directory: &Path, objects: &mut Vec<StoredObject>) -> io::Result<()> {
+            for entry in fs::read_dir(directory)? {
+                let entry = entry?;
+                let path = entry.path();
+                if entry.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+                if entry.file_type()?.is_dir() {
+                    walk(root, &path, objects)?;
+                    continue;
+                }
+
+                let relative = path.strip_prefix(root).unwrap_or(&path);
+                let key = relative
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                let metadata = entry.metadata()?;
+                objects.push(StoredObject {
+                    key,
+                    size: metadata.len(),
+                    modified: metadata.modified()?.into(),
+                });
+            }
+            Ok(())
+        }
+
+        // Only walk the directory the prefix is in, rather than every stored image.
+        let directory = match prefix.rsplit_once('/') {
+            Some((directory, _)) => self.path(directory),
+            None => self.root.clone(),
+        };
+        if !directory.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut objects = Vec::new();
+        walk(&self.root, &directory, &mut objects)?;
+        objects.retain(|object| object.key.starts_with(prefix) && !is_hidden(&object.key));
+        Ok(objects)
+    }
+
+    fn public_base_url(&self) -> &str {
+        &self.public_base_url
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_change_when_objects_are_replaced() {
+        let root = std::env::temp_dir().join(format!("fs-storage-{}", uuid::Uuid::new_v4()));
+        let storage = FsStorage::new(&root, "/image".into());
+
+        storage.put("ab/image.png", b"first", "image/png").unwrap();
+        let first = storage.version("ab/image.png").unwrap();
+        assert_eq!(storage.version("ab/image.png").unwrap(), first);
+        storage.put("ab/image.png", b"later", "image/png").unwrap();
+        assert_ne!(storage.version("ab/image.png").unwrap(), first);
+
+        storage.delete("ab/image.png").unwrap();
+        assert_eq!(
+            storage.version("ab/image.png").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
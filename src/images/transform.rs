@@ -0,0 +1,321 @@
+//! Resized, cropped and re-encoded copies of stored images, made on request for
+//! `/image/<path>?w=&h=&fit=&format=` and kept in a disk cache.
+//!
+//! Only the sizes in `IMAGE_TRANSFORM_SIZES` (a comma separated list of pixel sizes) can be asked
+//! for, so a client can't fill the cache or keep the server busy with every possible size. The
+//! cache lives in `IMAGE_TRANSFORM_CACHE_PATH` and is kept under `IMAGE_TRANSFORM_CACHE_MB`
+//! megabytes by deleting the least recently used copies.
+
+use super::storage::storage;
+use super::{encode, ImageFormat, VARIANT_QUALITY};
+use crate::config::TransformConfig;
+use image::imageops::FilterType;
+use rocket::FromFormField;
+use sha2::{Digest, Sha256};
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+/// Eviction frees space down to this fraction of the limit, so it doesn't run on every insert.
+const EVICTION_TARGET: f64 = 0.9;
+
+/// How an image is fitted into a box when both a width and a height are given.
+#[derive(FromFormField, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Fit {
+    /// Fill the box, cropping whatever sticks out around the centre.
+    #[default]
+    Cover,
+    /// Fit within the box, keeping the aspect ratio, so one side may be shorter.
+    Contain,
+    /// Stretch to the box.
+    Fill,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Transform {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Fit,
+    /// The format to encode to, or the source's format if it can be encoded.
+    pub format: Option<ImageFormat>,
+}
+
+#[derive(Debug)]
+pub enum TransformError {
+    SizeNotAllowed(u32),
+    UnsupportedFormat(String),
+    /// The stored image is in a format that can't be decoded here.
+    UnsupportedSource,
+}
+
+impl Display for TransformError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransformError::SizeNotAllowed(size) => {
+                let sizes = allowed_sizes()
+                    .iter()
+                    .map(u32::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(
+                    f,
+                    "Size {size} is not allowed, sizes must be one of {sizes}"
+                )
+            }
+            TransformError::UnsupportedFormat(format) => {
+                write!(
+                    f,
+                    "Cannot convert images to {format}, use jpeg, png or webp"
+                )
+            }
+            TransformError::UnsupportedSource => f.write_str("This image cannot be transformed"),
+        }
+    }
+}
+
+impl std::error::Error for TransformError {}
+
+impl Transform {
+    /// Checks the query parameters of an image request, returning `None` if there aren't any.
+    pub fn from_query(
+        width: Option<u32>,
+        height: Option<u32>,
+        fit: Option<Fit>,
+        format: Option<&str>,
+    ) -> Result<Option<Self>, TransformError> {
+        if width.is_none() && height.is_none() && fit.is_none() && format.is_none() {
+            return Ok(None);
+        }
+
+        for size in width.into_iter().chain(height) {
+            if !allowed_sizes().contains(&size) {
+                return Err(TransformError::SizeNotAllowed(size));
+            }
+        }
+
+        let format = format
+            .map(|format| match format.to_ascii_lowercase().as_str() {
+                "jpeg" | "jpg" => Ok(ImageFormat::Jpeg),
+                "png" => Ok(ImageFormat::Png),
+                "webp" => Ok(ImageFormat::WebP),
+                _ => Err(TransformError::UnsupportedFormat(format.to_owned())),
+            })
+            .transpose()?;
+
+        Ok(Some(Transform {
+            width,
+            height,
+            fit: fit.unwrap_or_default(),
+            format,
+        }))
+    }
+
+    /// Applies the transform to the image stored at `key`, using the cached copy if there is one.
+    ///
+    /// Copies are cached along with the version of the original they were made from, so an image
+    /// that has since been replaced or deleted isn't served from the cache.
+    pub fn apply(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        let version = storage().version(key)?;
+        let cache_path = self.cache_path(key, &version);
+        if let Ok(cached) = fs::read(&cache_path) {
+            // The modification time doubles as the last use, which eviction goes by.
+            if let Ok(file) = File::options().append(true).open(&cache_path) {
+                _ = file.set_modified(SystemTime::now());
+            }
+            return Ok(cached);
+        }
+
+        let transformed = self.transform(&storage().get(key)?)?;
+        if let Err(err) = cache().insert(&cache_path, &transformed) {
+            println!("Could not cache {}: {err}", cache_path.display());
+        }
+        Ok(transformed)
+    }
+
+    fn transform(&self, bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let source_format = ImageFormat::sniff(bytes)
+            .filter(|format| format.decoder_format().is_some())
+            .ok_or(TransformError::UnsupportedSource)?;
+        let image = image::load_from_memory(bytes)?;
+
+        let resized = match (self.width, self.height) {
+            (Some(width), Some(height)) => match self.fit {
+                Fit::Cover => image.resize_to_fill(width, height, FilterType::Lanczos3),
+                Fit::Contain => image.resize(width, height, FilterType::Lanczos3),
+                Fit::Fill => image.resize_exact(width, height, FilterType::Lanczos3),
+            },
+            (Some(width), None) => image.resize(width, u32::MAX, FilterType::Lanczos3),
+            (None, Some(height)) => image.resize(u32::MAX, height, FilterType::Lanczos3),
+            (None, None) => image,
+        };
+
+        let format = self.format.unwrap_or(match source_format {
+            format @ (ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP) => format,
+            // GIFs lose their animation anyway, so their first frame is sent as a PNG.
+            _ => ImageFormat::Png,
+        });
+        encode(&resized, format, VARIANT_QUALITY)
+    }
+
+    fn cache_path(&self, key: &str, version: &str) -> PathBuf {
+        let format = self.format.map_or("source", ImageFormat::extension);
+        let name = format!(
+            "{key}\n{version}\n{:?}\n{:?}\n{:?}\n{format}",
+            self.width, self.height, self.fit
+        );
+        let hash = format!("{:x}", Sha256::digest(name.as_bytes()));
+        cache().directory.join(&hash[..2]).join(hash)
+    }
+}
+
+struct Transforms {
+    sizes: Vec<u32>,
+    cache: Cache,
+}
+
+static TRANSFORMS: OnceLock<Transforms> = OnceLock::new();
+
+/// Sets up the allowed sizes and the cache, once at launch.
+pub fn init(config: &TransformConfig) {
+    let transforms = Transforms {
+        sizes: config.sizes.clone(),
+        cache: Cache {
+            directory: config.cache_path.clone(),
+            max_bytes: config.cache_max_bytes,
+            used_bytes: Mutex::new(None),
+        },
+    };
+    if TRANSFORMS.set(transforms).is_err() {
+        panic!("image transforms should only be set up once");
+    }
+}
+
+fn transforms() -> &'static Transforms {
+    TRANSFORMS
+        .get()
+        .expect("image transforms should be set up at launch")
+}
+
+fn allowed_sizes() -> &'static [u32] {
+    &transforms().sizes
+}
+
+struct Cache {
+    directory: PathBuf,
+    max_bytes: u64,
+    /// How much the cache holds, or `None` until the directory has been scanned.
+    used_bytes: Mutex<Option<u64>>,
+}
+
+fn cache() -> &'static Cache {
+    &transforms().cache
+}
+
+impl Cache {
+    fn insert(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".partial");
+        fs::write(&temporary, bytes)?;
+        fs::rename(&temporary, path)?;
+
+        let mut used_bytes = self
+            .used_bytes
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        let used = match *used_bytes {
+            Some(used) => used + bytes.len() as u64,
+            None => self.entries()?.iter().map(|(_, size, _)| size).sum(),
+        };
+        *used_bytes = Some(if used > self.max_bytes {
+            self.evict()?
+        } else {
+            used
+        });
+        Ok(())
+    }
+
+    /// Deletes the least recently used copies until the cache is back under its target size, and
+    /// returns how much it then holds.
+    fn evict(&self) -> io::Result<u64> {
+        let mut entries = self.entries()?;
+        entries.sort_by_key(|(_, _, used)| *used);
+
+        let target = (self.max_bytes as f64 * EVICTION_TARGET) as u64;
+        let mut used: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        for (path, size, _) in entries {
+            if used <= target {
+                break;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => used -= size,
+                // Someone else may have evicted it already.
+                Err(err) if err.kind() == io::ErrorKind::NotFound => used -= size,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(used)
+    }
+
+    /// Every cached copy along with its size and when it was last used.
+    fn entries(&self) -> io::Result<Vec<(PathBuf, u64, SystemTime)>> {
+        let mut entries = Vec::new();
+        let Ok(directories) = fs::read_dir(&self.directory) else {
+            return Ok(entries);
+        };
+        for directory in directories {
+            let directory = directory?;
+            if !directory.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(directory.path())? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_file() {
+                    entries.push((entry.path(), metadata.len(), metadata.modified()?));
+                }
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::time::Duration;
+
+    #[test]
+    fn evicts_least_recently_used_copies() {
+        let directory = env::temp_dir().join(format!("transform-cache-{}", uuid::Uuid::new_v4()));
+        let cache = Cache {
+            directory: directory.clone(),
+            max_bytes: 250,
+            used_bytes: Mutex::new(None),
+        };
+
+        let paths = ["aa/old", "bb/newer", "cc/newest"].map(|name| directory.join(name));
+        for (i, path) in paths.iter().enumerate() {
+            cache.insert(path, &[0; 100]).unwrap();
+            let used = SystemTime::now() - Duration::from_secs(60 * (10 - i as u64));
+            File::options()
+                .append(true)
+                .open(path)
+                .unwrap()
+                .set_modified(used)
+                .unwrap();
+        }
+
+        assert!(!paths[0].exists());
+        assert!(paths[1].exists() && paths[2].exists());
+        assert_eq!(*cache.used_bytes.lock().unwrap(), Some(200));
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
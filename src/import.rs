@@ -0,0 +1,152 @@
+//! Conversion of uploaded documents into [`ArticleContent`](crate::article::ArticleContent) for
+//! contributors who don't use Google Docs.
+
+pub mod docx;
+pub mod markdown;
+
+use crate::article::{ArticleParagraph, ArticleSpan, SpanContent};
+use crate::images;
+use crate::images::library::ImageStore;
+use rocket::fs::TempFile;
+use rocket::http::ContentType;
+use std::io;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImportFormat {
+    Docx,
+    Markdown,
+}
+
+impl ImportFormat {
+    /// Works out the format of an uploaded file from its name, falling back to its content type.
+    pub fn from_file(file: &TempFile<'_>) -> Option<Self> {
+        let extension = file
+            .raw_name()
+            .map(|name| name.dangerous_unsafe_unsanitized_raw().as_str())
+            .and_then(|name| name.rsplit_once('.'))
+            .map(|(_, extension)| extension.to_ascii_lowercase());
+
+        match extension.as_deref() {
+            Some("docx") => return Some(ImportFormat::Docx),
+            Some("md" | "markdown") => return Some(ImportFormat::Markdown),
+            _ => {}
+        }
+
+        let content_type = file.content_type()?;
+        if content_type.top() == "application"
+            && content_type.sub() == "vnd.openxmlformats-officedocument.wordprocessingml.document"
+        {
+            Some(ImportFormat::Docx)
+        } else if content_type == &ContentType::Markdown {
+            Some(ImportFormat::Markdown)
+        } else {
+            None
+        }
+    }
+}
+
+/// Reads the contents of an uploaded file into memory.
+pub async fn read_upload(file: &TempFile<'_>) -> io::Result<Vec<u8>> {
+    match file.path() {
+        Some(path) => rocket::tokio::fs::read(path).await,
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "upload is not a file",
+        )),
+    }
+}
+
+/// The character formatting that applies to a run of text while walking a document.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TextStyle {
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub strikethrough: bool,
+    pub color: Option<String>,
+}
+
+impl TextStyle {
+    pub fn span(&self, content: Vec<SpanContent>) -> ArticleSpan {
+        let text_decoration = match (self.underline, self.strikethrough) {
+            (true, true) => "underline line-through",
+            (true, false) => "underline",
+            (false, true) => "line-through",
+            (false, false) => "none",
+        };
+
+        ArticleSpan {
+            content,
+            font_style: if self.italic { "italic" } else { "normal" }.into(),
+            text_decoration: text_decoration.into(),
+            color: self.color.clone().unwrap_or_else(|| "#000000".into()),
+            font_weight: if self.bold { "700" } else { "400" }.into(),
+        }
+    }
+}
+
+/// Makes a paragraph with the same defaults the Drive importer uses for missing styles.
+pub(crate) fn default_paragraph() -> ArticleParagraph {
+    ArticleParagraph {
+        margin_left: "0".into(),
+        margin_right: "0".into(),
+        text_alignment: "left".into(),
+        text_indent: "0".into(),
+        spans: Vec::new(),
+    }
+}
+
+/// Checks that an image in a document is one the site accepts, going by its contents rather than
+/// its file name, and stores it, returning the url it is recorded as.
+pub(crate) fn store_image(bytes: &[u8], store: &ImageStore) -> anyhow::Result<String> {
+    let format = images::validate_image(bytes, None)?;
+    store.store_url(bytes, format.extension())
+}
+
+/// Closes the paragraph being built. The first paragraph with any text becomes the headline, the
+/// same as for Drive documents, and blank paragraphs before it are dropped. Images in those
+/// paragraphs, or in the headline itself, are kept at the start of the body.
+pub(crate) fn finish_paragraph(
+    paragraph: &mut Option<ArticleParagraph>,
+    headline: &mut Option<String>,
+    paragraphs: &mut Vec<ArticleParagraph>,
+) {
+    let Some(mut paragraph) = paragraph.take() else {
+        return;
+    };
+
+    if headline.is_some() {
+        paragraphs.push(paragraph);
+        return;
+    }
+
+    let text = paragraph_text(&paragraph);
+    if !text.trim().is_empty() {
+        *headline = Some(text.trim().to_owned());
+    }
+
+    for span in &mut paragraph.spans {
+        span.content
+            .retain(|content| matches!(content, SpanContent::image { .. }));
+    }
+    paragraph.spans.retain(|span| !span.content.is_empty());
+    if !paragraph.spans.is_empty() {
+        paragraphs.push(paragraph);
+    }
+}
+
+/// The plain text of a paragraph, used to pick out the headline.
+pub(crate) fn paragraph_text(paragraph: &ArticleParagraph) -> String {
+    let mut text = String::new();
+    for span in &paragraph.spans {
+        for content in &span.content {
+            match content {
+                SpanContent::text { content } | SpanContent::anchor { content, .. } => {
+                    text.push_str(content)
+                }
+                SpanContent::image { .. } => {}
+            }
+        }
+    }
+    text
+}
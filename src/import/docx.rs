@@ -0,0 +1,493 @@
+//! Reads Word documents (OOXML `.docx`) into [`ArticleContent`].
+//!
+//! A docx file is a zip archive. The text lives in `word/document.xml`, while hyperlink targets and
+//! embedded images are referenced by relationship id through `word/_rels/document.xml.rels`.
+
+use super::{default_paragraph, finish_paragraph, store_image, TextStyle};
+use crate::article::{ArticleContent, ArticleParagraph, SpanContent};
+use crate::images::library::ImageStore;
+use anyhow::{anyhow, Result};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::collections::HashMap;
+use std::io::{Cursor, Read as _};
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+const DOCUMENT_PATH: &str = "word/document.xml";
+const RELATIONSHIPS_PATH: &str = "word/_rels/document.xml.rels";
+const IMAGE_RELATIONSHIP_TYPE: &str =
+    "http://schemas.openxmlformats.org/officeDocument/2006/relationships/image";
+const HYPERLINK_RELATIONSHIP_TYPE: &str =
+    "http://schemas.openxmlformats.org/officeDocument/2006/relationships/hyperlink";
+
+/// English Metric Units per CSS pixel, which is how drawing sizes are stored.
+const EMU_PER_PIXEL: f64 = 9525.0;
+
+/// The most any one file in a document may unzip to. The sizes in the zip's own headers are
+/// whatever the uploader put there, so they can't be relied on.
+const MAX_ENTRY_BYTES: u64 = 32 * 1024 * 1024;
+
+/// Unzips a docx file, stores its images and converts its body into article content.
+pub fn import(docx_bytes: &[u8], store: &ImageStore) -> Result<ArticleContent> {
+    let mut zip = ZipArchive::new(Cursor::new(docx_bytes))?;
+
+    let document = read_entry(&mut zip, DOCUMENT_PATH)?
+        .ok_or_else(|| anyhow!("Provided file is not a Word document"))?;
+    let relationships = match read_entry(&mut zip, RELATIONSHIPS_PATH)? {
+        Some(relationships) => parse_relationships(&relationships)?,
+        None => HashMap::new(),
+    };
+
+    let mut links = HashMap::new();
+    let mut image_urls = HashMap::new();
+    for (id, relationship) in relationships {
+        if relationship.kind == HYPERLINK_RELATIONSHIP_TYPE {
+            links.insert(id, crate::links::clean_link(&relationship.target));
+        } else if relationship.kind == IMAGE_RELATIONSHIP_TYPE {
+            // Targets are relative to the `word/` directory unless they start at the package root.
+            let entry_name = match relationship.target.strip_prefix('/') {
+                Some(absolute) => absolute.to_owned(),
+                None => format!("word/{}", relationship.target),
+            };
+
+            let Some(image_bytes) = read_entry_bytes(&mut zip, &entry_name)? else {
+                continue;
+            };
+            let url = store_image(&image_bytes, store)
+                .map_err(|err| anyhow!("Could not import image {entry_name}: {err}"))?;
+            image_urls.insert(id, url);
+        }
+    }
+
+    document_to_article_content(&document, &links, &image_urls)
+}
+
+fn read_entry_bytes(zip: &mut ZipArchive<Cursor<&[u8]>>, name: &str) -> Result<Option<Vec<u8>>> {
+    let entry = match zip.by_name(name) {
+        Ok(entry) => entry,
+        Err(ZipError::FileNotFound) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut bytes = Vec::new();
+    entry.take(MAX_ENTRY_BYTES + 1).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 > MAX_ENTRY_BYTES {
+        return Err(anyhow!(
+            "{name} is larger than {} MiB once unzipped",
+            MAX_ENTRY_BYTES / 1024 / 1024
+        ));
+    }
+    Ok(Some(bytes))
+}
+
+fn read_entry(zip: &mut ZipArchive<Cursor<&[u8]>>, name: &str) -> Result<Option<String>> {
+    match read_entry_bytes(zip, name)? {
+        Some(bytes) => Ok(Some(String::from_utf8(bytes)?)),
+        None => Ok(None),
+    }
+}
+
+struct Relationship {
+    kind: String,
+    target: String,
+}
+
+fn parse_relationships(xml: &str) -> Result<HashMap<String, Relationship>> {
+    let mut reader = Reader::from_str(xml);
+    let mut relationships = HashMap::new();
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(tag) | Event::Empty(tag)
+                if tag.local_name().as_ref() == b"Relationship" =>
+            {
+                let id = attribute(&tag, b"Id")?;
+                let kind = attribute(&tag, b"Type")?;
+                let target = attribute(&tag, b"Target")?;
+
+                if let (Some(id), Some(kind), Some(target)) = (id, kind, target) {
+                    relationships.insert(id, Relationship { kind, target });
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(relationships)
+}
+
+/// Looks up an attribute by its local name, ignoring the namespace prefix.
+fn attribute(tag: &BytesStart, name: &[u8]) -> Result<Option<String>> {
+    for attribute in tag.attributes() {
+        let attribute = attribute?;
+        if attribute.key.local_name().as_ref() == name {
+            return Ok(Some(attribute.unescape_value()?.into_owned()));
+        }
+    }
+    Ok(None)
+}
+
+/// Whether a toggle property such as `<w:b/>` or `<w:i w:val="false"/>` is switched on.
+fn toggle_value(tag: &BytesStart) -> Result<bool> {
+    Ok(!matches!(
+        attribute(tag, b"val")?.as_deref(),
+        Some("0" | "false" | "off" | "none")
+    ))
+}
+
+/// Converts a length in twentieths of a point into a CSS length.
+fn twips_to_pt(twips: &str) -> Option<String> {
+    let twips = twips.parse::<f64>().ok()?;
+    Some(format!("{}pt", twips / 20.0))
+}
+
+fn emu_to_px(emu: &str) -> String {
+    emu.parse::<f64>()
+        .map(|emu| format!("{:.2}px", emu / EMU_PER_PIXEL))
+        .unwrap_or_default()
+}
+
+#[derive(Default)]
+struct Drawing {
+    embed: Option<String>,
+    width: String,
+    height: String,
+    alt: String,
+}
+
+/// Walks `word/document.xml`, turning every `<w:p>` into a paragraph and every run into a span.
+///
+/// The first paragraph with any text becomes the headline, the same as for Drive documents. Text
+/// inside drawings, VML pictures and embedded objects, such as text boxes, shapes and charts, isn't
+/// part of the article and is skipped, though pictures are kept as images.
+fn document_to_article_content(
+    document: &str,
+    links: &HashMap<String, String>,
+    image_urls: &HashMap<String, String>,
+) -> Result<ArticleContent> {
+    let mut reader = Reader::from_str(document);
+
+    let mut headline = None;
+    let mut paragraphs = Vec::new();
+
+    let mut paragraph: Option<ArticleParagraph> = None;
+    let mut in_paragraph_properties = false;
+    let mut in_run_properties = false;
+    let mut in_text = false;
+    let mut style = TextStyle::default();
+    let mut run_text = String::new();
+    let mut hyperlink: Option<String> = None;
+    let mut drawing: Option<Drawing> = None;
+    // How deep in `<w:drawing>`, `<w:pict>` and `<w:object>` elements the reader is.
+    let mut embedded_depth = 0usize;
+
+    loop {
+        let event = reader.read_event()?;
+        let (tag, is_empty) = match &event {
+            Event::Start(tag) => (Some(tag), false),
+            Event::Empty(tag) => (Some(tag), true),
+            _ => (None, false),
+        };
+
+        if let (Some(tag), true) = (tag, embedded_depth > 0) {
+            match tag.local_name().as_ref() {
+                b"drawing" | b"pict" | b"object" if !is_empty => embedded_depth += 1,
+                // Anything deeper belongs to a drawing in a text box, which is skipped.
+                b"extent" if embedded_depth == 1 => {
+                    if let Some(drawing) = &mut drawing {
+                        drawing.width = emu_to_px(&attribute(tag, b"cx")?.unwrap_or_default());
+                        drawing.height = emu_to_px(&attribute(tag, b"cy")?.unwrap_or_default());
+                    }
+                }
+                b"docPr" if embedded_depth == 1 => {
+                    if let Some(drawing) = &mut drawing {
+                        drawing.alt = attribute(tag, b"descr")?
+                            .or(attribute(tag, b"title")?)
+                            .unwrap_or_default();
+                    }
+                }
+                b"blip" if embedded_depth == 1 => {
+                    if let Some(drawing) = &mut drawing {
+                        drawing.embed = attribute(tag, b"embed")?;
+                    }
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        if let Some(tag) = tag {
+            match tag.local_name().as_ref() {
+                b"p" => {
+                    paragraph = Some(default_paragraph());
+                    if is_empty {
+                        finish_paragraph(&mut paragraph, &mut headline, &mut paragraphs);
+                    }
+                }
+                b"pPr" => in_paragraph_properties = !is_empty,
+                b"jc" if in_paragraph_properties => {
+                    if let Some(paragraph) = &mut paragraph {
+                        paragraph.text_alignment = match attribute(tag, b"val")?.as_deref() {
+                            Some("center") => "center",
+                            Some("right" | "end") => "right",
+                            Some("both" | "distribute") => "justify",
+                            _ => "left",
+                        }
+                        .into();
+                    }
+                }
+                b"ind" if in_paragraph_properties => {
+                    if let Some(paragraph) = &mut paragraph {
+                        let left = attribute(tag, b"left")?.or(attribute(tag, b"start")?);
+                        let right = attribute(tag, b"right")?.or(attribute(tag, b"end")?);
+                        if let Some(left) = left.as_deref().and_then(twips_to_pt) {
+                            paragraph.margin_left = left;
+                        }
+                        if let Some(right) = right.as_deref().and_then(twips_to_pt) {
+                            paragraph.margin_right = right;
+                        }
+                        if let Some(indent) = attribute(tag, b"firstLine")?
+                            .as_deref()
+                            .and_then(twips_to_pt)
+                        {
+                            paragraph.text_indent = indent;
+                        } else if let Some(hanging) =
+                            attribute(tag, b"hanging")?.as_deref().and_then(twips_to_pt)
+                        {
+                            paragraph.text_indent = format!("-{hanging}");
+                        }
+                    }
+                }
+                b"r" if !is_empty => {
+                    style = TextStyle::default();
+                    run_text.clear();
+                }
+                b"rPr" if !in_paragraph_properties => in_run_properties = !is_empty,
+                b"b" if in_run_properties => style.bold = toggle_value(tag)?,
+                b"i" if in_run_properties => style.italic = toggle_value(tag)?,
+                b"u" if in_run_properties => style.underline = toggle_value(tag)?,
+                b"strike" if in_run_properties => style.strikethrough = toggle_value(tag)?,
+                b"color" if in_run_properties => {
+                    style.color = attribute(tag, b"val")?
+                        .filter(|color| color != "auto")
+                        .map(|color| format!("#{color}"));
+                }
+                b"t" => in_text = !is_empty,
+                b"tab" if !in_paragraph_properties => run_text.push('\t'),
+                b"br" | b"cr" => run_text.push('\n'),
+                b"hyperlink" => {
+                    let href = match attribute(tag, b"id")? {
+                        Some(id) => links.get(&id).cloned(),
+                        None => attribute(tag, b"anchor")?.map(|anchor| format!("#{anchor}")),
+                    };
+                    hyperlink = if is_empty { None } else { href };
+                }
+                b"drawing" if !is_empty => {
+                    drawing = Some(Drawing::default());
+                    embedded_depth = 1;
+                }
+                b"pict" | b"object" if !is_empty => embedded_depth = 1,
+                _ => {}
+            }
+            continue;
+        }
+
+        match event {
+            Event::End(tag) if embedded_depth > 0 => {
+                if !matches!(tag.local_name().as_ref(), b"drawing" | b"pict" | b"object") {
+                    continue;
+                }
+                embedded_depth -= 1;
+                if embedded_depth > 0 {
+                    continue;
+                }
+
+                let src = drawing
+                    .take()
+                    .and_then(|drawing| Some((image_urls.get(drawing.embed.as_ref()?)?, drawing)));
+                if let (Some(paragraph), Some((src, drawing))) = (&mut paragraph, src) {
+                    paragraph
+                        .spans
+                        .push(TextStyle::default().span(vec![SpanContent::image {
+                            src: src.clone(),
+                            width: drawing.width,
+                            height: drawing.height,
+                            alt: drawing.alt,
+                        }]));
+                }
+            }
+            Event::Text(text) if in_text => run_text.push_str(&text.unescape()?),
+            Event::End(tag) => match tag.local_name().as_ref() {
+                b"p" => finish_paragraph(&mut paragraph, &mut headline, &mut paragraphs),
+                b"pPr" => in_paragraph_properties = false,
+                b"rPr" => in_run_properties = false,
+                b"t" => in_text = false,
+                b"r" => {
+                    if let (Some(paragraph), false) = (&mut paragraph, run_text.is_empty()) {
+                        let content = std::mem::take(&mut run_text);
+                        let content = match &hyperlink {
+                            Some(href) => SpanContent::anchor {
+                                href: href.clone(),
+                                content,
+                            },
+                            None => SpanContent::text { content },
+                        };
+                        paragraph.spans.push(style.span(vec![content]));
+                    }
+                }
+                b"hyperlink" => hyperlink = None,
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    let headline = headline.ok_or_else(|| anyhow!("Provided file has no text"))?;
+
+    Ok(ArticleContent {
+        headline,
+        paragraphs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    const NAMESPACES: &str = concat!(
+        r#"xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main" "#,
+        r#"xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships" "#,
+        r#"xmlns:wp="http://schemas.openxmlformats.org/drawingml/2006/wordprocessingDrawing" "#,
+        r#"xmlns:a="http://schemas.openxmlformats.org/drawingml/2006/main" "#,
+        r#"xmlns:wps="http://schemas.microsoft.com/office/word/2010/wordprocessingShape" "#,
+        r#"xmlns:mc="http://schemas.openxmlformats.org/markup-compatibility/2006" "#,
+        r#"xmlns:v="urn:schemas-microsoft-com:vml""#,
+    );
+
+    fn document(body: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+               <w:document {NAMESPACES}><w:body>{body}</w:body></w:document>"#
+        )
+    }
+
+    const DRAWING: &str = concat!(
+        r#"<w:r><w:drawing><wp:inline><wp:extent cx="952500" cy="476250"/>"#,
+        r#"<wp:docPr id="1" name="Picture 1" descr="A cover photo"/>"#,
+        r#"<a:graphic><a:graphicData><a:blip r:embed="rId7"/></a:graphicData></a:graphic>"#,
+        r#"</wp:inline></w:drawing></w:r>"#,
+    );
+
+    fn convert(body: &str) -> ArticleContent {
+        let links = HashMap::from([("rId5".to_owned(), "https://example.com/".to_owned())]);
+        let image_urls = HashMap::from([("rId7".to_owned(), "/image/ab/cover.png".to_owned())]);
+        document_to_article_content(&document(body), &links, &image_urls).unwrap()
+    }
+
+    #[test]
+    fn converts_runs_links_and_paragraph_properties() {
+        let content = convert(concat!(
+            r#"<w:p/><w:p><w:r><w:t>The headline</w:t></w:r></w:p>"#,
+            r#"<w:p><w:pPr><w:jc w:val="both"/><w:ind w:left="720" w:firstLine="360"/></w:pPr>"#,
+            r#"<w:r><w:rPr><w:b/><w:color w:val="FF0000"/></w:rPr>"#,
+            r#"<w:t xml:space="preserve">Bold </w:t></w:r>"#,
+            r#"<w:r><w:rPr><w:i w:val="0"/></w:rPr><w:t>plain</w:t></w:r>"#,
+            r#"<w:hyperlink r:id="rId5"><w:r><w:t>a link</w:t></w:r></w:hyperlink></w:p>"#,
+        ));
+
+        assert_eq!(content.headline, "The headline");
+        assert_eq!(content.paragraphs.len(), 1);
+        let paragraph = &content.paragraphs[0];
+        assert_eq!(paragraph.text_alignment, "justify");
+        assert_eq!(paragraph.margin_left, "36pt");
+        assert_eq!(paragraph.text_indent, "18pt");
+
+        let spans = &paragraph.spans;
+        assert_eq!(spans.len(), 3);
+        assert_eq!(spans[0].font_weight, "700");
+        assert_eq!(spans[0].color, "#FF0000");
+        assert_eq!(spans[1].font_style, "normal");
+        assert!(matches!(
+            &spans[2].content[0],
+            SpanContent::anchor { href, content }
+                if href == "https://example.com/" && content == "a link"
+        ));
+    }
+
+    #[test]
+    fn keeps_images_before_and_in_the_headline() {
+        let content = convert(&format!(
+            r#"<w:p>{DRAWING}</w:p><w:p><w:r><w:t>The headline</w:t></w:r>{DRAWING}</w:p>"#
+        ));
+
+        assert_eq!(content.headline, "The headline");
+        assert_eq!(content.paragraphs.len(), 2);
+        for paragraph in &content.paragraphs {
+            assert!(matches!(
+                &paragraph.spans[..],
+                [span] if matches!(
+                    &span.content[..],
+                    [SpanContent::image { src, width, height, alt }]
+                        if src == "/image/ab/cover.png"
+                            && width == "100.00px"
+                            && height == "50.00px"
+                            && alt == "A cover photo"
+                )
+            ));
+        }
+    }
+
+    #[test]
+    fn skips_text_in_shapes_and_text_boxes() {
+        let content = convert(concat!(
+            r#"<w:p><w:r><w:t>The headline</w:t></w:r></w:p>"#,
+            r#"<w:p><w:r><w:t xml:space="preserve">Before </w:t></w:r>"#,
+            r#"<w:r><mc:AlternateContent><mc:Choice><w:drawing><wp:anchor>"#,
+            r#"<wp:extent cx="952500" cy="476250"/><a:graphic><a:graphicData>"#,
+            r#"<a:p><a:r><a:t>Shape text</a:t></a:r></a:p>"#,
+            r#"<wps:txbx><w:txbxContent><w:p><w:r><w:t>Text box</w:t></w:r></w:p>"#,
+            r#"</w:txbxContent></wps:txbx></a:graphicData></a:graphic></wp:anchor></w:drawing>"#,
+            r#"</mc:Choice><mc:Fallback><w:pict><v:textbox><w:txbxContent>"#,
+            r#"<w:p><w:r><w:t>Text box</w:t></w:r></w:p></w:txbxContent></v:textbox></w:pict>"#,
+            r#"</mc:Fallback></mc:AlternateContent></w:r><w:r><w:t>after</w:t></w:r></w:p>"#,
+        ));
+
+        assert_eq!(content.paragraphs.len(), 1);
+        let texts: Vec<_> = content.paragraphs[0]
+            .spans
+            .iter()
+            .flat_map(|span| &span.content)
+            .map(|content| match content {
+                SpanContent::text { content } => content.as_str(),
+                _ => panic!("only text should be left"),
+            })
+            .collect();
+        assert_eq!(texts, ["Before ", "after"]);
+    }
+
+    #[test]
+    fn refuses_entries_that_unzip_too_large() {
+        let mut docx = Vec::new();
+        let mut zip = ZipWriter::new(Cursor::new(&mut docx));
+        zip.start_file(DOCUMENT_PATH, FileOptions::default())
+            .unwrap();
+        let chunk = vec![0; 1024 * 1024];
+        for _ in 0..=MAX_ENTRY_BYTES / chunk.len() as u64 {
+            zip.write_all(&chunk).unwrap();
+        }
+        zip.finish().unwrap();
+        drop(zip);
+
+        let mut archive = ZipArchive::new(Cursor::new(&docx[..])).unwrap();
+        assert!(read_entry_bytes(&mut archive, DOCUMENT_PATH).is_err());
+        assert!(read_entry_bytes(&mut archive, "word/missing.xml")
+            .unwrap()
+            .is_none());
+    }
+}
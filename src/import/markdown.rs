@@ -0,0 +1,216 @@
+//! Reads Markdown documents into [`ArticleContent`].
+//!
+//! Images can either be embedded as `data:` urls or uploaded alongside the document, in which case
+//! they are matched by file name. Anything else (e.g. an absolute url) is referenced as-is.
+
+use super::{default_paragraph, finish_paragraph, store_image, TextStyle};
+use crate::article::{ArticleContent, ArticleParagraph, SpanContent};
+use crate::images::library::ImageStore;
+use crate::links;
+use anyhow::{anyhow, Result};
+use pulldown_cmark::{Event, Options, Parser, Tag};
+use std::collections::HashMap;
+
+/// Converts a Markdown document into article content, storing any images it references.
+///
+/// `attachments` maps the file names of images uploaded with the document to their bytes.
+pub fn import(
+    source: &str,
+    attachments: &HashMap<String, Vec<u8>>,
+    store: &ImageStore,
+) -> Result<ArticleContent> {
+    to_article_content(source, |src| resolve_image(src, attachments, store))
+}
+
+/// Converts a Markdown document into article content, with `resolve_image` turning the source of
+/// each image into the url to reference it by.
+fn to_article_content(
+    source: &str,
+    mut resolve_image: impl FnMut(&str) -> Result<String>,
+) -> Result<ArticleContent> {
+    let mut headline = None;
+    let mut paragraphs = Vec::new();
+
+    let mut paragraph: Option<ArticleParagraph> = None;
+    let mut style = TextStyle::default();
+    let mut link: Option<(String, String)> = None;
+    let mut image: Option<(String, String)> = None;
+
+    for event in Parser::new_ext(source, Options::ENABLE_STRIKETHROUGH) {
+        match event {
+            Event::Start(Tag::Paragraph | Tag::Heading(..) | Tag::CodeBlock(_)) => {
+                paragraph.get_or_insert_with(default_paragraph);
+            }
+            Event::Start(Tag::Item) => {
+                finish_paragraph(&mut paragraph, &mut headline, &mut paragraphs);
+
+                let mut item = default_paragraph();
+                item.margin_left = "36pt".into();
+                item.spans.push(style.span(vec![SpanContent::text {
+                    content: "• ".into(),
+                }]));
+                paragraph = Some(item);
+            }
+            Event::Start(Tag::Emphasis) => style.italic = true,
+            Event::Start(Tag::Strong) => style.bold = true,
+            Event::Start(Tag::Strikethrough) => style.strikethrough = true,
+            Event::Start(Tag::Link(_, href, _)) => {
+                link = Some((links::clean_link(&href), String::new()))
+            }
+            Event::Start(Tag::Image(_, src, _)) => image = Some((src.into_string(), String::new())),
+            Event::End(Tag::Paragraph | Tag::Heading(..) | Tag::CodeBlock(_) | Tag::Item) => {
+                finish_paragraph(&mut paragraph, &mut headline, &mut paragraphs);
+            }
+            Event::End(Tag::Emphasis) => style.italic = false,
+            Event::End(Tag::Strong) => style.bold = false,
+            Event::End(Tag::Strikethrough) => style.strikethrough = false,
+            Event::End(Tag::Link(..)) => {
+                if let (Some((href, content)), Some(paragraph)) = (link.take(), &mut paragraph) {
+                    paragraph
+                        .spans
+                        .push(style.span(vec![SpanContent::anchor { href, content }]));
+                }
+            }
+            Event::End(Tag::Image(..)) => {
+                if let (Some((src, alt)), Some(paragraph)) = (image.take(), &mut paragraph) {
+                    let src = resolve_image(&src)?;
+                    paragraph.spans.push(style.span(vec![SpanContent::image {
+                        src,
+                        width: String::new(),
+                        height: String::new(),
+                        alt,
+                    }]));
+                }
+            }
+            Event::Text(text) | Event::Code(text) => {
+                if let Some((_, alt)) = &mut image {
+                    alt.push_str(&text);
+                } else if let Some((_, content)) = &mut link {
+                    content.push_str(&text);
+                } else if let Some(paragraph) = &mut paragraph {
+                    paragraph.spans.push(style.span(vec![SpanContent::text {
+                        content: text.into_string(),
+                    }]));
+                }
+            }
+            Event::SoftBreak | Event::HardBreak => {
+                let separator = if matches!(event, Event::SoftBreak) {
+                    " "
+                } else {
+                    "\n"
+                };
+                if let Some((_, content)) = &mut link {
+                    content.push_str(separator);
+                } else if let Some(paragraph) = &mut paragraph {
+                    paragraph.spans.push(style.span(vec![SpanContent::text {
+                        content: separator.into(),
+                    }]));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let headline = headline.ok_or_else(|| anyhow!("Provided file has no text"))?;
+
+    Ok(ArticleContent {
+        headline,
+        paragraphs,
+    })
+}
+
+/// Stores an embedded or attached image and returns the url to reference it by.
+fn resolve_image(
+    src: &str,
+    attachments: &HashMap<String, Vec<u8>>,
+    store: &ImageStore,
+) -> Result<String> {
+    if let Some(data) = src.strip_prefix("data:") {
+        let (_, encoded) = data
+            .split_once(";base64,")
+            .ok_or_else(|| anyhow!("Only base64 encoded data urls are supported"))?;
+        let bytes = base64::decode(encoded.trim())?;
+        return store_image(&bytes, store);
+    }
+
+    let file_name = src.rsplit('/').next().unwrap_or(src);
+    match attachments.get(file_name) {
+        Some(bytes) => store_image(bytes, store),
+        None => Ok(src.to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert(source: &str) -> ArticleContent {
+        to_article_content(source, |src| Ok(format!("/image/{src}"))).unwrap()
+    }
+
+    fn texts(paragraph: &ArticleParagraph) -> Vec<&str> {
+        paragraph
+            .spans
+            .iter()
+            .flat_map(|span| &span.content)
+            .map(|content| match content {
+                SpanContent::text { content } | SpanContent::anchor { content, .. } => content,
+                SpanContent::image { src, .. } => src,
+            })
+            .map(String::as_str)
+            .collect()
+    }
+
+    #[test]
+    fn converts_formatting_links_and_lists() {
+        let content = convert(
+            "# The headline\n\n\
+             Some **bold** and *italic* text with \
+             [a link](https://www.google.com/url?q=https://example.com/page&sa=D).\n\n\
+             - first\n\
+             - second\n",
+        );
+
+        assert_eq!(content.headline, "The headline");
+        assert_eq!(content.paragraphs.len(), 3);
+        assert_eq!(
+            texts(&content.paragraphs[0]),
+            [
+                "Some ",
+                "bold",
+                " and ",
+                "italic",
+                " text with ",
+                "a link",
+                "."
+            ]
+        );
+        let spans = &content.paragraphs[0].spans;
+        assert_eq!(spans[1].font_weight, "700");
+        assert_eq!(spans[3].font_style, "italic");
+        assert!(matches!(
+            &spans[5].content[0],
+            SpanContent::anchor { href, .. } if href == "https://example.com/page"
+        ));
+        assert_eq!(texts(&content.paragraphs[1]), ["• ", "first"]);
+        assert_eq!(content.paragraphs[2].margin_left, "36pt");
+    }
+
+    #[test]
+    fn keeps_images_before_the_headline() {
+        let content = convert("![Cover](cover.png)\n\n# The headline\n\nBody text.");
+
+        assert_eq!(content.headline, "The headline");
+        assert_eq!(texts(&content.paragraphs[0]), ["/image/cover.png"]);
+        assert!(matches!(
+            &content.paragraphs[0].spans[0].content[0],
+            SpanContent::image { alt, .. } if alt == "Cover"
+        ));
+        assert_eq!(texts(&content.paragraphs[1]), ["Body text."]);
+    }
+
+    #[test]
+    fn rejects_documents_without_text() {
+        assert!(to_article_content("![](a.png)", |src| Ok(src.into())).is_err());
+    }
+}
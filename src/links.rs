@@ -0,0 +1,159 @@
+//! Cleanup of links coming in from imported documents.
+//!
+//! Google Docs routes every link through `https://www.google.com/url?q=<target>&sa=D&...`, and
+//! links copied from newsletters and social media tend to carry tracking parameters. Neither should
+//! end up on the site.
+
+use crate::article::{ArticleContent, SpanContent};
+use url::Url;
+
+/// Query parameters that only exist to track where a click came from.
+const TRACKING_PARAMETERS: [&str; 10] = [
+    "fbclid", "gclid", "dclid", "gbraid", "wbraid", "msclkid", "yclid", "igshid", "mc_cid",
+    "mc_eid",
+];
+
+/// How many Google redirects wrapped in one another are unwrapped before giving up.
+const MAX_REDIRECT_DEPTH: usize = 4;
+
+/// Unwraps Google redirect links, drops tracking parameters and normalizes the result.
+///
+/// Relative links such as an in-page `#anchor`, and `mailto:` and `tel:` links, are returned as-is
+/// apart from surrounding whitespace. Links with any other scheme, such as `javascript:`, are
+/// dropped and come back empty.
+pub fn clean_link(href: &str) -> String {
+    let href = href.trim();
+    let Ok(mut url) = Url::parse(href) else {
+        return href.to_owned();
+    };
+    match url.scheme() {
+        "http" | "https" => {}
+        "mailto" | "tel" => return href.to_owned(),
+        _ => return String::new(),
+    }
+
+    for _ in 0..MAX_REDIRECT_DEPTH {
+        match redirect_target(&url) {
+            Some(target) => url = target,
+            None => break,
+        }
+    }
+
+    if let Some(query) = url.query() {
+        let kept = query
+            .split('&')
+            .filter(|pair| !pair.is_empty() && !is_tracking_parameter(pair))
+            .collect::<Vec<_>>()
+            .join("&");
+        url.set_query(if kept.is_empty() { None } else { Some(&kept) });
+    }
+    if url.fragment() == Some("") {
+        url.set_fragment(None);
+    }
+
+    url.into()
+}
+
+/// The destination of a `google.com/url` redirect, if `url` is one and it leads to an `http(s)`
+/// url.
+fn redirect_target(url: &Url) -> Option<Url> {
+    let host = url.host_str()?;
+    let is_google = host == "google.com" || host.ends_with(".google.com");
+    if !is_google || url.path() != "/url" {
+        return None;
+    }
+
+    let (_, target) = url
+        .query_pairs()
+        .find(|(key, _)| key == "q" || key == "url")?;
+    Url::parse(target.trim())
+        .ok()
+        .filter(|target| matches!(target.scheme(), "http" | "https"))
+}
+
+fn is_tracking_parameter(pair: &str) -> bool {
+    let key = pair.split('=').next().unwrap_or(pair).to_ascii_lowercase();
+    key.starts_with("utm_") || TRACKING_PARAMETERS.contains(&key.as_str())
+}
+
+/// Cleans every link in an article, returning whether any of them changed.
+pub fn clean_article_links(content: &mut ArticleContent) -> bool {
+    let mut changed = false;
+
+    let links = content
+        .paragraphs
+        .iter_mut()
+        .flat_map(|paragraph| &mut paragraph.spans)
+        .flat_map(|span| &mut span.content);
+    for content in links {
+        if let SpanContent::anchor { href, .. } = content {
+            let cleaned = clean_link(href);
+            if cleaned != *href {
+                *href = cleaned;
+                changed = true;
+            }
+        }
+    }
+
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redirect(target: &str) -> String {
+        let target = url::form_urlencoded::byte_serialize(target.as_bytes()).collect::<String>();
+        format!("https://www.google.com/url?q={target}&sa=D&source=editors&usg=AOvVaw0")
+    }
+
+    #[test]
+    fn unwraps_redirects_and_drops_tracking() {
+        assert_eq!(
+            clean_link(&redirect(
+                "https://example.com/a?utm_source=x&id=3&fbclid=y#"
+            )),
+            "https://example.com/a?id=3"
+        );
+        assert_eq!(
+            clean_link(&redirect(&redirect("https://example.com/"))),
+            "https://example.com/"
+        );
+    }
+
+    #[test]
+    fn stops_unwrapping_after_a_few_redirects() {
+        let mut href = "https://example.com/".to_owned();
+        for _ in 0..MAX_REDIRECT_DEPTH + 1 {
+            href = redirect(&href);
+        }
+        let cleaned = clean_link(&href);
+        assert!(cleaned.starts_with("https://www.google.com/url?q="));
+        assert_ne!(cleaned, href);
+    }
+
+    #[test]
+    fn keeps_relative_and_contact_links() {
+        assert_eq!(clean_link(" #h.abc123 "), "#h.abc123");
+        assert_eq!(clean_link("/article/1"), "/article/1");
+        assert_eq!(
+            clean_link("mailto:editor@example.com"),
+            "mailto:editor@example.com"
+        );
+        assert_eq!(clean_link("tel:+15555550100"), "tel:+15555550100");
+    }
+
+    #[test]
+    fn drops_script_and_other_schemes() {
+        assert_eq!(clean_link("javascript:alert(1)"), "");
+        assert_eq!(clean_link(" JavaScript:alert(1)"), "");
+        assert_eq!(clean_link("java\tscript:alert(1)"), "");
+        assert_eq!(clean_link("data:text/html,<script>alert(1)</script>"), "");
+    }
+
+    #[test]
+    fn keeps_redirects_to_other_schemes_wrapped() {
+        let href = redirect("javascript:alert(1)");
+        assert!(clean_link(&href).starts_with("https://www.google.com/url?q=javascript"));
+    }
+}
@@ -3,33 +3,50 @@ extern crate diesel;
 
 mod article;
 mod article_submission;
+mod audit;
 mod auth;
+mod client_ip;
+mod config;
+mod csrf;
 mod endpoints;
 mod error;
+mod export;
 mod gdrive;
+mod images;
+mod import;
+mod links;
 mod paginated;
+mod rate_limit;
 mod schema;
 mod section;
 mod writer;
 
+use audit::{Actor, Change, Target};
+use auth::throttle::LoginThrottle;
+use config::AppConfig;
+use csrf::Csrf;
 use diesel::prelude::*;
-use rocket::fs::FileServer;
+use rate_limit::RateLimit;
+use rocket::fairing::AdHoc;
 use rocket::{launch, routes};
-use std::env;
 use std::sync::Mutex;
+use std::time::Duration;
 
 #[launch]
 async fn rocket() -> _ {
-    let db_connection = Mutex::new(establish_connection());
-    let build_dir = env::var("BUILD_DIR").unwrap_or_else(|_| "build".into());
+    let config = AppConfig::from_env().unwrap_or_else(|err| {
+        println!("{err}");
+        std::process::exit(1);
+    });
+    let db_connection = Mutex::new(establish_connection(&config.database_url));
 
-    let client_secret_path = env::var("CLIENT_SECRET_PATH")
-        .expect("environment variable 'CLIENT_SECRET_PATH' should be set");
+    // Set up image storage now, so a bad configuration is caught at launch.
+    images::storage::init(&config.images);
+    images::transform::init(&config.images.transform);
 
-    let images_dir = env::var("ARTICLE_IMAGE_PATH")
-        .expect("environment variable 'ARTICLE_IMAGE_PATH' should be set");
-
-    let file_service = gdrive::make_files_service(client_secret_path).await;
+    let file_service = gdrive::make_files_service(&config.drive).await;
+    let gc_interval_hours = config.image_gc_interval_hours;
+    let database_url = config.database_url.clone();
 
     rocket::build()
         .mount("/", routes![endpoints::index])
@@ -43,17 +60,49 @@ async fn rocket() -> _ {
                 endpoints::patch_article_by_id,
                 endpoints::get_writer,
                 endpoints::upload_picture,
+                endpoints::get_images,
+                endpoints::patch_image,
+                endpoints::import_document,
                 endpoints::get_featured_article,
                 endpoints::post_writers,
+                endpoints::post_headshot,
                 endpoints::patch_writer_by_id,
+                endpoints::post_writer_account,
+                endpoints::delete_writer_account,
+                endpoints::current_writer,
                 endpoints::api_fallback,
                 endpoints::get_writer_by_name,
                 endpoints::get_writer_id_articles,
                 endpoints::get_article_by_slug,
+                endpoints::export_article,
                 endpoints::logout,
                 endpoints::login,
+                endpoints::refresh_session,
                 endpoints::current_role,
+                endpoints::get_csrf_token,
+                endpoints::get_sessions,
+                endpoints::delete_session,
+                endpoints::delete_user_sessions,
+                endpoints::current_permissions,
+                endpoints::get_permission_grants,
+                endpoints::post_permission_grant,
+                endpoints::delete_permission_grant,
+                endpoints::get_two_factor,
+                endpoints::post_two_factor_enroll,
+                endpoints::post_two_factor_confirm,
+                endpoints::post_two_factor_recovery_codes,
+                endpoints::delete_two_factor,
+                endpoints::delete_user_two_factor,
+                endpoints::get_two_factor_policy,
+                endpoints::put_two_factor_policy,
+                endpoints::get_api_keys,
+                endpoints::post_api_key,
+                endpoints::delete_api_key,
+                endpoints::get_audit_log,
                 endpoints::delete_article,
+                endpoints::clean_article_links,
+                endpoints::strip_image_metadata,
+                endpoints::collect_orphaned_images,
                 endpoints::get_writers,
                 endpoints::get_drive_drafts,
                 endpoints::get_drive_finals,
@@ -62,19 +111,74 @@ async fn rocket() -> _ {
                 endpoints::get_file_content,
                 endpoints::get_article_submissions,
                 endpoints::post_article_submission,
+                endpoints::patch_article_submission,
                 endpoints::delete_article_submission,
+                endpoints::rate_limited,
+                endpoints::csrf_rejected,
             ],
         )
-        .mount("/image", routes![endpoints::image_fallback])
-        .mount("/image", FileServer::from(images_dir))
+        .mount(
+            "/image",
+            routes![endpoints::get_image, endpoints::image_fallback],
+        )
         .manage(db_connection)
-        .manage(build_dir)
+        .manage(config)
         .manage(file_service)
+        .manage(LoginThrottle::default())
+        .attach(RateLimit::default())
+        .attach(Csrf)
+        .attach(AdHoc::on_liftoff("Image garbage collection", move |_| {
+            Box::pin(async move { schedule_image_gc(gc_interval_hours, database_url) })
+        }))
 }
 
-fn establish_connection() -> PgConnection {
-    _ = dotenvy::dotenv();
+/// Collects orphaned images every `IMAGE_GC_INTERVAL_HOURS` hours (daily by default), or never
+/// if it is set to 0.
+fn schedule_image_gc(interval_hours: u64, database_url: String) {
+    if interval_hours == 0 {
+        return;
+    }
+
+    rocket::tokio::spawn(async move {
+        let mut interval =
+            rocket::tokio::time::interval(Duration::from_secs(interval_hours * 3600));
+        // The first tick completes immediately, which would run a collection on every restart.
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            let database_url = database_url.clone();
+            let result = rocket::tokio::task::spawn_blocking(move || {
+                let db_connection = establish_connection(&database_url);
+                let references = images::gc::referenced_images(&db_connection)?;
+                let report = images::gc::collect_orphans(&references, &Default::default())?;
+                Actor::job("image_gc").record(
+                    &db_connection,
+                    "collect_orphaned_images",
+                    Target::Site,
+                    Change::result(&report),
+                )?;
+                anyhow::Ok(report)
+            })
+            .await;
+
+            match result {
+                Ok(Ok(report)) => {
+                    let trashed = report
+                        .orphans
+                        .iter()
+                        .filter(|orphan| orphan.trashed)
+                        .count();
+                    println!("Image garbage collection moved {trashed} orphaned images to trash");
+                }
+                Ok(Err(err)) => println!("Image garbage collection failed: {err}"),
+                Err(err) => println!("Image garbage collection panicked: {err}"),
+            }
+        }
+    });
+}
 
-    let db_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    PgConnection::establish(&db_url).unwrap_or_else(|_| panic!("error connecting to {}", db_url))
+fn establish_connection(db_url: &str) -> PgConnection {
+    PgConnection::establish(db_url).unwrap_or_else(|_| panic!("error connecting to {}", db_url))
 }
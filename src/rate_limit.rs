@@ -0,0 +1,139 @@
+//! A limit on how many writes each IP address can make without being logged in.
+//!
+//! Everything that changes something needs a session, apart from logging in and out and
+//! refreshing a session, so those are what this limits in practice. Requests with a session or an
+//! API key are left alone, as staff and their scripts can be trusted to upload a lot at once.
+//!
+//! Each IP address gets a bucket of [`BURST`] requests, which refills at [`PER_MINUTE`] requests
+//! a minute. A request that finds its bucket empty is sent to [`RATE_LIMITED_PATH`] instead of
+//! where it was going, which answers with `429 Too Many Requests`.
+
+use crate::auth::current_principal;
+use crate::client_ip::client_ip;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::Method;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::Data;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+const BURST: f64 = 20.0;
+const PER_MINUTE: f64 = 20.0;
+/// Full buckets are only cleared out once this many IP addresses are tracked.
+const MAX_TRACKED: usize = 10_000;
+
+pub const RATE_LIMITED_PATH: &str = "/api/rate-limited";
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Default)]
+pub struct RateLimit {
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimit {
+    /// Takes a request out of the IP address's bucket, or says how long until there is one.
+    fn take(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let refill = |bucket: &Bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            (bucket.tokens + elapsed * PER_MINUTE / 60.0).min(BURST)
+        };
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        if buckets.len() >= MAX_TRACKED {
+            buckets.retain(|_, bucket| refill(bucket) < BURST);
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: BURST,
+            updated: now,
+        });
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) * 60.0 / PER_MINUTE,
+            ))
+        }
+    }
+}
+
+/// Why a request was sent to [`RATE_LIMITED_PATH`], with how long to wait before trying again.
+#[derive(Clone, Copy)]
+pub struct RateLimited(pub Duration);
+
+#[rocket::async_trait]
+impl Fairing for RateLimit {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limit writes without a session",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        if !matches!(
+            req.method(),
+            Method::Post | Method::Put | Method::Patch | Method::Delete
+        ) || !req.uri().path().starts_with("/api/")
+        {
+            return;
+        }
+        let Some(ip) = client_ip(req) else {
+            return;
+        };
+        if current_principal(&*req).await.is_some() {
+            return;
+        }
+
+        if let Err(wait) = self.take(ip, Instant::now()) {
+            req.local_cache(|| Some(RateLimited(wait)));
+            req.set_method(Method::Get);
+            req.set_uri(Origin::parse(RATE_LIMITED_PATH).expect("valid path"));
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RateLimited {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match req.local_cache(|| None::<RateLimited>) {
+            Some(limited) => Outcome::Success(*limited),
+            None => Outcome::Forward(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn refills_over_time() {
+        let limit = RateLimit::default();
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let start = Instant::now();
+
+        for _ in 0..BURST as usize {
+            assert_eq!(limit.take(ip, start), Ok(()));
+        }
+        let wait = limit.take(ip, start).unwrap_err();
+        assert_eq!(wait, Duration::from_secs_f64(60.0 / PER_MINUTE));
+        assert_eq!(limit.take(IpAddr::V4(Ipv4Addr::UNSPECIFIED), start), Ok(()));
+
+        assert_eq!(limit.take(ip, start + wait), Ok(()));
+        assert!(limit.take(ip, start + wait).is_err());
+    }
+}
@@ -1,4 +1,23 @@
 table! {
+    use crate::auth::permission::PermissionMapping;
+    use diesel::sql_types::*;
+
+    api_keys (id) {
+        id -> Int4,
+        name -> Text,
+        key_prefix -> Text,
+        key_hash -> Text,
+        permissions -> Array<PermissionMapping>,
+        created_by -> Text,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        last_used_at -> Nullable<Timestamptz>,
+        revoked_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    use crate::article_submission::SubmissionStatusMapping;
     use crate::section::SectionMapping;
     use diesel::sql_types::*;
 
@@ -10,6 +29,25 @@ table! {
         author_id -> Int4,
         drive_file_id -> Text,
         thumbnail_url -> Nullable<Text>,
+        status -> SubmissionStatusMapping,
+    }
+}
+
+table! {
+    use crate::auth::RoleMapping;
+    use diesel::sql_types::*;
+
+    audit_log (id) {
+        id -> Int4,
+        actor -> Nullable<Text>,
+        role -> Nullable<RoleMapping>,
+        action -> Text,
+        target_type -> Text,
+        target_id -> Nullable<Text>,
+        before -> Nullable<Jsonb>,
+        after -> Nullable<Jsonb>,
+        ip_address -> Nullable<Text>,
+        created_at -> Timestamptz,
     }
 }
 
@@ -32,6 +70,92 @@ table! {
     }
 }
 
+table! {
+    images (id) {
+        id -> Int4,
+        path -> Text,
+        width -> Nullable<Int4>,
+        height -> Nullable<Int4>,
+        hash -> Text,
+        uploader -> Text,
+        credit -> Nullable<Text>,
+        caption -> Nullable<Text>,
+        alt_text -> Nullable<Text>,
+        created_at -> Timestamptz,
+        variants -> Jsonb,
+    }
+}
+
+table! {
+    use crate::auth::permission::PermissionMapping;
+    use crate::auth::RoleMapping;
+    use crate::section::SectionMapping;
+    use diesel::sql_types::*;
+
+    permission_grants (id) {
+        id -> Int4,
+        role -> Nullable<RoleMapping>,
+        username -> Nullable<Text>,
+        permission -> PermissionMapping,
+        section -> Nullable<SectionMapping>,
+    }
+}
+
+table! {
+    refresh_tokens (id) {
+        id -> Int4,
+        token_hash -> Text,
+        expires_at -> Timestamptz,
+        used_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+        session_id -> Text,
+    }
+}
+
+table! {
+    use crate::auth::RoleMapping;
+    use diesel::sql_types::*;
+
+    sessions (id) {
+        id -> Text,
+        username -> Text,
+        role -> RoleMapping,
+        created_at -> Timestamptz,
+        last_seen_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        two_factor -> Bool,
+        csrf_token -> Text,
+    }
+}
+
+table! {
+    two_factor (username) {
+        username -> Text,
+        secret -> Text,
+        enabled_at -> Nullable<Timestamptz>,
+        last_used_step -> Nullable<Int8>,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    use crate::auth::RoleMapping;
+    use diesel::sql_types::*;
+
+    two_factor_policy (role) {
+        role -> RoleMapping,
+    }
+}
+
+table! {
+    two_factor_recovery_codes (id) {
+        id -> Int4,
+        username -> Text,
+        code_hash -> Text,
+        used_at -> Nullable<Timestamptz>,
+    }
+}
+
 table! {
     writers (id) {
         id -> Int4,
@@ -43,6 +167,33 @@ table! {
     }
 }
 
+table! {
+    writer_accounts (id) {
+        id -> Int4,
+        username -> Text,
+        password_hash -> Text,
+        writer_id -> Int4,
+        created_at -> Timestamptz,
+    }
+}
+
 joinable!(articles -> writers (writer_id));
+joinable!(refresh_tokens -> sessions (session_id));
+joinable!(two_factor_recovery_codes -> two_factor (username));
+joinable!(writer_accounts -> writers (writer_id));
 
-allow_tables_to_appear_in_same_query!(article_submission, articles, writers,);
+allow_tables_to_appear_in_same_query!(
+    api_keys,
+    article_submission,
+    audit_log,
+    articles,
+    images,
+    permission_grants,
+    refresh_tokens,
+    sessions,
+    two_factor,
+    two_factor_policy,
+    two_factor_recovery_codes,
+    writer_accounts,
+    writers,
+);
@@ -2,7 +2,7 @@ use diesel_derive_enum::DbEnum;
 use rocket::request::FromParam;
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Copy, Debug, DbEnum, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DbEnum, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Section {
     News,